@@ -0,0 +1,36 @@
+extern crate rust_redlock;
+
+use std::time;
+use rust_redlock::*;
+
+fn critical_section(lock: &Lock) -> usize {
+    lock.resource_name().len()
+}
+
+fn example() -> RedlockResult<()> {
+    let redlock = Redlock::builder()
+        .add_address("redis://redis1.example.com")
+        .add_address("redis://redis2.example.com")
+        .add_address("redis://redis3.example.com")
+        .retry_count(5)
+        .build()?;
+
+    // Run the critical section with the lock held, it's released afterwards.
+    let len = redlock.with_lock("resource_name_to_lock",
+                                time::Duration::from_millis(1000),
+                                critical_section)?;
+    println!("locked a resource name of {} bytes", len);
+
+    // Or tell apart a busy resource from the failures.
+    match redlock.try_lock("resource_name_to_lock", time::Duration::from_millis(1000)) {
+        Ok(Some(lock)) => lock.unlock()?,
+        Ok(None) => println!("the resource is locked by another client"),
+        Err(RedlockError::AllNodesFailed(err)) => println!("every server failed: {}", err),
+        Err(err) => return Err(err),
+    }
+    Ok(())
+}
+
+fn main() {
+    example().unwrap();
+}
@@ -12,6 +12,7 @@ fn example() -> RedlockResult<()> {
                                    retry_delay: time::Duration::from_millis(400),
                                    retry_jitter: 400,
                                    drift_factor: 0.01,
+                                   ..Config::default()
                                })?;
 
     // Acquire the lock of the specified resource.
@@ -0,0 +1,653 @@
+use std::time::{Duration, Instant, SystemTime};
+use futures::future::join_all;
+use futures::stream::{self, Stream};
+use crate::errors::{AttemptTiming, NodeOutcome, RedlockResult, RedlockError};
+use crate::backend::Node;
+use crate::redlock::{expirations, Config, Redlock, UnlockOutcome};
+use crate::util;
+
+#[derive(Debug)]
+enum RequestInfo<'a> {
+    Lock,
+    Extend { resource_value: &'a str },
+}
+
+#[derive(Debug)]
+enum Attempt<'a> {
+    Acquired(AsyncLock<'a>),
+    // The attempt failed, with the result of each server.
+    Failed(Vec<NodeOutcome>),
+}
+
+// Pending releases the servers locked by an attempt which is dropped before
+// it completes, on the tokio runtime. It does nothing once disarmed.
+struct Pending {
+    redlock: Option<AsyncRedlock>,
+    resource_name: String,
+    value: String,
+}
+
+impl Pending {
+    fn new(redlock: &AsyncRedlock, resource_name: &str, value: &str) -> Pending {
+        Pending {
+            redlock: Some(redlock.clone()),
+            resource_name: String::from(resource_name),
+            value: String::from(value),
+        }
+    }
+
+    fn disarm(mut self) {
+        self.redlock = None;
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let redlock = match self.redlock.take() {
+            Some(redlock) => redlock,
+            None => return,
+        };
+        // Without a runtime, the locks are left until they expire
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let (resource_name, value) = (self.resource_name.clone(), self.value.clone());
+            runtime.spawn(async move {
+                let _ = redlock.release_dropped(&resource_name, &value).await;
+            });
+        }
+    }
+}
+
+// AsyncLock represents a acquired lock for specified resource, acquired by
+// an AsyncRedlock.
+#[derive(Debug)]
+pub struct AsyncLock<'a> {
+    redlock: &'a AsyncRedlock,
+    resource_name: String,
+    value: String,
+    expiration: SystemTime,
+    // The validity is checked against the monotonic clock.
+    valid_until: Instant,
+    acquisition_time: Duration,
+    attempts: u32,
+}
+
+impl<'a> AsyncLock<'a> {
+    // The time the lock expires at, with the clock drift subtracted.
+    pub fn expiration(&self) -> SystemTime {
+        self.expiration
+    }
+
+    // The time left until the lock expires, or None if it has already
+    // expired.
+    pub fn remaining_ttl(&self) -> Option<Duration> {
+        self.redlock.redlock.remaining_ttl(self.valid_until)
+    }
+
+    // How long the successful attempt took to reach the quorum.
+    pub fn acquisition_time(&self) -> Duration {
+        self.acquisition_time
+    }
+
+    // The number of attempts made to acquire the lock, 1 means it was
+    // acquired on the first try.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    // Release the acquired lock.
+    pub async fn unlock(&self) -> RedlockResult<()> {
+        self.redlock.unlock(&self.resource_name, &self.value).await
+    }
+
+    // Extend the TTL of acquired lock.
+    pub async fn extend(&self, ttl: Duration) -> RedlockResult<AsyncLock<'a>> {
+        self.redlock.redlock.check_unexpired(self.valid_until)?;
+
+        self.redlock.extend(&self.resource_name, &self.value, ttl).await
+    }
+}
+
+// AsyncRedlock runs the Redlock algorithm on top of asynchronous redis
+// connections, requesting all the servers concurrently.
+#[derive(Debug, Clone)]
+pub struct AsyncRedlock {
+    redlock: Redlock,
+}
+
+impl AsyncRedlock {
+    // Create a new async redlock instance.
+    pub fn new<T: redis::IntoConnectionInfo>(config: Config<T>) -> RedlockResult<AsyncRedlock> {
+        Ok(AsyncRedlock { redlock: Redlock::new(config)? })
+    }
+
+    // Locks the given resource using the Redlock algorithm.
+    pub async fn lock(&self, resource_name: &str, ttl: Duration) -> RedlockResult<AsyncLock<'_>> {
+        self.request(RequestInfo::Lock, resource_name, ttl).await
+    }
+
+    async fn extend(&self,
+                    resource_name: &str,
+                    value: &str,
+                    ttl: Duration)
+                    -> RedlockResult<AsyncLock<'_>> {
+        self.request(RequestInfo::Extend { resource_value: value },
+                     resource_name,
+                     ttl)
+            .await
+    }
+
+    // Locks the given resource whenever it becomes available: the stream
+    // keeps attempting, sleeping with tokio::time between the attempts, and
+    // yields a lock once it's acquired, regardless of the retry count. It
+    // is meant to be polled among other events, e.g. in select!, and can be
+    // dropped at any time: the servers locked by an attempt in flight are
+    // then released in the background. Once a lock is yielded, polling
+    // again waits for it to be released or to expire, and yields the next
+    // one. The stream ends after yielding an error which retrying would not
+    // fix, e.g. InvalidTtl, the servers failing are retried.
+    pub fn lock_stream<'a>(&'a self,
+                           resource_name: &'a str,
+                           ttl: Duration)
+                           -> impl Stream<Item = RedlockResult<AsyncLock<'a>>> + 'a {
+        stream::unfold(Some(0_u32), move |attempts| async move {
+            let mut attempts = attempts?;
+            loop {
+                attempts = attempts.saturating_add(1);
+                match self.attempt(&RequestInfo::Lock, resource_name, ttl, attempts).await {
+                    Ok(Attempt::Acquired(lock)) => return Some((Ok(lock), Some(0))),
+                    Ok(Attempt::Failed(_)) => {}
+                    Err(err) => return Some((Err(err), None)),
+                }
+                tokio::time::sleep(self.redlock.get_retry_timeout(attempts)).await;
+            }
+        })
+    }
+
+    async fn request(&self,
+                     info: RequestInfo<'_>,
+                     resource_name: &str,
+                     ttl: Duration)
+                     -> RedlockResult<AsyncLock<'_>> {
+        let mut attempts = 0;
+        let start = Instant::now();
+        let mut timings = Vec::new();
+        let mut node_results = Vec::new();
+
+        while attempts < self.redlock.retry_count {
+            attempts += 1;
+            let attempt_start = start.elapsed();
+            match self.attempt(&info, resource_name, ttl, attempts).await? {
+                Attempt::Acquired(lock) => return Ok(lock),
+                Attempt::Failed(results) => node_results = results,
+            }
+            let sleep = self.redlock.get_retry_timeout(attempts);
+            timings.push(AttemptTiming {
+                start: attempt_start,
+                duration: start.elapsed() - attempt_start,
+                votes: node_results
+                    .iter()
+                    .filter(|outcome| matches!(**outcome, NodeOutcome::Acquired))
+                    .count(),
+                sleep,
+            });
+            tokio::time::sleep(sleep).await;
+        }
+
+        // Exceed the retry count, return the error
+        let resource_name = String::from(resource_name);
+        match info {
+            RequestInfo::Lock => {
+                Err(self.redlock.lock_failed(resource_name, attempts, node_results, timings))
+            }
+            RequestInfo::Extend { .. } => {
+                // Every server failed, return the first error as the cause
+                if node_results.iter().all(|outcome| matches!(*outcome, NodeOutcome::Error(..))) {
+                    if let NodeOutcome::Error(_, err) = node_results.swap_remove(0) {
+                        return Err(RedlockError::AllNodesFailed(Box::new(err)));
+                    }
+                }
+                let votes = node_results
+                    .iter()
+                    .filter(|outcome| matches!(**outcome, NodeOutcome::Acquired))
+                    .count();
+                Err(RedlockError::UnableToExtend(resource_name, votes, self.redlock.quorum, attempts))
+            }
+        }
+    }
+
+    // Make a single attempt, releasing the servers locked by it if it fails
+    // or if it's dropped before it completes.
+    async fn attempt(&self,
+                     info: &RequestInfo<'_>,
+                     resource_name: &str,
+                     ttl: Duration,
+                     attempts: u32)
+                     -> RedlockResult<Attempt<'_>> {
+        let key = self.redlock.key(resource_name);
+        let ttl = self.redlock.jitter_ttl(ttl);
+        let drift = self.redlock.get_drift(&ttl);
+
+        // Start time of this attempt
+        let start = self.redlock.clock.now();
+        let start_instant = self.redlock.clock.instant();
+        let (expiration, valid_until) = expirations(start, start_instant, ttl, drift)?;
+
+        let value: String = match *info {
+            RequestInfo::Lock => util::get_random_token(self.redlock.token_len),
+            RequestInfo::Extend { resource_value } => String::from(resource_value),
+        };
+        // An extension does not release the lock when dropped, it's kept
+        // until it expires like on a failed extension
+        let pending = match *info {
+            RequestInfo::Lock => Some(Pending::new(self, resource_name, &value)),
+            RequestInfo::Extend { .. } => None,
+        };
+
+        let (key_ref, value_ref, ttl_ref) = (&key, &value, &ttl);
+        let requests = self.redlock.nodes.iter().map(|node| async move {
+            match *info {
+                RequestInfo::Lock => lock(node, key_ref, value_ref, ttl_ref).await,
+                RequestInfo::Extend { .. } => extend(node, key_ref, value_ref, ttl_ref).await,
+            }
+        });
+        let mut node_results = self.redlock.node_outcomes(join_all(requests).await);
+        let votes = node_results
+            .iter()
+            .filter(|outcome| matches!(**outcome, NodeOutcome::Acquired))
+            .count();
+
+        let now = self.redlock.clock.instant();
+        let lock = AsyncLock {
+            redlock: self,
+            resource_name: String::from(resource_name),
+            value,
+            expiration,
+            valid_until,
+            acquisition_time: now.saturating_duration_since(start_instant),
+            attempts,
+        };
+
+        // suceess: aquire the lock
+        let min_valid_until = now.checked_add(self.redlock.min_validity);
+        if votes >= self.redlock.quorum &&
+           min_valid_until.is_some_and(|min_valid_until| lock.valid_until > min_valid_until) {
+            if let Some(pending) = pending {
+                pending.disarm();
+            }
+            self.redlock.track(resource_name, &lock.value);
+            return Ok(Attempt::Acquired(lock));
+        }
+
+        // fail: releases all aquired locks and retry, a failed extension is
+        // left to expire since the lock it extends is still valid
+        if let Some(pending) = pending {
+            if votes > 0 {
+                let _ = self.rollback(resource_name, &lock.value, &mut node_results).await;
+            }
+            pending.disarm();
+        }
+        Ok(Attempt::Failed(node_results))
+    }
+
+    // Release the locks of a failed attempt from the servers which granted
+    // it in a single round, see Redlock::rolled_back.
+    async fn rollback(&self,
+                      resource_name: &str,
+                      value: &str,
+                      node_results: &mut [NodeOutcome])
+                      -> RedlockResult<()> {
+        let key = self.redlock.key(resource_name);
+        let granted: Vec<usize> = (0..node_results.len())
+            .filter(|&index| matches!(node_results[index], NodeOutcome::Acquired))
+            .collect();
+        let requests = granted
+            .iter()
+            .map(|&index| compare_and_del(&self.redlock.nodes[index], &key, value));
+        let request_results = join_all(requests).await;
+        let request_results = granted.iter().copied().zip(request_results).collect();
+        self.redlock.rolled_back(resource_name, value, node_results, request_results)
+    }
+
+    // Release an attempt dropped in flight from every server in a single
+    // round, see Redlock::released_everywhere.
+    async fn release_dropped(&self, resource_name: &str, value: &str) -> RedlockResult<()> {
+        let key = self.redlock.key(resource_name);
+        let requests = self.redlock
+            .nodes
+            .iter()
+            .map(|node| compare_and_del(node, &key, value));
+        let request_results = join_all(requests).await;
+        self.redlock.released_everywhere(resource_name, value, request_results)
+    }
+
+    async fn unlock(&self, resource_name: &str, value: &str) -> RedlockResult<()> {
+        self.unlock_outcomes(resource_name, value).await.0
+    }
+
+    // Unlock the resource, along with the results of the servers of the
+    // last attempt.
+    async fn unlock_outcomes(&self,
+                             resource_name: &str,
+                             value: &str)
+                             -> (RedlockResult<()>, Vec<NodeOutcome>) {
+        let mut attempts = 0;
+        let mut votes = 0;
+        let mut node_results = Vec::new();
+        let key = self.redlock.key(resource_name);
+
+        while attempts < self.redlock.unlock_retry_count {
+            attempts += 1;
+
+            let requests = self.redlock
+                .nodes
+                .iter()
+                .map(|node| unlock(node, &key, value));
+            node_results = self.redlock.node_outcomes(join_all(requests).await);
+            votes = node_results
+                .iter()
+                .filter(|outcome| matches!(**outcome, NodeOutcome::Acquired))
+                .count();
+
+            if votes >= self.redlock.quorum {
+                self.redlock.untrack(resource_name, value);
+                return (Ok(()), node_results);
+            }
+
+            if attempts < self.redlock.unlock_retry_count {
+                tokio::time::sleep(self.redlock.get_unlock_retry_timeout(attempts)).await;
+            }
+        }
+
+        // Exceed the retry count, return the error
+        (Err(RedlockError::UnableToUnlock(String::from(resource_name),
+                                          votes,
+                                          self.redlock.quorum,
+                                          attempts)),
+         node_results)
+    }
+}
+
+async fn lock(node: &Node,
+              resource_name: &str,
+              value: &str,
+              ttl: &Duration)
+              -> RedlockResult<bool> {
+    let (mut con, scripts) = node.get_async().await?;
+    match scripts
+              .lock
+              .key(resource_name)
+              .arg(value)
+              .arg(util::num_milliseconds(ttl))
+              .invoke_async::<Option<()>>(&mut con)
+              .await? {
+        Some(_) => Ok(true),
+        _ => Ok(false),
+    }
+}
+
+async fn unlock(node: &Node, resource_name: &str, value: &str) -> RedlockResult<bool> {
+    Ok(compare_and_del(node, resource_name, value).await? == UnlockOutcome::Released)
+}
+
+async fn compare_and_del(node: &Node,
+                         resource_name: &str,
+                         value: &str)
+                         -> RedlockResult<UnlockOutcome> {
+    let (mut con, scripts) = node.get_async().await?;
+    match scripts
+              .unlock
+              .key(resource_name)
+              .arg(value)
+              .invoke_async::<i32>(&mut con)
+              .await? {
+        1 => Ok(UnlockOutcome::Released),
+        -1 => Ok(UnlockOutcome::HeldByOther),
+        _ => Ok(UnlockOutcome::NotPresent),
+    }
+}
+
+async fn extend(node: &Node,
+                resource_name: &str,
+                value: &str,
+                ttl: &Duration)
+                -> RedlockResult<bool> {
+    let (mut con, scripts) = node.get_async().await?;
+    match scripts
+              .extend
+              .key(resource_name)
+              .arg(value)
+              .arg(util::num_milliseconds(ttl))
+              .invoke_async::<i32>(&mut con)
+              .await? {
+        1 => Ok(true),
+        _ => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use redis::Commands;
+    use crate::redlock::BackoffStrategy;
+
+    fn new_redlock() -> AsyncRedlock {
+        AsyncRedlock::new(Config::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_async_lock() {
+        let redlock = new_redlock();
+        let resource_name = "test_async_lock";
+        let one_second = Duration::from_millis(1000);
+
+        let lock = redlock.lock(resource_name, one_second).await.unwrap();
+        assert!(lock.expiration < SystemTime::now() + one_second);
+        assert!(lock.acquisition_time() < one_second);
+        assert_eq!(lock.attempts(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_async_lock_with_huge_ttl() {
+        let redlock = new_redlock();
+        match redlock
+                  .lock("test_async_lock_with_huge_ttl", Duration::from_secs(u64::MAX))
+                  .await {
+            Err(RedlockError::InvalidTtl(_)) => {}
+            res => panic!("expected InvalidTtl, got {:?}", res.map(|lock| lock.attempts())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_lock_twice() {
+        let redlock = AsyncRedlock::new::<&str>(Config {
+                                                    retry_count: 2,
+                                                    retry_delay: Duration::from_millis(100),
+                                                    retry_jitter: 50,
+                                                    ..Config::default()
+                                                })
+                .unwrap();
+        let resource_name = "test_async_lock_twice";
+        let one_second = Duration::from_millis(1000);
+
+        let _lock = redlock.lock(resource_name, one_second).await.unwrap();
+        assert!(redlock.lock(resource_name, one_second).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_async_lock_contended() {
+        let redlock = AsyncRedlock::new(Config {
+                                            retry_count: 3,
+                                            retry_delay: Duration::from_millis(200),
+                                            retry_jitter: 0,
+                                            backoff: BackoffStrategy::Constant,
+                                            ..Config::default()
+                                        })
+                .unwrap();
+        let resource_name = "test_async_lock_contended";
+        let one_second = Duration::from_millis(1000);
+
+        // The failed attempts have nothing to release, only the retry
+        // delays are waited for
+        let lock = redlock.lock(resource_name, one_second * 5).await.unwrap();
+        let start = Instant::now();
+        assert!(redlock.lock(resource_name, one_second).await.is_err());
+        assert!(start.elapsed() < one_second);
+        lock.unlock().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_async_lock_all_nodes_failed() {
+        let redlock = AsyncRedlock::new(Config {
+                                            addrs: vec!["redis://127.0.0.1:1"],
+                                            retry_count: 1,
+                                            ..Config::default()
+                                        })
+                .unwrap();
+
+        match redlock
+                  .lock("test_async_lock_all_nodes_failed", Duration::from_millis(1000))
+                  .await {
+            Err(RedlockError::AllNodesFailed(_)) => {}
+            _ => panic!("expected AllNodesFailed"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_unlock() {
+        let redlock = new_redlock();
+        let resource_name = "test_async_unlock";
+        let lock = redlock
+            .lock(resource_name, Duration::from_millis(2000))
+            .await
+            .unwrap();
+        lock.unlock().await.unwrap();
+
+        let res: Option<String> = redis::Client::open("redis://127.0.0.1")
+            .unwrap()
+            .get_connection()
+            .unwrap()
+            .get(resource_name)
+            .unwrap();
+        assert!(res.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_async_extend() {
+        let redlock = new_redlock();
+        let resource_name = "test_async_extend";
+        let lock = redlock
+            .lock(resource_name, Duration::from_millis(2000))
+            .await
+            .unwrap();
+        let lock_extended = lock.extend(Duration::from_millis(2000)).await.unwrap();
+
+        assert_eq!(lock_extended.value, lock.value);
+        assert!(lock_extended.expiration < SystemTime::now() + Duration::from_millis(2000));
+    }
+
+    fn get(resource_name: &str) -> Option<String> {
+        redis::Client::open("redis://127.0.0.1")
+            .unwrap()
+            .get_connection()
+            .unwrap()
+            .get(resource_name)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_async_failed_extend_kept() {
+        let resource_name = "test_async_failed_extend_kept";
+        let redlock = AsyncRedlock::new(Config {
+                                            retry_count: 1,
+                                            ..Config::with_addrs(vec!["redis://127.0.0.1/0",
+                                                                      "redis://127.0.0.1/1",
+                                                                      "redis://127.0.0.1/2"])
+                                        })
+                .unwrap();
+        let lock = redlock
+            .lock(resource_name, Duration::from_millis(2000))
+            .await
+            .unwrap();
+        let mut cons: Vec<redis::Connection> = (0..3)
+            .map(|db| {
+                     redis::Client::open(format!("redis://127.0.0.1/{}", db))
+                         .unwrap()
+                         .get_connection()
+                         .unwrap()
+                 })
+            .collect();
+        for con in &mut cons[..2] {
+            let _: () = con.del(resource_name).unwrap();
+        }
+
+        // The extension fails, but the lock is left on the last server
+        assert!(lock.extend(Duration::from_millis(2000)).await.is_err());
+        let value: Option<String> = cons[2].get(resource_name).unwrap();
+        assert_eq!(value.as_deref(), Some(&lock.value[..]));
+        let _: () = cons[2].del(resource_name).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_async_lock_stream() {
+        let redlock = AsyncRedlock::new(Config {
+                                            retry_delay: Duration::from_millis(50),
+                                            retry_jitter: 0,
+                                            ..Config::default()
+                                        })
+                .unwrap();
+        let resource_name = "test_async_lock_stream";
+        let ttl = Duration::from_millis(2000);
+        let held = redlock.lock(resource_name, ttl).await.unwrap();
+
+        // The stream keeps waiting past the retry count while the lock is held
+        let mut locks = Box::pin(redlock.lock_stream(resource_name, ttl));
+        tokio::select! {
+            lock = locks.next() => panic!("expected the lock to be held, got {:?}", lock),
+            _ = tokio::time::sleep(Duration::from_millis(700)) => {}
+        }
+
+        held.unlock().await.unwrap();
+        let lock = tokio::time::timeout(Duration::from_millis(1000), locks.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(get(resource_name), Some(lock.value.clone()));
+        lock.unlock().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_async_lock_stream_invalid_ttl() {
+        let redlock = new_redlock();
+        let mut locks = Box::pin(redlock.lock_stream("test_async_lock_stream_invalid_ttl",
+                                                     Duration::from_secs(u64::MAX)));
+        assert!(matches!(locks.next().await, Some(Err(RedlockError::InvalidTtl(_)))));
+        assert!(locks.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_async_pending_released_on_drop() {
+        let redlock = new_redlock();
+        let resource_name = "test_async_pending_released_on_drop";
+        let lock = redlock
+            .lock(resource_name, Duration::from_millis(2000))
+            .await
+            .unwrap();
+
+        // An attempt dropped in flight releases what it has locked
+        drop(Pending::new(&redlock, resource_name, &lock.value));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(get(resource_name).is_none());
+
+        // Unless it has completed
+        let lock = redlock
+            .lock(resource_name, Duration::from_millis(2000))
+            .await
+            .unwrap();
+        Pending::new(&redlock, resource_name, &lock.value).disarm();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(get(resource_name), Some(lock.value.clone()));
+        lock.unlock().await.unwrap();
+    }
+}
@@ -0,0 +1,744 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "test-util")]
+use std::collections::HashMap;
+#[cfg(feature = "test-util")]
+use std::sync::Mutex;
+#[cfg(feature = "test-util")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "test-util")]
+use std::time::Instant;
+use crate::errors::RedlockResult;
+use crate::pool::ConnectionPool;
+use crate::redlock::UnlockOutcome;
+use crate::scripts::{EXTEND_WITH_DATA, LOCK_FENCED, LOCK_OR_EXISTING, LOCK_READ, LOCK_WRITE, PTTL,
+                     TRANSFER};
+#[cfg(feature = "async")]
+use crate::scripts::Scripts;
+use crate::util;
+
+// SetOutcome is the result of setting a lock on a single server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOutcome {
+    // The lock has been set.
+    Acquired,
+    // The resource is already locked.
+    AlreadyHeld,
+}
+
+// LockBackend abstracts the operations a Redlock runs against a single
+// server. The redis servers are the backends by default, others can be
+// given to Redlock::with_backends, e.g. the in-memory backend of the
+// test-util feature.
+pub trait LockBackend: Send + Sync {
+    // Set the key to the value with the given TTL if the key does not
+    // exist.
+    fn set_nx_px(&self, key: &str, value: &str, ttl: &Duration) -> RedlockResult<SetOutcome>;
+
+    // Delete the key if it is set to the value.
+    fn compare_and_del(&self, key: &str, value: &str) -> RedlockResult<UnlockOutcome>;
+
+    // Set the TTL of the key if it is set to the value, returns whether it
+    // has been set.
+    fn compare_and_pexpire(&self, key: &str, value: &str, ttl: &Duration) -> RedlockResult<bool>;
+
+    // Get the remaining TTL of the key if it is set to the value, None if
+    // it is not set to the value or has expired.
+    fn compare_and_pttl(&self, key: &str, value: &str) -> RedlockResult<Option<Duration>>;
+
+    // Get the value of the key.
+    fn get(&self, key: &str) -> RedlockResult<Option<String>>;
+
+    // Like set_nx_px, and increment the counter at fence_key if the key has
+    // been set, returns the new value of the counter in that case.
+    fn set_nx_px_fenced(&self,
+                        key: &str,
+                        fence_key: &str,
+                        value: &str,
+                        ttl: &Duration)
+                        -> RedlockResult<Option<u64>>;
+
+    // Like set_nx_px, but also refreshes the TTL if the key is already set
+    // to the value. The default implementation is not atomic.
+    fn set_nx_px_or_existing(&self, key: &str, value: &str, ttl: &Duration) -> RedlockResult<bool> {
+        Ok(self.set_nx_px(key, value, ttl)? == SetOutcome::Acquired ||
+           self.compare_and_pexpire(key, value, ttl)?)
+    }
+
+    // Set the key to the new value with the given TTL if it is set to the
+    // value, returns whether it has been set. The default implementation
+    // is not atomic, another client may set the key in between.
+    fn compare_and_swap(&self,
+                        key: &str,
+                        value: &str,
+                        new_value: &str,
+                        ttl: &Duration)
+                        -> RedlockResult<bool> {
+        if self.compare_and_del(key, value)? != UnlockOutcome::Released &&
+           self.get(key)?.as_deref() != Some(new_value) {
+            return Ok(false);
+        }
+        Ok(self.set_nx_px(key, new_value, ttl)? == SetOutcome::Acquired ||
+           self.compare_and_pexpire(key, new_value, ttl)?)
+    }
+
+    // Add the value to the readers kept at readers_key with the given TTL,
+    // unless the key is set by a writer. Returns whether it has been added.
+    // The default implementation does not support the read/write locks.
+    fn add_reader(&self,
+                  key: &str,
+                  readers_key: &str,
+                  value: &str,
+                  ttl: &Duration)
+                  -> RedlockResult<bool> {
+        let _ = (key, readers_key, value, ttl);
+        Err(unsupported("read/write locks"))
+    }
+
+    // Remove the value from the readers kept at readers_key, returns
+    // whether it was there.
+    fn remove_reader(&self, readers_key: &str, value: &str) -> RedlockResult<bool> {
+        let _ = (readers_key, value);
+        Err(unsupported("read/write locks"))
+    }
+
+    // Like set_nx_px, but only if there is no reader at readers_key.
+    // Returns whether the key has been set.
+    fn set_nx_px_exclusive(&self,
+                           key: &str,
+                           readers_key: &str,
+                           value: &str,
+                           ttl: &Duration)
+                           -> RedlockResult<bool> {
+        let _ = (key, readers_key, value, ttl);
+        Err(unsupported("read/write locks"))
+    }
+
+    // Like compare_and_pexpire, and set data_key to the data with the same
+    // TTL if the TTL of the key has been set, see Lock::extend_with_data.
+    // The default implementation does not support the companion data.
+    fn compare_and_pexpire_with_data(&self,
+                                     key: &str,
+                                     data_key: &str,
+                                     value: &str,
+                                     data: &[u8],
+                                     ttl: &Duration)
+                                     -> RedlockResult<bool> {
+        let _ = (key, data_key, value, data, ttl);
+        Err(unsupported("companion data"))
+    }
+
+    // Like set_nx_px, compare_and_del and compare_and_pexpire, with a key
+    // and a value which may not be UTF-8, see Redlock::lock_bytes. The
+    // default implementations only support the UTF-8 ones.
+    fn set_nx_px_bytes(&self,
+                       key: &[u8],
+                       value: &[u8],
+                       ttl: &Duration)
+                       -> RedlockResult<SetOutcome> {
+        self.set_nx_px(utf8(key)?, utf8(value)?, ttl)
+    }
+
+    fn compare_and_del_bytes(&self, key: &[u8], value: &[u8]) -> RedlockResult<UnlockOutcome> {
+        self.compare_and_del(utf8(key)?, utf8(value)?)
+    }
+
+    fn compare_and_pexpire_bytes(&self,
+                                 key: &[u8],
+                                 value: &[u8],
+                                 ttl: &Duration)
+                                 -> RedlockResult<bool> {
+        self.compare_and_pexpire(utf8(key)?, utf8(value)?, ttl)
+    }
+
+    // Check whether the backend answers.
+    fn ping(&self) -> RedlockResult<()> {
+        Ok(())
+    }
+}
+
+fn utf8(bytes: &[u8]) -> RedlockResult<&str> {
+    std::str::from_utf8(bytes).map_err(|_| unsupported("binary keys and values"))
+}
+
+fn unsupported(feature: &'static str) -> crate::errors::RedlockError {
+    redis::RedisError::from((redis::ErrorKind::InvalidClientConfig,
+                             "The backend does not support the request",
+                             String::from(feature)))
+            .into()
+}
+
+impl LockBackend for ConnectionPool {
+    fn set_nx_px(&self, key: &str, value: &str, ttl: &Duration) -> RedlockResult<SetOutcome> {
+        self.set_nx_px_bytes(key.as_bytes(), value.as_bytes(), ttl)
+    }
+
+    fn set_nx_px_bytes(&self,
+                       key: &[u8],
+                       value: &[u8],
+                       ttl: &Duration)
+                       -> RedlockResult<SetOutcome> {
+        match self.scripts()
+                  .lock
+                  .key(key)
+                  .arg(value)
+                  .arg(util::num_milliseconds(ttl))
+                  .invoke::<Option<String>>(&mut *self.get()?)? {
+            Some(ref reply) if reply == "OK" => Ok(SetOutcome::Acquired),
+            None => Ok(SetOutcome::AlreadyHeld),
+            Some(_) => {
+                Err(redis::RedisError::from((redis::ErrorKind::TypeError,
+                                             "Unexpected reply of the lock script"))
+                            .into())
+            }
+        }
+    }
+
+    fn compare_and_del(&self, key: &str, value: &str) -> RedlockResult<UnlockOutcome> {
+        self.compare_and_del_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    fn compare_and_del_bytes(&self, key: &[u8], value: &[u8]) -> RedlockResult<UnlockOutcome> {
+        match self.scripts()
+                  .unlock
+                  .key(key)
+                  .arg(value)
+                  .invoke::<i32>(&mut *self.get()?)? {
+            1 => Ok(UnlockOutcome::Released),
+            -1 => Ok(UnlockOutcome::HeldByOther),
+            _ => Ok(UnlockOutcome::NotPresent),
+        }
+    }
+
+    fn compare_and_pexpire(&self, key: &str, value: &str, ttl: &Duration) -> RedlockResult<bool> {
+        self.compare_and_pexpire_bytes(key.as_bytes(), value.as_bytes(), ttl)
+    }
+
+    fn compare_and_pexpire_bytes(&self,
+                                 key: &[u8],
+                                 value: &[u8],
+                                 ttl: &Duration)
+                                 -> RedlockResult<bool> {
+        match self.scripts()
+                  .extend
+                  .key(key)
+                  .arg(value)
+                  .arg(util::num_milliseconds(ttl))
+                  .invoke::<i32>(&mut *self.get()?)? {
+            1 => Ok(true),
+            _ => Ok(false),
+        }
+    }
+
+    fn compare_and_pttl(&self, key: &str, value: &str) -> RedlockResult<Option<Duration>> {
+        match PTTL.key(key).arg(value).invoke::<Option<i64>>(&mut *self.get()?)? {
+            Some(pttl) if pttl >= 0 => Ok(Some(Duration::from_millis(pttl as u64))),
+            _ => Ok(None),
+        }
+    }
+
+    fn get(&self, key: &str) -> RedlockResult<Option<String>> {
+        Ok(redis::cmd("GET").arg(key).query(&mut *self.get()?)?)
+    }
+
+    fn set_nx_px_fenced(&self,
+                        key: &str,
+                        fence_key: &str,
+                        value: &str,
+                        ttl: &Duration)
+                        -> RedlockResult<Option<u64>> {
+        match LOCK_FENCED
+                  .key(key)
+                  .key(fence_key)
+                  .arg(value)
+                  .arg(util::num_milliseconds(ttl))
+                  .invoke::<(i32, u64)>(&mut *self.get()?)? {
+            (1, token) => Ok(Some(token)),
+            _ => Ok(None),
+        }
+    }
+
+    fn set_nx_px_or_existing(&self, key: &str, value: &str, ttl: &Duration) -> RedlockResult<bool> {
+        match LOCK_OR_EXISTING
+                  .key(key)
+                  .arg(value)
+                  .arg(util::num_milliseconds(ttl))
+                  .invoke::<i32>(&mut *self.get()?)? {
+            1 => Ok(true),
+            _ => Ok(false),
+        }
+    }
+
+    fn compare_and_swap(&self,
+                        key: &str,
+                        value: &str,
+                        new_value: &str,
+                        ttl: &Duration)
+                        -> RedlockResult<bool> {
+        match TRANSFER
+                  .key(key)
+                  .arg(value)
+                  .arg(new_value)
+                  .arg(util::num_milliseconds(ttl))
+                  .invoke::<i32>(&mut *self.get()?)? {
+            1 => Ok(true),
+            _ => Ok(false),
+        }
+    }
+
+    fn compare_and_pexpire_with_data(&self,
+                                     key: &str,
+                                     data_key: &str,
+                                     value: &str,
+                                     data: &[u8],
+                                     ttl: &Duration)
+                                     -> RedlockResult<bool> {
+        match EXTEND_WITH_DATA
+                  .key(key)
+                  .key(data_key)
+                  .arg(value)
+                  .arg(util::num_milliseconds(ttl))
+                  .arg(data)
+                  .invoke::<i32>(&mut *self.get()?)? {
+            1 => Ok(true),
+            _ => Ok(false),
+        }
+    }
+
+    fn add_reader(&self,
+                  key: &str,
+                  readers_key: &str,
+                  value: &str,
+                  ttl: &Duration)
+                  -> RedlockResult<bool> {
+        match LOCK_READ
+                  .key(key)
+                  .key(readers_key)
+                  .arg(value)
+                  .arg(util::num_milliseconds(ttl))
+                  .invoke::<i32>(&mut *self.get()?)? {
+            1 => Ok(true),
+            _ => Ok(false),
+        }
+    }
+
+    fn remove_reader(&self, readers_key: &str, value: &str) -> RedlockResult<bool> {
+        let removed: i32 = redis::cmd("ZREM")
+            .arg(readers_key)
+            .arg(value)
+            .query(&mut *self.get()?)?;
+        Ok(removed == 1)
+    }
+
+    fn set_nx_px_exclusive(&self,
+                           key: &str,
+                           readers_key: &str,
+                           value: &str,
+                           ttl: &Duration)
+                           -> RedlockResult<bool> {
+        match LOCK_WRITE
+                  .key(key)
+                  .key(readers_key)
+                  .arg(value)
+                  .arg(util::num_milliseconds(ttl))
+                  .invoke::<i32>(&mut *self.get()?)? {
+            1 => Ok(true),
+            _ => Ok(false),
+        }
+    }
+
+    fn ping(&self) -> RedlockResult<()> {
+        redis::cmd("PING").query::<String>(&mut *self.get()?)?;
+        Ok(())
+    }
+}
+
+// A server of a Redlock, either a redis server or a custom backend.
+#[derive(Clone)]
+pub(crate) enum Node {
+    Redis(ConnectionPool),
+    Backend(Arc<dyn LockBackend>),
+}
+
+impl Node {
+    pub(crate) fn backend(&self) -> &dyn LockBackend {
+        match *self {
+            Node::Redis(ref pool) => pool,
+            Node::Backend(ref backend) => &**backend,
+        }
+    }
+
+    // Open an asynchronous connection along with the scripts to run on it,
+    // only the redis servers support it.
+    #[cfg(feature = "async")]
+    pub(crate) async fn get_async(&self)
+                                  -> RedlockResult<(redis::aio::MultiplexedConnection, &Scripts)> {
+        match *self {
+            Node::Redis(ref pool) => Ok((pool.get_async().await?, pool.scripts())),
+            Node::Backend(_) => {
+                Err(redis::RedisError::from((redis::ErrorKind::InvalidClientConfig,
+                                             "Custom backends do not support async requests"))
+                            .into())
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Node::Redis(ref pool) => f.debug_tuple("Redis").field(pool).finish(),
+            Node::Backend(_) => f.write_str("Backend"),
+        }
+    }
+}
+
+// InMemoryBackend keeps the locks in memory, to test the code using a
+// Redlock without any redis server. The failures of a server and its
+// clock going forward can be simulated.
+#[cfg(feature = "test-util")]
+#[derive(Debug, Default)]
+pub struct InMemoryBackend {
+    state: Mutex<State>,
+    down: AtomicBool,
+}
+
+#[cfg(feature = "test-util")]
+#[derive(Debug, Default)]
+struct State {
+    entries: HashMap<String, (String, Instant)>,
+    counters: HashMap<String, u64>,
+    // The readers of the read/write locks by readers key, with the time
+    // they expire at.
+    readers: HashMap<String, HashMap<String, Instant>>,
+    // How far the clock of the backend is ahead of the real one.
+    skew: Duration,
+}
+
+#[cfg(feature = "test-util")]
+impl State {
+    fn now(&self) -> Instant {
+        Instant::now() + self.skew
+    }
+
+    // Get the value of the key, dropping it if it has expired.
+    fn get(&mut self, key: &str) -> Option<&str> {
+        let now = self.now();
+        if self.entries.get(key).is_some_and(|entry| entry.1 <= now) {
+            self.entries.remove(key);
+        }
+        self.entries.get(key).map(|entry| entry.0.as_str())
+    }
+
+    fn set(&mut self, key: &str, value: &str, ttl: &Duration) {
+        let expiration = self.now() + *ttl;
+        self.entries.insert(String::from(key), (String::from(value), expiration));
+    }
+
+    // Get the readers at the key, dropping the expired ones.
+    fn readers(&mut self, readers_key: &str) -> &mut HashMap<String, Instant> {
+        let now = self.now();
+        let readers = self.readers.entry(String::from(readers_key)).or_default();
+        readers.retain(|_, expiration| *expiration > now);
+        readers
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl InMemoryBackend {
+    pub fn new() -> InMemoryBackend {
+        InMemoryBackend::default()
+    }
+
+    // Make every request fail while down, as if the server were
+    // unreachable.
+    pub fn set_down(&self, down: bool) {
+        self.down.store(down, Ordering::SeqCst);
+    }
+
+    // Move the clock of the backend forward, the locks expire accordingly.
+    pub fn advance(&self, duration: Duration) {
+        self.state.lock().unwrap().skew += duration;
+    }
+
+    fn state(&self) -> RedlockResult<std::sync::MutexGuard<'_, State>> {
+        if self.down.load(Ordering::SeqCst) {
+            return Err(redis::RedisError::from((redis::ErrorKind::IoError,
+                                                "The in-memory backend is down"))
+                               .into());
+        }
+        Ok(self.state.lock().unwrap())
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl LockBackend for InMemoryBackend {
+    fn set_nx_px(&self, key: &str, value: &str, ttl: &Duration) -> RedlockResult<SetOutcome> {
+        let mut state = self.state()?;
+        if state.get(key).is_some() {
+            return Ok(SetOutcome::AlreadyHeld);
+        }
+        state.set(key, value, ttl);
+        Ok(SetOutcome::Acquired)
+    }
+
+    fn compare_and_del(&self, key: &str, value: &str) -> RedlockResult<UnlockOutcome> {
+        let mut state = self.state()?;
+        match state.get(key) {
+            Some(current) if current == value => {
+                state.entries.remove(key);
+                Ok(UnlockOutcome::Released)
+            }
+            Some(_) => Ok(UnlockOutcome::HeldByOther),
+            None => Ok(UnlockOutcome::NotPresent),
+        }
+    }
+
+    fn compare_and_pexpire(&self, key: &str, value: &str, ttl: &Duration) -> RedlockResult<bool> {
+        let mut state = self.state()?;
+        if state.get(key) != Some(value) {
+            return Ok(false);
+        }
+        state.set(key, value, ttl);
+        Ok(true)
+    }
+
+    fn compare_and_pttl(&self, key: &str, value: &str) -> RedlockResult<Option<Duration>> {
+        let mut state = self.state()?;
+        if state.get(key) != Some(value) {
+            return Ok(None);
+        }
+        let now = state.now();
+        Ok(state.entries.get(key).map(|entry| entry.1.duration_since(now)))
+    }
+
+    fn get(&self, key: &str) -> RedlockResult<Option<String>> {
+        Ok(self.state()?.get(key).map(String::from))
+    }
+
+    fn set_nx_px_fenced(&self,
+                        key: &str,
+                        fence_key: &str,
+                        value: &str,
+                        ttl: &Duration)
+                        -> RedlockResult<Option<u64>> {
+        let mut state = self.state()?;
+        if state.get(key).is_some() {
+            return Ok(None);
+        }
+        state.set(key, value, ttl);
+        let counter = state.counters.entry(String::from(fence_key)).or_insert(0);
+        *counter += 1;
+        Ok(Some(*counter))
+    }
+
+    fn compare_and_swap(&self,
+                        key: &str,
+                        value: &str,
+                        new_value: &str,
+                        ttl: &Duration)
+                        -> RedlockResult<bool> {
+        let mut state = self.state()?;
+        match state.get(key) {
+            Some(current) if current == value || current == new_value => {
+                state.set(key, new_value, ttl);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn add_reader(&self,
+                  key: &str,
+                  readers_key: &str,
+                  value: &str,
+                  ttl: &Duration)
+                  -> RedlockResult<bool> {
+        let mut state = self.state()?;
+        if state.get(key).is_some() {
+            return Ok(false);
+        }
+        let expiration = state.now() + *ttl;
+        state.readers(readers_key).insert(String::from(value), expiration);
+        Ok(true)
+    }
+
+    fn remove_reader(&self, readers_key: &str, value: &str) -> RedlockResult<bool> {
+        Ok(self.state()?.readers(readers_key).remove(value).is_some())
+    }
+
+    fn set_nx_px_exclusive(&self,
+                           key: &str,
+                           readers_key: &str,
+                           value: &str,
+                           ttl: &Duration)
+                           -> RedlockResult<bool> {
+        let mut state = self.state()?;
+        if state.get(key).is_some() || !state.readers(readers_key).is_empty() {
+            return Ok(false);
+        }
+        state.set(key, value, ttl);
+        Ok(true)
+    }
+
+    fn ping(&self) -> RedlockResult<()> {
+        self.state().map(|_| ())
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::errors::RedlockError;
+    use crate::redlock::{Config, Redlock};
+
+    fn new_redlock(count: usize) -> (Vec<Arc<InMemoryBackend>>, Redlock) {
+        let backends: Vec<Arc<InMemoryBackend>> =
+            (0..count).map(|_| Arc::new(InMemoryBackend::new())).collect();
+        let redlock = Redlock::with_backends(backends
+                                                 .iter()
+                                                 .map(|backend| backend.clone() as Arc<dyn LockBackend>)
+                                                 .collect(),
+                                             Config {
+                                                 retry_count: 2,
+                                                 retry_delay: Duration::from_millis(10),
+                                                 retry_jitter: 10,
+                                                 ..Config::default()
+                                             })
+                .unwrap();
+        (backends, redlock)
+    }
+
+    #[test]
+    fn test_in_memory_lock() {
+        let (backends, redlock) = new_redlock(3);
+        let lock = redlock.lock("resource", Duration::from_millis(1000)).unwrap();
+        assert!(redlock.is_locked("resource").unwrap());
+        assert!(redlock.try_lock("resource", Duration::from_millis(1000)).unwrap().is_none());
+        assert_eq!(backends[0].get("resource").unwrap().as_deref(), Some(lock.value()));
+
+        let lock = lock.extend(Duration::from_millis(2000)).unwrap();
+        lock.unlock().unwrap();
+        assert!(!redlock.is_locked("resource").unwrap());
+    }
+
+    #[test]
+    fn test_in_memory_node_failures() {
+        let (backends, redlock) = new_redlock(3);
+        backends[1].set_down(true);
+        backends[2].set_down(true);
+        assert!(!redlock.has_quorum_available());
+        assert!(redlock.lock("resource", Duration::from_millis(1000)).is_err());
+        // The lock acquired on the only server up has been released
+        assert!(backends[0].get("resource").unwrap().is_none());
+
+        backends[1].set_down(false);
+        backends[2].set_down(false);
+        let lock = redlock.lock("resource", Duration::from_millis(1000)).unwrap();
+        lock.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_in_memory_clock_skew() {
+        let (backends, redlock) = new_redlock(3);
+        let _lock = redlock.lock("resource", Duration::from_millis(1000)).unwrap();
+
+        // The lock has expired on a single server only
+        backends[0].advance(Duration::from_millis(1000));
+        assert!(redlock.is_locked("resource").unwrap());
+
+        backends[1].advance(Duration::from_millis(1000));
+        backends[2].advance(Duration::from_millis(1000));
+        assert!(!redlock.is_locked("resource").unwrap());
+        let lock = redlock.lock("resource", Duration::from_millis(1000)).unwrap();
+        lock.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_in_memory_lock_fenced() {
+        let (_, redlock) = new_redlock(3);
+        let first = redlock.lock_fenced("resource", Duration::from_millis(1000)).unwrap();
+        first.unlock().unwrap();
+        let second = redlock.lock_fenced("resource", Duration::from_millis(1000)).unwrap();
+        assert!(second.fence_token() > first.fence_token());
+    }
+
+    #[test]
+    fn test_in_memory_read_write_locks() {
+        let (_, redlock) = new_redlock(3);
+        let ttl = Duration::from_millis(1000);
+        let first = redlock.lock_read("resource", ttl).unwrap();
+        let second = redlock.lock_read("resource", ttl).unwrap();
+        assert!(redlock.lock_write("resource", ttl).is_err());
+
+        first.unlock().unwrap();
+        second.unlock().unwrap();
+        let writer = redlock.lock_write("resource", ttl).unwrap();
+        assert!(redlock.lock_read("resource", ttl).is_err());
+        writer.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_in_memory_flapping_node() {
+        let backends: Vec<Arc<InMemoryBackend>> =
+            (0..3).map(|_| Arc::new(InMemoryBackend::new())).collect();
+        let redlock = Redlock::with_backends(backends
+                                                 .iter()
+                                                 .map(|backend| backend.clone() as Arc<dyn LockBackend>)
+                                                 .collect(),
+                                             Config {
+                                                 retry_count: 1,
+                                                 breaker_threshold: Some(2),
+                                                 breaker_cooldown: Duration::from_millis(200),
+                                                 ..Config::default()
+                                             })
+                .unwrap();
+
+        // The node fails twice in a row, then is skipped even once it's back
+        backends[2].set_down(true);
+        let lock = redlock.lock("resource", Duration::from_millis(1000)).unwrap();
+        lock.unlock().unwrap();
+        backends[2].set_down(false);
+        let health = redlock.health_check();
+        assert!(health[0].1.is_ok());
+        assert!(matches!(health[2].1, Err(RedlockError::CircuitOpen(_))));
+        let lock = redlock.lock("resource", Duration::from_millis(1000)).unwrap();
+        assert!(backends[2].get("resource").unwrap().is_none());
+        lock.unlock().unwrap();
+
+        // A probe fails after the cooldown, the node is skipped again
+        thread::sleep(Duration::from_millis(250));
+        backends[2].set_down(true);
+        assert!(matches!(redlock.health_check()[2].1, Err(RedlockError::RedisError(_))));
+        backends[2].set_down(false);
+        assert!(matches!(redlock.health_check()[2].1, Err(RedlockError::CircuitOpen(_))));
+
+        // A probe succeeds after the cooldown, the node is used again
+        thread::sleep(Duration::from_millis(250));
+        assert!(redlock.health_check()[2].1.is_ok());
+        let lock = redlock.lock("resource", Duration::from_millis(1000)).unwrap();
+        assert_eq!(backends[2].get("resource").unwrap().as_deref(), Some(lock.value()));
+        lock.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_breaker_threshold_zero() {
+        let res = Redlock::with_backends(vec![Arc::new(InMemoryBackend::new()) as Arc<dyn LockBackend>],
+                                         Config {
+                                             breaker_threshold: Some(0),
+                                             ..Config::default()
+                                         });
+        assert!(matches!(res, Err(RedlockError::InvalidConfig(reason))
+                              if reason.starts_with("breaker_threshold")));
+    }
+
+    #[test]
+    fn test_with_no_backend() {
+        match Redlock::with_backends(Vec::new(), Config::default()) {
+            Err(RedlockError::NoServerError) => {}
+            res => panic!("expected NoServerError, got {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use std::str;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use crate::backend::SetOutcome;
+use crate::errors::{AttemptTiming, NodeOutcome, RedlockError, RedlockResult};
+use crate::quorum::{evaluate_quorum, Decision};
+use crate::redlock::{expirations, Redlock, UnlockOutcome};
+use crate::util;
+
+// BinaryLock represents a lock of a resource whose name or value may not be
+// UTF-8, e.g. a raw UUID, acquired by Redlock::lock_bytes.
+#[derive(Debug)]
+pub struct BinaryLock<'a> {
+    redlock: &'a Redlock,
+    resource_name: Vec<u8>,
+    value: Vec<u8>,
+    expiration: SystemTime,
+    valid_until: Instant,
+}
+
+impl<'a> BinaryLock<'a> {
+    pub fn resource_name(&self) -> &[u8] {
+        &self.resource_name
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    // The time the lock expires at, with the clock drift subtracted.
+    pub fn expiration(&self) -> SystemTime {
+        self.expiration
+    }
+
+    // The time left until the lock expires, or None if it has already
+    // expired.
+    pub fn remaining_ttl(&self) -> Option<Duration> {
+        self.redlock.remaining_ttl(self.valid_until)
+    }
+
+    // Release the acquired lock.
+    pub fn unlock(&self) -> RedlockResult<()> {
+        self.redlock.unlock_bytes(&self.resource_name, &self.value)
+    }
+
+    // Extend the TTL of acquired lock.
+    pub fn extend(&self, ttl: Duration) -> RedlockResult<BinaryLock<'a>> {
+        self.redlock.check_unexpired(self.valid_until)?;
+
+        self.redlock.request_bytes(&self.resource_name, Some(&self.value), true, ttl)
+    }
+}
+
+impl Redlock {
+    // Locks the given resource like lock, with a name which may not be
+    // UTF-8. The keys are binary safe, the name is appended to the key
+    // prefix as is. A UTF-8 name is locked under the same key as with lock,
+    // so both lock the same resource, but the key mapper can only map the
+    // UTF-8 names. The custom backends only support the UTF-8 names and
+    // values, unless they implement the byte requests.
+    pub fn lock_bytes(&self, resource_name: &[u8], ttl: Duration) -> RedlockResult<BinaryLock<'_>> {
+        self.request_bytes(resource_name, None, false, ttl)
+    }
+
+    // Locks the given resource like lock_bytes with the given value instead
+    // of a random one, e.g. an identifier of the owner.
+    pub fn lock_bytes_with_value(&self,
+                                 resource_name: &[u8],
+                                 value: &[u8],
+                                 ttl: Duration)
+                                 -> RedlockResult<BinaryLock<'_>> {
+        if value.is_empty() {
+            return Err(RedlockError::InvalidValue);
+        }
+        self.request_bytes(resource_name, Some(value), false, ttl)
+    }
+
+    // Lock the resource with the given value, or a random one if there is
+    // none, or extend it.
+    fn request_bytes(&self,
+                     resource_name: &[u8],
+                     resource_value: Option<&[u8]>,
+                     extend: bool,
+                     ttl: Duration)
+                     -> RedlockResult<BinaryLock<'_>> {
+        let key = self.key_bytes(resource_name);
+
+        let mut attempts = 0;
+        let mut votes = 0;
+        let request_start = Instant::now();
+        let mut timings = Vec::new();
+        let mut node_results = Vec::new();
+        while attempts < self.retry_count {
+            attempts += 1;
+            let attempt_start = request_start.elapsed();
+
+            let ttl = self.jitter_ttl(ttl);
+            let drift = self.get_drift(&ttl);
+            let value = match resource_value {
+                Some(value) => value.to_vec(),
+                None => util::get_random_token(self.token_len).into_bytes(),
+            };
+            let start = self.clock.now();
+            let start_instant = self.clock.instant();
+            let (expiration, valid_until) = expirations(start, start_instant, ttl, drift)?;
+
+            let request_results = self.request_all(|backend| if extend {
+                backend.compare_and_pexpire_bytes(&key, &value, &ttl)
+            } else {
+                Ok(backend.set_nx_px_bytes(&key, &value, &ttl)? == SetOutcome::Acquired)
+            });
+            node_results = self.node_outcomes(request_results);
+            votes = node_results
+                .iter()
+                .filter(|outcome| matches!(**outcome, NodeOutcome::Acquired))
+                .count();
+
+            let lock = BinaryLock {
+                redlock: self,
+                resource_name: resource_name.to_vec(),
+                value,
+                expiration,
+                valid_until,
+            };
+            let min_valid_until = self.clock.instant().checked_add(self.min_validity);
+            if evaluate_quorum(&node_results, self.quorum) == Decision::Acquire &&
+               min_valid_until.is_some_and(|min_valid_until| valid_until > min_valid_until) {
+                return Ok(lock);
+            }
+
+            // Release the servers which have granted a lock, an extension
+            // is left to expire like on the other failed extensions
+            if votes > 0 && !extend {
+                let _ = lock.unlock(); // Expires anyway
+            }
+            let mut timing = AttemptTiming {
+                start: attempt_start,
+                duration: request_start.elapsed() - attempt_start,
+                votes,
+                sleep: Duration::from_millis(0),
+            };
+            if attempts < self.retry_count {
+                timing.sleep = self.get_retry_timeout(attempts);
+                thread::sleep(timing.sleep);
+            }
+            timings.push(timing);
+        }
+
+        let resource_name = String::from_utf8_lossy(resource_name).into_owned();
+        if extend {
+            Err(RedlockError::UnableToExtend(resource_name, votes, self.quorum, attempts))
+        } else {
+            Err(self.lock_failed(resource_name, attempts, node_results, timings))
+        }
+    }
+
+    fn unlock_bytes(&self, resource_name: &[u8], value: &[u8]) -> RedlockResult<()> {
+        let key = self.key_bytes(resource_name);
+
+        let mut attempts = 0;
+        let mut votes = 0;
+        while attempts < self.unlock_retry_count {
+            attempts += 1;
+
+            let request_results = self.request_all(|backend| {
+                Ok(backend.compare_and_del_bytes(&key, value)? == UnlockOutcome::Released)
+            });
+            let node_results = self.node_outcomes(request_results);
+            votes = node_results
+                .iter()
+                .filter(|outcome| matches!(**outcome, NodeOutcome::Acquired))
+                .count();
+            if evaluate_quorum(&node_results, self.quorum) == Decision::Acquire {
+                return Ok(());
+            }
+
+            if attempts < self.unlock_retry_count {
+                thread::sleep(self.get_unlock_retry_timeout(attempts));
+            }
+        }
+
+        Err(RedlockError::UnableToUnlock(String::from_utf8_lossy(resource_name).into_owned(),
+                                         votes,
+                                         self.quorum,
+                                         attempts))
+    }
+
+    // The key of a binary resource name, the same as the one of key for a
+    // UTF-8 name.
+    fn key_bytes(&self, resource_name: &[u8]) -> Vec<u8> {
+        match str::from_utf8(resource_name) {
+            Ok(resource_name) => self.key(resource_name).into_bytes(),
+            Err(_) => [self.key_prefix.as_bytes(), resource_name].concat(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::Commands;
+    use crate::redlock::Config;
+
+    // A raw UUID, which is not valid UTF-8.
+    const RAW_UUID: &[u8] = b"\xfe\x9b\x11\x07\xd3\x4a\x4c\x8e\x9f\x80\xc3\x28\x1a\x00\x5e\xff";
+
+    fn get(key: &[u8]) -> Option<Vec<u8>> {
+        redis::Client::open("redis://127.0.0.1")
+            .unwrap()
+            .get_connection()
+            .unwrap()
+            .get(key)
+            .unwrap()
+    }
+
+    fn new_redlock() -> Redlock {
+        Redlock::new(Config {
+                         retry_count: 2,
+                         retry_delay: Duration::from_millis(50),
+                         retry_jitter: 0,
+                         key_prefix: String::from("test_binary:"),
+                         ..Config::default()
+                     })
+                .unwrap()
+    }
+
+    #[test]
+    fn test_lock_bytes() {
+        let redlock = new_redlock();
+        let key = [&b"test_binary:"[..], RAW_UUID].concat();
+        let lock = redlock
+            .lock_bytes(RAW_UUID, Duration::from_millis(2000))
+            .unwrap();
+        assert_eq!(lock.resource_name(), RAW_UUID);
+        assert_eq!(get(&key), Some(lock.value().to_vec()));
+        match redlock.lock_bytes(RAW_UUID, Duration::from_millis(2000)) {
+            Err(RedlockError::QuorumNotReached(_, 0, 1, 2, _, _)) => {}
+            res => panic!("expected QuorumNotReached, got {:?}", res),
+        }
+
+        let extended = lock.extend(Duration::from_millis(2000)).unwrap();
+        assert!(extended.expiration() > lock.expiration());
+        lock.unlock().unwrap();
+        assert!(get(&key).is_none());
+        assert!(extended.extend(Duration::from_millis(2000)).is_err());
+    }
+
+    #[test]
+    fn test_lock_bytes_with_value() {
+        let redlock = new_redlock();
+        let resource_name = [RAW_UUID, b":with_value"].concat();
+        let value = b"\x00\xffowner";
+        let lock = redlock
+            .lock_bytes_with_value(&resource_name, value, Duration::from_millis(2000))
+            .unwrap();
+        assert_eq!(get(&[&b"test_binary:"[..], &resource_name].concat()),
+                   Some(value.to_vec()));
+        lock.unlock().unwrap();
+
+        let res = redlock.lock_bytes_with_value(&resource_name, b"", Duration::from_millis(2000));
+        assert!(matches!(res, Err(RedlockError::InvalidValue)));
+    }
+
+    #[test]
+    fn test_lock_bytes_utf8() {
+        // A UTF-8 name locks the same resource as lock
+        let redlock = new_redlock();
+        let lock = redlock
+            .lock("test_lock_bytes_utf8", Duration::from_millis(2000))
+            .unwrap();
+        assert!(redlock
+                    .lock_bytes(b"test_lock_bytes_utf8", Duration::from_millis(2000))
+                    .is_err());
+        lock.unlock().unwrap();
+
+        let lock = redlock
+            .lock_bytes(b"test_lock_bytes_utf8", Duration::from_millis(2000))
+            .unwrap();
+        assert!(redlock
+                    .lock("test_lock_bytes_utf8", Duration::from_millis(2000))
+                    .is_err());
+        lock.unlock().unwrap();
+    }
+}
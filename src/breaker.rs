@@ -0,0 +1,113 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Breaker is the circuit breaker of a server: once the server has failed
+// threshold times in a row, it is skipped until the cooldown has elapsed.
+// A single request is then let through to probe it, which closes the
+// breaker if it succeeds, or opens it for another cooldown otherwise.
+#[derive(Debug)]
+pub(crate) struct Breaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // The number of failures in a row.
+    failures: u32,
+    // When the breaker has been opened, or the last probe let through.
+    opened_at: Option<Instant>,
+}
+
+impl Breaker {
+    pub(crate) fn new(threshold: u32, cooldown: Duration) -> Breaker {
+        Breaker {
+            threshold,
+            cooldown,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    // Whether a request may be sent to the server, otherwise the time left
+    // until the next probe. Letting a probe through keeps the breaker open
+    // for the other requests, until the probe is recorded.
+    pub(crate) fn check(&self) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let opened_at = match state.opened_at {
+            Some(opened_at) => opened_at,
+            None => return Ok(()),
+        };
+
+        let elapsed = opened_at.elapsed();
+        if elapsed < self.cooldown {
+            return Err(self.cooldown - elapsed);
+        }
+        state.opened_at = Some(Instant::now());
+        Ok(())
+    }
+
+    // Record the result of a request sent to the server.
+    pub(crate) fn record(&self, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        if success {
+            *state = State::default();
+            return;
+        }
+
+        state.failures = state.failures.saturating_add(1);
+        if state.failures >= self.threshold {
+            state.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_breaker_opens_after_threshold() {
+        let breaker = Breaker::new(2, Duration::from_millis(1000));
+        assert!(breaker.check().is_ok());
+        breaker.record(false);
+        assert!(breaker.check().is_ok());
+        breaker.record(false);
+        let retry_in = breaker.check().unwrap_err();
+        assert!(retry_in > Duration::from_millis(900));
+        assert!(retry_in <= Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_breaker_success_resets_failures() {
+        let breaker = Breaker::new(2, Duration::from_millis(1000));
+        breaker.record(false);
+        breaker.record(true);
+        breaker.record(false);
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn test_breaker_probe() {
+        let breaker = Breaker::new(1, Duration::from_millis(100));
+        breaker.record(false);
+        assert!(breaker.check().is_err());
+
+        // A single probe is let through after the cooldown
+        thread::sleep(Duration::from_millis(150));
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_err());
+
+        // The probe failed, the breaker is open for another cooldown
+        breaker.record(false);
+        assert!(breaker.check().is_err());
+        thread::sleep(Duration::from_millis(150));
+        assert!(breaker.check().is_ok());
+
+        // The probe succeeded, the breaker is closed
+        breaker.record(true);
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_ok());
+    }
+}
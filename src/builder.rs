@@ -0,0 +1,278 @@
+use std::time::Duration;
+use std::sync::Arc;
+use redis::IntoConnectionInfo;
+use crate::errors::{RedlockResult, RedlockError};
+use crate::pool::Client;
+use crate::redlock::{BackoffStrategy, Config, Drift, PartialRetryPolicy, Redlock};
+
+// RedlockBuilder builds a Redlock step by step, starting from the same
+// settings as Config::default() but without any address.
+pub struct RedlockBuilder {
+    clients: Vec<redis::RedisResult<Client>>,
+    config: Config<redis::ConnectionInfo>,
+}
+
+impl RedlockBuilder {
+    pub fn new() -> RedlockBuilder {
+        RedlockBuilder {
+            clients: Vec::new(),
+            config: Config::with_addrs(Vec::new()),
+        }
+    }
+
+    // Add the address of a redis server, addresses of different types can
+    // be mixed.
+    pub fn add_address<T: IntoConnectionInfo>(mut self, addr: T) -> RedlockBuilder {
+        self.clients.push(redis::Client::open(addr).map(Client::from));
+        self
+    }
+
+    // Add a redis cluster, given the addresses of some of its nodes. The
+    // whole cluster counts as a single server for the quorum.
+    #[cfg(feature = "cluster")]
+    pub fn add_cluster<T: IntoConnectionInfo>(mut self, nodes: Vec<T>) -> RedlockBuilder {
+        self.clients
+            .push(redis::cluster::ClusterClient::new(nodes).map(Client::Cluster));
+        self
+    }
+
+    pub fn retry_count(mut self, retry_count: u32) -> RedlockBuilder {
+        self.config.retry_count = retry_count;
+        self
+    }
+
+    pub fn retry_delay(mut self, retry_delay: Duration) -> RedlockBuilder {
+        self.config.retry_delay = retry_delay;
+        self
+    }
+
+    pub fn retry_jitter(mut self, retry_jitter: u32) -> RedlockBuilder {
+        self.config.retry_jitter = retry_jitter;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: BackoffStrategy) -> RedlockBuilder {
+        self.config.backoff = backoff;
+        self
+    }
+
+    pub fn rng_seed(mut self, rng_seed: u64) -> RedlockBuilder {
+        self.config.rng_seed = Some(rng_seed);
+        self
+    }
+
+    pub fn drift_factor(mut self, drift_factor: f32) -> RedlockBuilder {
+        self.config.drift_factor = drift_factor;
+        self
+    }
+
+    pub fn clock_drift_ms(mut self, clock_drift_ms: u64) -> RedlockBuilder {
+        self.config.clock_drift_ms = clock_drift_ms;
+        self
+    }
+
+    pub fn drift(mut self, drift: Drift) -> RedlockBuilder {
+        self.config.drift = drift;
+        self
+    }
+
+    pub fn pool_size(mut self, pool_size: u32) -> RedlockBuilder {
+        self.config.pool_size = pool_size;
+        self
+    }
+
+    pub fn token_len(mut self, token_len: usize) -> RedlockBuilder {
+        self.config.token_len = token_len;
+        self
+    }
+
+    pub fn min_validity(mut self, min_validity: Duration) -> RedlockBuilder {
+        self.config.min_validity = min_validity;
+        self
+    }
+
+    pub fn command_timeout(mut self, command_timeout: Duration) -> RedlockBuilder {
+        self.config.command_timeout = Some(command_timeout);
+        self
+    }
+
+    pub fn key_prefix<T: Into<String>>(mut self, key_prefix: T) -> RedlockBuilder {
+        self.config.key_prefix = key_prefix.into();
+        self
+    }
+
+    pub fn key_mapper<F>(mut self, key_mapper: F) -> RedlockBuilder
+        where F: Fn(&str) -> String + Send + Sync + 'static
+    {
+        self.config.key_mapper = Some(Arc::new(key_mapper));
+        self
+    }
+
+    // Take the addresses from the given resolver instead of the ones added,
+    // see Config::resolver.
+    pub fn resolver<F>(mut self, resolver: F) -> RedlockBuilder
+        where F: Fn() -> redis::RedisResult<Vec<redis::ConnectionInfo>> + Send + Sync + 'static
+    {
+        self.config.resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    // Lock the resources starting with the given prefix for the given TTL
+    // with lock_default, see Config::default_ttls.
+    pub fn default_ttl<T: Into<String>>(mut self, prefix: T, ttl: Duration) -> RedlockBuilder {
+        self.config.default_ttls.insert(prefix.into(), ttl);
+        self
+    }
+
+    pub fn username<T: Into<String>>(mut self, username: T) -> RedlockBuilder {
+        self.config.username = Some(username.into());
+        self
+    }
+
+    pub fn password<T: Into<String>>(mut self, password: T) -> RedlockBuilder {
+        self.config.password = Some(password.into());
+        self
+    }
+
+    pub fn expiration_jitter(mut self, expiration_jitter: Duration) -> RedlockBuilder {
+        self.config.expiration_jitter = expiration_jitter;
+        self
+    }
+
+    pub fn partial_retry(mut self, partial_retry: PartialRetryPolicy) -> RedlockBuilder {
+        self.config.partial_retry = partial_retry;
+        self
+    }
+
+    pub fn max_operations(mut self, max_operations: u32) -> RedlockBuilder {
+        self.config.max_operations = Some(max_operations);
+        self
+    }
+
+    pub fn unlock_retry_count(mut self, unlock_retry_count: u32) -> RedlockBuilder {
+        self.config.unlock_retry_count = Some(unlock_retry_count);
+        self
+    }
+
+    pub fn unlock_retry_delay(mut self, unlock_retry_delay: Duration) -> RedlockBuilder {
+        self.config.unlock_retry_delay = Some(unlock_retry_delay);
+        self
+    }
+
+    pub fn track_locks(mut self, track_locks: bool) -> RedlockBuilder {
+        self.config.track_locks = track_locks;
+        self
+    }
+
+    pub fn strict_odd_nodes(mut self, strict_odd_nodes: bool) -> RedlockBuilder {
+        self.config.strict_odd_nodes = strict_odd_nodes;
+        self
+    }
+
+    pub fn breaker_threshold(mut self, breaker_threshold: u32) -> RedlockBuilder {
+        self.config.breaker_threshold = Some(breaker_threshold);
+        self
+    }
+
+    pub fn breaker_cooldown(mut self, breaker_cooldown: Duration) -> RedlockBuilder {
+        self.config.breaker_cooldown = breaker_cooldown;
+        self
+    }
+
+    // The labels of the servers, in the order they have been added.
+    pub fn labels<T: Into<String>>(mut self, labels: Vec<T>) -> RedlockBuilder {
+        self.config.labels = labels.into_iter().map(Into::into).collect();
+        self
+    }
+
+    // Create the redlock instance.
+    pub fn build(self) -> RedlockResult<Redlock> {
+        if self.config.resolver.is_some() {
+            return Redlock::new(self.config);
+        }
+
+        let mut clients = Vec::with_capacity(self.clients.len());
+        for (index, client) in self.clients.into_iter().enumerate() {
+            clients.push(client.map_err(|err| RedlockError::InvalidAddress(index, err))?);
+        }
+
+        Redlock::with_clients(clients, self.config)
+    }
+}
+
+impl Default for RedlockBuilder {
+    fn default() -> Self {
+        RedlockBuilder::new()
+    }
+}
+
+impl Redlock {
+    // Create a builder of redlock instance.
+    pub fn builder() -> RedlockBuilder {
+        RedlockBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_default() {
+        let redlock = Redlock::builder()
+            .add_address("redis://127.0.0.1")
+            .build()
+            .unwrap();
+        assert_eq!(redlock.nodes.len(), 1);
+        assert_eq!(redlock.retry_count, 10);
+    }
+
+    #[test]
+    fn test_builder() {
+        let redlock = Redlock::builder()
+            .add_address("redis://127.0.0.1")
+            .add_address(String::from("redis://127.0.0.1:6380"))
+            .add_address(("127.0.0.1", 6381))
+            .retry_count(3)
+            .retry_delay(Duration::from_millis(200))
+            .retry_jitter(100)
+            .drift_factor(0.02)
+            .pool_size(4)
+            .build()
+            .unwrap();
+        assert_eq!(redlock.nodes.len(), 3);
+        assert_eq!(redlock.quorum, 2);
+        assert_eq!(redlock.retry_count, 3);
+    }
+
+    #[test]
+    fn test_builder_without_address() {
+        match Redlock::builder().build() {
+            Err(RedlockError::NoServerError) => {}
+            _ => panic!("expected NoServerError"),
+        }
+    }
+
+    #[test]
+    fn test_builder_with_invalid_address() {
+        match Redlock::builder()
+                  .add_address("redis://127.0.0.1")
+                  .add_address("http://127.0.0.1")
+                  .build() {
+            Err(RedlockError::InvalidAddress(1, _)) => {}
+            _ => panic!("expected InvalidAddress"),
+        }
+    }
+
+    #[cfg(feature = "cluster")]
+    #[test]
+    fn test_builder_with_cluster() {
+        let redlock = Redlock::builder()
+            .add_address("redis://127.0.0.1")
+            .add_cluster(vec!["redis://127.0.0.1:7000", "redis://127.0.0.1:7001"])
+            .build()
+            .unwrap();
+        assert_eq!(redlock.nodes.len(), 2);
+        assert_eq!(redlock.quorum, 2);
+    }
+}
@@ -0,0 +1,97 @@
+use std::fmt;
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::{Arc, Mutex};
+#[cfg(any(test, feature = "test-util"))]
+use std::time::Duration;
+use std::time::{Instant, SystemTime};
+
+// Clock tells Redlock the time, to compute the expiration and the validity
+// of the locks, so that the tests can control it. The redis servers keep
+// their own clock: the keys still expire in real time.
+pub trait Clock: Send + Sync {
+    // The wall clock time, which the expirations are given in.
+    fn now(&self) -> SystemTime;
+
+    // The monotonic time, which the validity is checked against.
+    fn instant(&self) -> Instant;
+}
+
+impl fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Clock")
+    }
+}
+
+// The clock used when none is given, the clocks of the system.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// TestClock is a clock which only moves forward when advanced, e.g. to
+// expire a lock without waiting. Its clones share the same time.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Clone)]
+pub struct TestClock {
+    start: SystemTime,
+    start_instant: Instant,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl TestClock {
+    // Create a clock stopped at the current time.
+    pub fn new() -> TestClock {
+        TestClock {
+            start: SystemTime::now(),
+            start_instant: Instant::now(),
+            elapsed: Arc::new(Mutex::new(Duration::from_millis(0))),
+        }
+    }
+
+    // Move the clock forward by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Default for TestClock {
+    fn default() -> Self {
+        TestClock::new()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Clock for TestClock {
+    fn now(&self) -> SystemTime {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    fn instant(&self) -> Instant {
+        self.start_instant + *self.elapsed.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_test_clock() {
+        let clock = TestClock::new();
+        let (now, instant) = (clock.now(), clock.instant());
+        assert_eq!(clock.now(), now);
+
+        clock.clone().advance(Duration::from_millis(1500));
+        assert_eq!(clock.now(), now + Duration::from_millis(1500));
+        assert_eq!(clock.instant(), instant + Duration::from_millis(1500));
+    }
+}
@@ -1,18 +1,156 @@
+use std::io;
 use std::time;
-use redis;
 
 pub type RedlockResult<T> = Result<T, RedlockError>;
 
+// NodeOutcome is the result of requesting a single redis server.
+#[derive(Debug)]
+pub enum NodeOutcome {
+    // The server granted the request.
+    Acquired,
+    // The server refused the request, e.g. the resource is already held.
+    Held,
+    // The request to the server with the given label failed.
+    Error(String, RedlockError),
+}
+
+// AttemptTiming tells how an attempt of a failed lock request went, to tune
+// the retry count and delay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AttemptTiming {
+    // When the attempt started, since the start of the request.
+    pub start: time::Duration,
+    // How long the attempt took, including the release of the servers it
+    // acquired.
+    pub duration: time::Duration,
+    // The number of servers which granted the attempt.
+    pub votes: usize,
+    // How long the request slept after the attempt, zero after the last
+    // one.
+    pub sleep: time::Duration,
+}
+
 quick_error!{
   #[derive(Debug)]
   pub enum RedlockError {
     RedisError(err: redis::RedisError) { from(err: redis::RedisError) -> (err) }
     TimeError(err: time::SystemTimeError) { from(err: time::SystemTimeError) -> (err) }
+    InvalidAddress(index: usize, err: redis::RedisError) {
+      description("Invalid redis server address")
+      display("Invalid redis server address at index {}: {}", index, err)
+      cause(err)
+    }
+    AuthenticationFailed(label: String, err: redis::RedisError) {
+      description("Authentication to a redis server failed")
+      display("Authentication to the redis server {} failed: {}", label, err)
+      cause(err)
+    }
+    InvalidScript(name: &'static str, err: redis::RedisError) {
+      description("Invalid script")
+      display("Invalid {} script: {}", name, err)
+      cause(err)
+    }
     NoServerError { description("Redlock must be initialized with at least one redis server") }
+    EvenNodeCount(count: usize) {
+      description("Redlock must be initialized with an odd number of servers")
+      display("Redlock must be initialized with an odd number of servers, got {}", count)
+    }
+    DelayJitterError { description("Retry jitter must not be larger than retry delay") }
+    InvalidDriftFactor(drift_factor: f32) {
+      description("Drift factor must be finite and in the range [0, 1)")
+      display("Drift factor must be finite and in the range [0, 1), got {}", drift_factor)
+    }
+    InvalidRetryCount {
+      description("The retry count is the number of attempts and must be at least 1")
+    }
+    InvalidConfig(reason: &'static str) {
+      description("Invalid configuration")
+      display("Invalid configuration: {}", reason)
+    }
+    ResolveFailed(err: redis::RedisError) {
+      description("Unable to resolve the addresses of the servers")
+      display("Unable to resolve the addresses of the servers: {}", err)
+    }
+    ResolvedNodeCount(expected: usize, resolved: usize) {
+      description("The resolver gave another number of servers")
+      display("The resolver gave {} servers instead of {}", resolved, expected)
+    }
+    InvalidTtl(ttl: time::Duration) {
+      description("The TTL is out of the range of the clocks or of redis")
+      display("The TTL is out of the range of the clocks or of redis: {:?}", ttl)
+    }
     TimeoutError { description("Redlock request timeout") }
+    CircuitOpen(retry_in: time::Duration) {
+      description("The server has failed too many times in a row and is skipped")
+      display("The server has failed too many times in a row and is skipped for {:?}",
+              retry_in)
+    }
     LockExpired { description("The lock has already expired") }
-    UnableToLock { description("Unable to lock the resource") }
-    UnableToUnlock { description("Unable to unlock the resource") }
-    UnableToExtend { description("Unable to extend the resource") }
+    NoDefaultTtl(resource_name: String) {
+      description("No default TTL matches the resource name")
+      display("No default TTL matches '{}'", resource_name)
+    }
+    // Kept for compatibility, the lock requests fail with QuorumNotReached.
+    UnableToLock(resource_name: String, votes: usize, quorum: usize, attempts: u32) {
+      description("Unable to lock the resource")
+      display("Failed to lock '{}': got {} of {} required votes after {} attempts",
+              resource_name, votes, quorum, attempts)
+    }
+    QuorumNotReached(resource_name: String,
+                     votes: usize,
+                     quorum: usize,
+                     attempts: u32,
+                     node_results: Vec<NodeOutcome>,
+                     timings: Vec<AttemptTiming>) {
+      description("Unable to lock the resource on a quorum of servers")
+      display("Failed to lock '{}': got {} of {} required votes after {} attempts",
+              resource_name, votes, quorum, attempts)
+    }
+    QuorumUnreachable(failed: usize, quorum: usize, node_results: Vec<NodeOutcome>) {
+      description("Too many redis servers failed to ever reach the quorum")
+      display("Unable to reach the quorum of {}: the same {} servers failed twice in a row",
+              quorum, failed)
+    }
+    AllNodesFailed(err: Box<RedlockError>) {
+      description("Every redis server failed the request")
+      display("Every redis server failed the request, the first error: {}", err)
+      cause(&**err)
+    }
+    Cancelled { description("The lock request has been cancelled") }
+    BudgetExhausted {
+      description("The lock request has exceeded the maximum number of operations")
+    }
+    OrphanedLock(err: Box<RedlockError>) {
+      description("Unable to release the locks of a failed attempt")
+      display("Unable to release the locks of a failed attempt: {}", err)
+      cause(&**err)
+    }
+    UnableToUnlock(resource_name: String, votes: usize, quorum: usize, attempts: u32) {
+      description("Unable to unlock the resource")
+      display("Unable to unlock '{}': {} of {} required votes after {} attempts",
+              resource_name, votes, quorum, attempts)
+    }
+    UnableToExtend(resource_name: String, votes: usize, quorum: usize, attempts: u32) {
+      description("Unable to extend the resource")
+      display("Unable to extend '{}': {} of {} required votes after {} attempts",
+              resource_name, votes, quorum, attempts)
+    }
+    UnableToTransfer(resource_name: String, votes: usize, quorum: usize, attempts: u32) {
+      description("Unable to transfer the resource")
+      display("Unable to transfer '{}': {} of {} required votes after {} attempts",
+              resource_name, votes, quorum, attempts)
+    }
+    EmptyKeyPrefix {
+      description("The key prefix must not be empty to clean up the keys of this service")
+    }
+    InvalidValue { description("The lock value must not be empty") }
+    LockTrackingDisabled {
+      description("The locks must be tracked to release them on shutdown")
+    }
+    SignalHandler(err: io::Error) {
+      description("Unable to install the signal handler")
+      display("Unable to install the signal handler: {}", err)
+      cause(err)
+    }
   }
 }
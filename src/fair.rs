@@ -0,0 +1,189 @@
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::backend::Node;
+use crate::errors::{AttemptTiming, NodeOutcome, RedlockResult};
+use crate::pool::ConnectionPool;
+use crate::redlock::{has_hash_tag, Lock, Redlock};
+use crate::scripts::{FAIR_DEQUEUE, FAIR_ENQUEUE};
+use crate::util;
+
+impl Redlock {
+    // Locks the given resource like lock, but in the order the callers
+    // have asked for it: the contenders are queued on the first redis
+    // server, and only the one at the head of the queue tries to lock the
+    // resource, so that none of them starves under contention. It trades
+    // latency for fairness, since the resource is only locked once the
+    // head of the queue polls again. A contender which stops polling, e.g.
+    // since it has died, is removed from the queue once its entry expires,
+    // a little after its next retry was due. The retry count must be high
+    // enough to wait for the contenders queued before. If the first server
+    // fails or is not a redis server, the contenders are not queued.
+    pub fn lock_fair(&self, resource_name: &str, ttl: Duration) -> RedlockResult<Lock<'_>> {
+        let queue = match self.nodes.first() {
+            Some(Node::Redis(pool)) => Some(pool),
+            _ => None,
+        };
+        let queue_key = queue_key(&self.key(resource_name));
+        let contender = util::get_random_token(self.token_len);
+        let leave = || if let Some(pool) = queue {
+            let _ = dequeue(pool, &queue_key, &contender); // Expires anyway
+        };
+
+        let mut attempts = 0;
+        let start = Instant::now();
+        let mut timings = Vec::new();
+        let mut node_results = Vec::new();
+        while attempts < self.retry_count {
+            attempts += 1;
+            let wait = self.get_retry_timeout(attempts);
+            let attempt_start = start.elapsed();
+
+            // The entry must outlive the wait until the next poll
+            let at_head = queue.is_none_or(|pool| {
+                enqueue(pool, &queue_key, &contender, wait * 2 + ENTRY_MARGIN).unwrap_or(true)
+            });
+            node_results = Vec::new();
+            if at_head {
+                match self.try_lock_with_results(resource_name, ttl) {
+                    Ok(Ok(mut lock)) => {
+                        leave();
+                        lock.attempts = attempts;
+                        return Ok(lock);
+                    }
+                    Ok(Err(results)) => node_results = results,
+                    Err(err) => {
+                        leave();
+                        return Err(err);
+                    }
+                }
+            }
+
+            let mut timing = AttemptTiming {
+                start: attempt_start,
+                duration: start.elapsed() - attempt_start,
+                votes: node_results
+                    .iter()
+                    .filter(|outcome| matches!(**outcome, NodeOutcome::Acquired))
+                    .count(),
+                sleep: Duration::from_millis(0),
+            };
+            if attempts < self.retry_count {
+                timing.sleep = wait;
+                thread::sleep(wait);
+            }
+            timings.push(timing);
+        }
+
+        leave();
+        Err(self.lock_failed(String::from(resource_name), attempts, node_results, timings))
+    }
+}
+
+// The time a queue entry outlives the next poll of its contender.
+const ENTRY_MARGIN: Duration = Duration::from_millis(100);
+
+// The queue must be in the same hash slot as the resource, like the fence
+// counter.
+fn queue_key(key: &str) -> String {
+    if has_hash_tag(key) {
+        format!("{}:queue", key)
+    } else {
+        format!("{{{}}}:queue", key)
+    }
+}
+
+// Queue the contender if needed, returns whether it is at the head.
+fn enqueue(pool: &ConnectionPool,
+           queue_key: &str,
+           contender: &str,
+           entry_ttl: Duration)
+           -> RedlockResult<bool> {
+    Ok(FAIR_ENQUEUE
+           .key(queue_key)
+           .arg(contender)
+           .arg(util::num_milliseconds(&entry_ttl))
+           .invoke::<i32>(&mut *pool.get()?)? == 1)
+}
+
+fn dequeue(pool: &ConnectionPool, queue_key: &str, contender: &str) -> RedlockResult<()> {
+    FAIR_DEQUEUE
+        .key(queue_key)
+        .arg(contender)
+        .invoke::<i32>(&mut *pool.get()?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::Commands;
+    use crate::errors::RedlockError;
+    use crate::redlock::Config;
+
+    fn new_redlock(retry_count: u32) -> Redlock {
+        Redlock::new(Config {
+                         retry_count,
+                         retry_delay: Duration::from_millis(50),
+                         retry_jitter: 0,
+                         ..Config::default()
+                     })
+                .unwrap()
+    }
+
+    fn connection() -> redis::Connection {
+        redis::Client::open("redis://127.0.0.1")
+            .unwrap()
+            .get_connection()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_lock_fair() {
+        let resource_name = "test_lock_fair";
+        let redlock = new_redlock(3);
+        let lock = redlock
+            .lock_fair(resource_name, Duration::from_millis(1000))
+            .unwrap();
+        assert_eq!(lock.attempts(), 1);
+
+        // The contender has left the queue
+        let len: usize = connection().llen(queue_key(resource_name)).unwrap();
+        assert_eq!(len, 0);
+        lock.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_lock_fair_waits_for_head() {
+        let resource_name = "test_lock_fair_waits_for_head";
+        let queue = queue_key(resource_name);
+        let mut con = connection();
+        let _: () = con.del(&queue).unwrap();
+
+        // Another contender is queued before, and keeps polling
+        let _: () = con.rpush(&queue, "other").unwrap();
+        let _: () = redis::cmd("SET")
+            .arg(format!("{}:other", queue))
+            .arg(1)
+            .arg("PX")
+            .arg(10000)
+            .query(&mut con)
+            .unwrap();
+        match new_redlock(3).lock_fair(resource_name, Duration::from_millis(1000)) {
+            Err(RedlockError::QuorumNotReached(_, 0, 1, 3, _, _)) => {}
+            res => panic!("expected QuorumNotReached, got {:?}", res),
+        }
+        let queued: Vec<String> = con.lrange(&queue, 0, -1).unwrap();
+        assert_eq!(queued, vec!["other"]);
+
+        // The other contender has died, its entry has expired
+        let _: () = con.del(format!("{}:other", queue)).unwrap();
+        let redlock = new_redlock(3);
+        let lock = redlock
+            .lock_fair(resource_name, Duration::from_millis(1000))
+            .unwrap();
+        assert_eq!(lock.attempts(), 1);
+        let len: usize = con.llen(&queue).unwrap();
+        assert_eq!(len, 0);
+        lock.unlock().unwrap();
+    }
+}
@@ -1,4 +1,9 @@
 //! A rust redlock implementation for distributed, highly-available redis locks.
+//!
+//! The crate is named `rust_redlock`, and its whole API is exported from the
+//! root, so that `use rust_redlock::*;` brings in `Redlock`, `Config`,
+//! `Lock`, `RedlockError` and `RedlockResult` among others. See the examples
+//! directory.
 
 #[macro_use]
 extern crate lazy_static;
@@ -7,10 +12,85 @@ extern crate quick_error;
 extern crate redis;
 extern crate rand;
 
-pub use self::errors::RedlockResult;
-pub use self::redlock::{Lock, Redlock, Config};
+// Emit a tracing event, compiled out without the tracing feature.
+macro_rules! trace_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
 
+pub use self::backend::{LockBackend, SetOutcome};
+pub use self::binary::BinaryLock;
+#[cfg(feature = "test-util")]
+pub use self::backend::InMemoryBackend;
+pub use self::builder::RedlockBuilder;
+pub use self::clock::{Clock, SystemClock};
+#[cfg(feature = "test-util")]
+pub use self::clock::TestClock;
+pub use self::errors::{AttemptTiming, NodeOutcome, RedlockError, RedlockResult};
+pub use self::observer::Observer;
+pub use self::quorum::{evaluate_quorum, Decision};
+pub use self::redlock::{Lock, FencedLock, LockGuard, OwnedLock, Redlock, Config, BackoffStrategy, Drift,
+                        AddrResolver, KeyMapper, LockRecord, LockStrength, OrphanPolicy,
+                        OrphanRecorder, PartialRetryPolicy, PollResult, UnlockOutcome,
+                        UnlockReport};
+pub use self::rwlock::ReadLock;
+pub use self::watchdog::{AutoExtendHandle, LockEvent, QuorumWatch};
+#[cfg(feature = "async")]
+pub use self::aio::{AsyncLock, AsyncRedlock};
+#[cfg(feature = "sentinel")]
+pub use self::sentinel::sentinel_resolver;
+
+mod backend;
+mod binary;
+mod breaker;
+mod builder;
+mod clock;
 mod errors;
+mod observer;
+mod quorum;
 mod scripts;
 mod redlock;
+mod pool;
+mod rwlock;
 mod util;
+mod watchdog;
+#[cfg(feature = "async")]
+mod aio;
+#[cfg(feature = "notifications")]
+mod notify;
+#[cfg(feature = "fair")]
+mod fair;
+#[cfg(feature = "tokio")]
+mod spawn;
+#[cfg(all(feature = "signal", unix))]
+mod shutdown;
+#[cfg(feature = "sentinel")]
+mod sentinel;
+
+// The thread safety of the public types is part of the API, make sure it
+// does not regress.
+#[allow(dead_code)]
+fn assert_thread_safety() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    fn assert_send<T: Send>() {}
+
+    assert_send_sync::<Redlock>();
+    assert_send_sync::<Lock<'static>>();
+    assert_send_sync::<OwnedLock>();
+    assert_send_sync::<FencedLock<'static>>();
+    assert_send_sync::<ReadLock<'static>>();
+    assert_send_sync::<BinaryLock<'static>>();
+    assert_send::<LockGuard<'static>>();
+    assert_send_sync::<AutoExtendHandle>();
+    assert_send_sync::<QuorumWatch>();
+    assert_send_sync::<RedlockBuilder>();
+    assert_send_sync::<RedlockError>();
+    #[cfg(feature = "test-util")]
+    assert_send_sync::<InMemoryBackend>();
+    #[cfg(feature = "async")]
+    assert_send_sync::<AsyncRedlock>();
+    #[cfg(feature = "async")]
+    assert_send_sync::<AsyncLock<'static>>();
+}
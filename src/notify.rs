@@ -0,0 +1,149 @@
+use std::cmp;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::backend::Node;
+use crate::errors::{AttemptTiming, NodeOutcome, RedlockResult};
+use crate::redlock::{Lock, Redlock};
+
+impl Redlock {
+    // Locks the given resource like lock, but instead of sleeping between
+    // two attempts, waits for the keyspace notifications of the resource on
+    // the first redis server, so that it retries as soon as the lock is
+    // released or expires there. The notifications must be enabled on the
+    // server, e.g. with notify-keyspace-events set to "Kgx". It works best
+    // against a single server, since the other servers are not watched.
+    // Without the notifications, it degrades to sleeping for the retry
+    // delay like lock.
+    pub fn lock_notified(&self, resource_name: &str, ttl: Duration) -> RedlockResult<Lock<'_>> {
+        let subscriber = self.nodes
+            .iter()
+            .filter_map(|node| match *node {
+                            Node::Redis(ref pool) => pool.get_dedicated(),
+                            Node::Backend(_) => None,
+                        })
+            .next();
+
+        if let Some(Ok((mut con, db))) = subscriber {
+            let channel = format!("__keyspace@{}__:{}", db, self.key(resource_name));
+            let mut pubsub = con.as_pubsub();
+            if pubsub.subscribe(&channel).is_ok() {
+                return self.lock_waiting(resource_name, ttl, |timeout| {
+                    let start = Instant::now();
+                    // Wake up on any event of the resource, or after the
+                    // timeout as if it was polled
+                    let _ = pubsub.set_read_timeout(Some(cmp::max(timeout,
+                                                                  Duration::from_millis(1))));
+                    if let Err(err) = pubsub.get_message() {
+                        if !err.is_timeout() {
+                            thread::sleep(timeout.saturating_sub(start.elapsed()));
+                        }
+                    }
+                });
+            }
+        }
+
+        self.lock_waiting(resource_name, ttl, thread::sleep)
+    }
+
+    // Try to lock the given resource up to the retry count, waiting with
+    // the given function between two attempts.
+    fn lock_waiting<W>(&self,
+                       resource_name: &str,
+                       ttl: Duration,
+                       mut wait: W)
+                       -> RedlockResult<Lock<'_>>
+        where W: FnMut(Duration)
+    {
+        let mut attempts = 0;
+        let start = Instant::now();
+        let mut timings = Vec::new();
+        let mut node_results = Vec::new();
+        while attempts < self.retry_count {
+            attempts += 1;
+            let attempt_start = start.elapsed();
+            match self.try_lock_with_results(resource_name, ttl)? {
+                Ok(mut lock) => {
+                    lock.attempts = attempts;
+                    return Ok(lock);
+                }
+                Err(results) => node_results = results,
+            }
+            let mut timing = AttemptTiming {
+                start: attempt_start,
+                duration: start.elapsed() - attempt_start,
+                votes: node_results
+                    .iter()
+                    .filter(|outcome| matches!(**outcome, NodeOutcome::Acquired))
+                    .count(),
+                sleep: Duration::from_millis(0),
+            };
+            if attempts < self.retry_count {
+                timing.sleep = self.get_retry_timeout(attempts);
+                wait(timing.sleep);
+            }
+            timings.push(timing);
+        }
+
+        Err(self.lock_failed(String::from(resource_name), attempts, node_results, timings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::Commands;
+    use crate::redlock::Config;
+
+    #[test]
+    fn test_lock_notified() {
+        let resource_name = "test_lock_notified";
+        let ttl = Duration::from_millis(10000);
+        let mut con = redis::Client::open("redis://127.0.0.1")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("notify-keyspace-events")
+            .arg("Kgx")
+            .query(&mut con)
+            .unwrap();
+
+        // Polling would only retry after 2 seconds
+        let redlock = Redlock::new(Config {
+                                       retry_count: 3,
+                                       retry_delay: Duration::from_millis(2000),
+                                       retry_jitter: 0,
+                                       ..Config::default()
+                                   })
+                .unwrap();
+        let _: () = con.set(resource_name, "other").unwrap();
+
+        let start = Instant::now();
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                let _: () = con.del(resource_name).unwrap();
+            });
+
+            let lock = redlock.lock_notified(resource_name, ttl).unwrap();
+            assert_eq!(lock.attempts(), 2);
+            lock.unlock().unwrap();
+        });
+        assert!(start.elapsed() < Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn test_lock_notified_without_server() {
+        let redlock = Redlock::new(Config {
+                                       retry_count: 2,
+                                       retry_delay: Duration::from_millis(100),
+                                       retry_jitter: 0,
+                                       ..Config::with_addrs(vec!["redis://127.0.0.1:1"])
+                                   })
+                .unwrap();
+        assert!(redlock.lock_notified("test_lock_notified_without_server",
+                                      Duration::from_millis(1000))
+                    .is_err());
+    }
+}
@@ -0,0 +1,45 @@
+use std::fmt;
+use std::time::Duration;
+use crate::errors::{NodeOutcome, RedlockError};
+
+// Observer gets notified of the lock requests of a Redlock, e.g. to collect
+// metrics. All the methods do nothing by default. They are called after the
+// connections to the redis servers have been given back, and should return
+// quickly since they are called in the path of the request.
+pub trait Observer: Send + Sync {
+    // An attempt to lock the resource is about to start, attempt starts
+    // from 1.
+    fn on_attempt(&self, _resource_name: &str, _attempt: u32) {}
+
+    // The resource has been locked after the given number of attempts.
+    fn on_acquired(&self, _resource_name: &str, _attempts: u32, _elapsed: Duration) {}
+
+    // The resource could not be locked after the given number of attempts.
+    fn on_failed(&self, _resource_name: &str, _attempts: u32) {}
+
+    // A request is about to be sent to the redis server at the given index,
+    // with the given label.
+    fn on_node_request(&self, _index: usize, _label: &str) {}
+
+    // The request to the redis server at the given index, with the given
+    // label, failed.
+    fn on_node_error(&self, _index: usize, _label: &str, _err: &RedlockError) {}
+
+    // A lock given up on, e.g. the lock of a failed attempt, may still be
+    // held by a server which granted it, that failed to release it, and is
+    // left there until it expires. The results are those of the last unlock
+    // attempt, in the order of the servers, Acquired meaning the lock has
+    // been released from the server or was not there.
+    fn on_orphaned(&self, _resource_name: &str, _value: &str, _node_results: &[NodeOutcome]) {}
+}
+
+impl fmt::Debug for dyn Observer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Observer")
+    }
+}
+
+// The observer used when none is given, it ignores everything.
+pub(crate) struct NoopObserver;
+
+impl Observer for NoopObserver {}
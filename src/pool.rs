@@ -0,0 +1,452 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use redis::{ConnectionLike, RedisResult, Value};
+use crate::errors::RedlockResult;
+use crate::scripts::Scripts;
+
+// Client connects to a node of Redlock, which is either a single redis
+// server or a whole redis cluster.
+#[derive(Clone)]
+pub(crate) enum Client {
+    Single(redis::Client),
+    #[cfg(feature = "cluster")]
+    Cluster(redis::cluster::ClusterClient),
+}
+
+impl Client {
+    // Open a new connection, which gives up on connecting and on the
+    // commands after the timeout if there is one.
+    fn get_connection(&self, timeout: Option<Duration>) -> RedisResult<Connection> {
+        let con = match (self, timeout) {
+            (Client::Single(client), Some(timeout)) => {
+                Connection::Single(client.get_connection_with_timeout(timeout)?)
+            }
+            (Client::Single(client), None) => Connection::Single(client.get_connection()?),
+            #[cfg(feature = "cluster")]
+            (Client::Cluster(client), _) => Connection::Cluster(client.get_connection()?),
+        };
+
+        if timeout.is_some() {
+            con.set_timeouts(timeout)?;
+        }
+        Ok(con)
+    }
+
+    // Replace the credentials of the server with the given ones, if any.
+    // The nodes of a redis cluster keep the credentials of their addresses.
+    pub(crate) fn with_credentials(self,
+                                   username: Option<&str>,
+                                   password: Option<&str>)
+                                   -> RedisResult<Client> {
+        match self {
+            Client::Single(client) if username.is_some() || password.is_some() => {
+                let mut info = client.get_connection_info().clone();
+                if let Some(username) = username {
+                    info.redis.username = Some(String::from(username));
+                }
+                if let Some(password) = password {
+                    info.redis.password = Some(String::from(password));
+                }
+                Ok(Client::Single(redis::Client::open(info)?))
+            }
+            client => Ok(client),
+        }
+    }
+
+    // Whether both clients connect to the same server and database.
+    fn same_server(&self, other: &Client) -> bool {
+        match (self, other) {
+            (Client::Single(client), Client::Single(other)) => {
+                let (info, other) = (client.get_connection_info(), other.get_connection_info());
+                info.addr.to_string() == other.addr.to_string() && info.redis.db == other.redis.db
+            }
+            #[cfg(feature = "cluster")]
+            _ => false,
+        }
+    }
+}
+
+impl From<redis::Client> for Client {
+    fn from(client: redis::Client) -> Client {
+        Client::Single(client)
+    }
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Client::Single(ref client) => f.debug_tuple("Single").field(client).finish(),
+            #[cfg(feature = "cluster")]
+            Client::Cluster(_) => f.debug_tuple("Cluster").finish(),
+        }
+    }
+}
+
+// Connection is a connection to a node of Redlock.
+pub(crate) enum Connection {
+    Single(redis::Connection),
+    #[cfg(feature = "cluster")]
+    Cluster(redis::cluster::ClusterConnection),
+}
+
+impl Connection {
+    fn set_timeouts(&self, timeout: Option<Duration>) -> RedisResult<()> {
+        match *self {
+            Connection::Single(ref con) => {
+                con.set_read_timeout(timeout)?;
+                con.set_write_timeout(timeout)
+            }
+            #[cfg(feature = "cluster")]
+            Connection::Cluster(ref con) => {
+                con.set_read_timeout(timeout)?;
+                con.set_write_timeout(timeout)
+            }
+        }
+    }
+
+    fn inner(&self) -> &dyn ConnectionLike {
+        match *self {
+            Connection::Single(ref con) => con,
+            #[cfg(feature = "cluster")]
+            Connection::Cluster(ref con) => con,
+        }
+    }
+
+    fn inner_mut(&mut self) -> &mut dyn ConnectionLike {
+        match *self {
+            Connection::Single(ref mut con) => con,
+            #[cfg(feature = "cluster")]
+            Connection::Cluster(ref mut con) => con,
+        }
+    }
+}
+
+impl ConnectionLike for Connection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        self.inner_mut().req_packed_command(cmd)
+    }
+
+    fn req_packed_commands(&mut self,
+                           cmd: &[u8],
+                           offset: usize,
+                           count: usize)
+                           -> RedisResult<Vec<Value>> {
+        self.inner_mut().req_packed_commands(cmd, offset, count)
+    }
+
+    fn req_command(&mut self, cmd: &redis::Cmd) -> RedisResult<Value> {
+        self.inner_mut().req_command(cmd)
+    }
+
+    fn get_db(&self) -> i64 {
+        self.inner().get_db()
+    }
+
+    fn check_connection(&mut self) -> bool {
+        self.inner_mut().check_connection()
+    }
+
+    fn is_open(&self) -> bool {
+        self.inner().is_open()
+    }
+}
+
+// The number of keys SCAN looks at per call.
+const SCAN_COUNT: usize = 100;
+
+// ConnectionPool keeps the idle connections to a redis server, so that they
+// can be reused by the following requests instead of reconnecting each time.
+pub(crate) struct ConnectionPool {
+    // Shared with the requests in flight, so that it's not cloned for each
+    // of them.
+    client: RwLock<Arc<Client>>,
+    // Bumped when the client is replaced, so that the connections checked
+    // out before are not given back to the pool.
+    generation: AtomicU64,
+    idle: Mutex<Vec<Connection>>,
+    size: usize,
+    timeout: Option<Duration>,
+    scripts: Arc<Scripts>,
+}
+
+impl ConnectionPool {
+    pub(crate) fn new<C: Into<Client>>(client: C,
+                                       size: usize,
+                                       timeout: Option<Duration>)
+                                       -> ConnectionPool {
+        ConnectionPool {
+            client: RwLock::new(Arc::new(client.into())),
+            generation: AtomicU64::new(0),
+            idle: Mutex::new(Vec::with_capacity(size)),
+            size,
+            timeout,
+            scripts: Arc::new(Scripts::default()),
+        }
+    }
+
+    // Run the given scripts instead of the built-in ones.
+    pub(crate) fn with_scripts(mut self, scripts: Arc<Scripts>) -> ConnectionPool {
+        self.scripts = scripts;
+        self
+    }
+
+    pub(crate) fn scripts(&self) -> &Scripts {
+        &self.scripts
+    }
+
+    fn client(&self) -> Arc<Client> {
+        self.client.read().unwrap().clone()
+    }
+
+    // Connect to the server of the given client from now on, e.g. once a
+    // master has failed over, unless it's the current one. The idle
+    // connections to the previous server are closed. Whether the client
+    // has been replaced is returned.
+    pub(crate) fn replace_client(&self, client: Client) -> bool {
+        let mut current = self.client.write().unwrap();
+        if current.same_server(&client) {
+            return false;
+        }
+
+        *current = Arc::new(client);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.idle.lock().unwrap().clear();
+        true
+    }
+
+    // Open an async connection to a single redis server, the async requests
+    // are not supported by the cluster nodes.
+    #[cfg(feature = "async")]
+    pub(crate) async fn get_async(&self) -> RedlockResult<redis::aio::MultiplexedConnection> {
+        let mut config = redis::AsyncConnectionConfig::new();
+        if let Some(timeout) = self.timeout {
+            config = config
+                .set_connection_timeout(timeout)
+                .set_response_timeout(timeout);
+        }
+
+        let client = self.client();
+        let con = match *client {
+            Client::Single(ref single) => {
+                single.get_multiplexed_async_connection_with_config(&config)
+            }
+            #[cfg(feature = "cluster")]
+            Client::Cluster(_) => {
+                return Err(redis::RedisError::from((redis::ErrorKind::InvalidClientConfig,
+                                                    "Redis cluster nodes do not support async \
+                                                     requests"))
+                                   .into())
+            }
+        };
+        Ok(con.await?)
+    }
+
+    // Open a connection outside of the pool, e.g. to subscribe to a channel,
+    // along with the index of its database. Only a single server supports
+    // it, None is returned for a cluster.
+    #[cfg(feature = "notifications")]
+    pub(crate) fn get_dedicated(&self) -> Option<RedlockResult<(redis::Connection, i64)>> {
+        let client = self.client();
+        let db = match *client {
+            Client::Single(ref single) => single.get_connection_info().redis.db,
+            #[cfg(feature = "cluster")]
+            Client::Cluster(_) => return None,
+        };
+        let con = match client.get_connection(self.timeout) {
+            Ok(Connection::Single(con)) => con,
+            #[cfg(feature = "cluster")]
+            Ok(Connection::Cluster(_)) => unreachable!(),
+            Err(err) => return Some(Err(err.into())),
+        };
+        Some(Ok((con, db)))
+    }
+
+    // Check out an idle connection, or open a new one if there is none.
+    pub(crate) fn get(&self) -> RedlockResult<PooledConnection<'_>> {
+        let generation = self.generation.load(Ordering::SeqCst);
+        let idle = self.idle.lock().unwrap().pop();
+        let con = match idle {
+            Some(con) => con,
+            None => self.client().get_connection(self.timeout)?,
+        };
+
+        Ok(PooledConnection {
+               pool: self,
+               con: Some(con),
+               generation,
+           })
+    }
+
+    // Get the keys matching the given glob pattern with SCAN, which does not
+    // block the server like KEYS. Only a single server supports it.
+    pub(crate) fn scan(&self, pattern: &str) -> RedlockResult<Vec<String>> {
+        match **self.client.read().unwrap() {
+            Client::Single(_) => {}
+            #[cfg(feature = "cluster")]
+            Client::Cluster(_) => {
+                return Err(redis::RedisError::from((redis::ErrorKind::InvalidClientConfig,
+                                                    "Redis cluster nodes do not support \
+                                                     scanning"))
+                                   .into())
+            }
+        }
+
+        let mut cmd = redis::cmd("SCAN");
+        cmd.cursor_arg(0)
+            .arg("MATCH")
+            .arg(pattern)
+            .arg("COUNT")
+            .arg(SCAN_COUNT);
+        let mut con = self.get()?;
+        let keys = cmd.iter::<String>(&mut *con)?.collect();
+        Ok(keys)
+    }
+
+    pub(crate) fn idle_count(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    fn put(&self, con: Connection, generation: u64) {
+        if !con.is_open() {
+            return;
+        }
+
+        // The connection is to a server which has been replaced, checked
+        // under the lock of the idle connections, which are cleared after
+        // the replacement
+        let mut idle = self.idle.lock().unwrap();
+        if self.generation.load(Ordering::SeqCst) != generation {
+            return;
+        }
+        if idle.len() < self.size {
+            idle.push(con);
+        }
+    }
+}
+
+// Cloning a pool keeps the client, the size and the scripts, but not the
+// idle connections.
+impl Clone for ConnectionPool {
+    fn clone(&self) -> ConnectionPool {
+        ConnectionPool::new((*self.client()).clone(), self.size, self.timeout)
+            .with_scripts(self.scripts.clone())
+    }
+}
+
+impl fmt::Debug for ConnectionPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConnectionPool")
+            .field("client", &**self.client.read().unwrap())
+            .field("idle", &self.idle_count())
+            .field("size", &self.size)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+// PooledConnection gives the connection back to its pool when dropped.
+pub(crate) struct PooledConnection<'a> {
+    pool: &'a ConnectionPool,
+    con: Option<Connection>,
+    generation: u64,
+}
+
+impl<'a> Deref for PooledConnection<'a> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.con.as_ref().unwrap()
+    }
+}
+
+impl<'a> DerefMut for PooledConnection<'a> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.con.as_mut().unwrap()
+    }
+}
+
+impl<'a> Drop for PooledConnection<'a> {
+    fn drop(&mut self) {
+        if let Some(con) = self.con.take() {
+            self.pool.put(con, self.generation);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_reuses_connection() {
+        let pool = ConnectionPool::new(redis::Client::open("redis://127.0.0.1").unwrap(), 2, None);
+        {
+            let _con1 = pool.get().unwrap();
+            let _con2 = pool.get().unwrap();
+            let _con3 = pool.get().unwrap();
+            assert_eq!(pool.idle_count(), 0);
+        }
+        assert_eq!(pool.idle_count(), 2);
+
+        let _con = pool.get().unwrap();
+        assert_eq!(pool.idle_count(), 1);
+    }
+
+    #[test]
+    fn test_clone() {
+        let pool = ConnectionPool::new(redis::Client::open("redis://127.0.0.1").unwrap(), 2, None);
+        drop(pool.get().unwrap());
+        assert_eq!(pool.idle_count(), 1);
+
+        let cloned = pool.clone();
+        assert_eq!(cloned.idle_count(), 0);
+        assert_eq!(cloned.size, pool.size);
+    }
+
+    #[test]
+    fn test_get_hung_server() {
+        // The connection is accepted by the kernel but never answered
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("redis://{}", listener.local_addr().unwrap());
+        let pool = ConnectionPool::new(redis::Client::open(addr).unwrap(),
+                                       2,
+                                       Some(Duration::from_millis(100)));
+
+        let start = std::time::Instant::now();
+        let res = pool.get().and_then(|mut con| {
+                                          Ok(redis::cmd("PING").query::<String>(&mut *con)?)
+                                      });
+        assert!(res.is_err());
+        assert!(start.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_get_unreachable_server() {
+        let pool = ConnectionPool::new(redis::Client::open("redis://127.0.0.1:1").unwrap(), 2, None);
+        assert!(pool.get().is_err());
+        assert_eq!(pool.idle_count(), 0);
+    }
+
+    #[test]
+    fn test_replace_client() {
+        let pool = ConnectionPool::new(redis::Client::open("redis://127.0.0.1/0").unwrap(), 2, None);
+        let stale = pool.get().unwrap();
+        drop(pool.get().unwrap());
+        assert_eq!(pool.idle_count(), 1);
+
+        assert!(!pool.replace_client(Client::from(redis::Client::open("redis://127.0.0.1/0")
+                                                      .unwrap())));
+        assert_eq!(pool.idle_count(), 1);
+        assert!(pool.replace_client(Client::from(redis::Client::open("redis://127.0.0.1/1")
+                                                     .unwrap())));
+        assert_eq!(pool.idle_count(), 0);
+
+        // The connections to the previous server are not reused
+        drop(stale);
+        assert_eq!(pool.idle_count(), 0);
+        assert_eq!(pool.get().unwrap().get_db(), 1);
+    }
+}
@@ -0,0 +1,90 @@
+use crate::errors::NodeOutcome;
+
+// Decision is the outcome of an attempt given the results of the servers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    // A quorum of the servers granted the request.
+    Acquire,
+    // Less than a quorum of the servers granted the request, but enough of
+    // them answered to reach it, e.g. the resource is held by another
+    // client. A later attempt may succeed.
+    Retry,
+    // Too many servers failed for this attempt to reach the quorum.
+    Fail,
+}
+
+// Decide the outcome of an attempt from the results of the servers, which
+// is, with n servers:
+//
+//   granted >= quorum       => Acquire
+//   n - failed < quorum     => Fail
+//   otherwise               => Retry
+//
+// It does not touch any server, so it can be fed with hypothetical
+// results, e.g. to tell how many failed servers are tolerated. The validity
+// of the lock is checked by the caller, since it depends on the time.
+pub fn evaluate_quorum(node_results: &[NodeOutcome], quorum: usize) -> Decision {
+    let votes = node_results
+        .iter()
+        .filter(|outcome| matches!(**outcome, NodeOutcome::Acquired))
+        .count();
+    let errors = node_results
+        .iter()
+        .filter(|outcome| matches!(**outcome, NodeOutcome::Error(..)))
+        .count();
+
+    if votes >= quorum {
+        Decision::Acquire
+    } else if node_results.len() - errors < quorum {
+        Decision::Fail
+    } else {
+        Decision::Retry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::RedlockError;
+
+    fn outcomes(acquired: usize, held: usize, errors: usize) -> Vec<NodeOutcome> {
+        let mut outcomes = Vec::new();
+        outcomes.extend((0..acquired).map(|_| NodeOutcome::Acquired));
+        outcomes.extend((0..held).map(|_| NodeOutcome::Held));
+        outcomes.extend((0..errors).map(|index| {
+                                            NodeOutcome::Error(index.to_string(),
+                                                               RedlockError::TimeoutError)
+                                        }));
+        outcomes
+    }
+
+    #[test]
+    fn test_evaluate_quorum() {
+        // (acquired, held, errors, decision) with 5 servers and a quorum of 3
+        let table = [(5, 0, 0, Decision::Acquire),
+                     (3, 2, 0, Decision::Acquire),
+                     (3, 0, 2, Decision::Acquire),
+                     (2, 3, 0, Decision::Retry),
+                     (2, 1, 2, Decision::Retry),
+                     (0, 3, 2, Decision::Retry),
+                     (2, 0, 3, Decision::Fail),
+                     (0, 2, 3, Decision::Fail),
+                     (0, 0, 5, Decision::Fail)];
+        for &(acquired, held, errors, decision) in &table {
+            assert_eq!(evaluate_quorum(&outcomes(acquired, held, errors), 3),
+                       decision,
+                       "{} acquired, {} held, {} errors",
+                       acquired,
+                       held,
+                       errors);
+        }
+    }
+
+    #[test]
+    fn test_evaluate_quorum_order() {
+        let mut node_results = outcomes(2, 1, 0);
+        assert_eq!(evaluate_quorum(&node_results, 2), Decision::Acquire);
+        node_results.reverse();
+        assert_eq!(evaluate_quorum(&node_results, 2), Decision::Acquire);
+    }
+}
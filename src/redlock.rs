@@ -1,372 +1,5260 @@
-use std::ops::{Add, Sub};
-use std::time::{Duration, SystemTime};
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::ops::{Add, Deref};
+use std::panic;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::default::Default;
 use std::thread;
-use redis;
-use rand::{thread_rng, Rng};
-use scripts::{LOCK, UNLOCK, EXTEND};
-use errors::{RedlockResult, RedlockError};
-use util;
+use rand::{thread_rng, Rng, SeedableRng, StdRng};
+use crate::errors::{AttemptTiming, NodeOutcome, RedlockResult, RedlockError};
+use crate::observer::{NoopObserver, Observer};
+use crate::backend::{LockBackend, Node, SetOutcome};
+use crate::breaker::Breaker;
+use crate::clock::{Clock, SystemClock};
+use crate::pool::{Client, ConnectionPool};
+use crate::quorum::{evaluate_quorum, Decision};
+use crate::scripts::Scripts;
+use crate::util;
 
 #[derive(Debug)]
-enum RequestInfo<'a> {
-    Lock,
-    Extend { resource_value: &'a str },
+pub(crate) enum RequestInfo<'a> {
+    Lock {
+        resource_value: Option<&'a str>,
+        fence: Option<&'a AtomicU64>,
+    },
+    LockOrExisting { resource_value: &'a str },
+    // Lock unless there is a reader, see lock_write.
+    LockExclusive { readers_key: &'a str },
+    // Extend, and set the companion data key if there is data.
+    Extend {
+        resource_value: &'a str,
+        data: Option<&'a [u8]>,
+    },
+    Transfer {
+        resource_value: &'a str,
+        new_value: &'a str,
+    },
 }
 
-// Lock represents a acquired lock for specified resource.
+impl<'a> RequestInfo<'a> {
+    // Whether the request sets the resource to a new random value, which
+    // nobody holds yet, rather than to a value the caller may hold.
+    fn writes_fresh_value(&self) -> bool {
+        match *self {
+            RequestInfo::Lock { resource_value, .. } => resource_value.is_none(),
+            RequestInfo::LockExclusive { .. } => true,
+            RequestInfo::LockOrExisting { .. } |
+            RequestInfo::Extend { .. } |
+            RequestInfo::Transfer { .. } => false,
+        }
+    }
+}
+
+// The outcome of a single attempt to request the servers.
+enum Attempt<'a> {
+    // A quorum of the servers granted the request.
+    Acquired(Lock<'a>),
+    // The attempt failed, and the acquired locks have been released.
+    Failed(Vec<NodeOutcome>),
+    // The attempt failed, and releasing the acquired locks failed as well,
+    // which aborts the request according to the orphan policy.
+    Aborted(RedlockError),
+}
+
+// Lock represents a acquired lock for specified resource. It is Send and
+// Sync, it can be used from other threads as long as its Redlock lives.
 #[derive(Debug)]
 pub struct Lock<'a> {
     redlock: &'a Redlock,
     resource_name: String,
     value: String,
     expiration: SystemTime,
+    // The validity is tracked with the monotonic clock, the wall clock can
+    // jump backwards or forwards, e.g. on NTP adjustments.
+    valid_until: Instant,
+    acquisition_time: Duration,
+    pub(crate) attempts: u32,
+    // Whether the lock has been downgraded to advisory, shared with the
+    // watch of its quorum.
+    advisory: Arc<AtomicBool>,
 }
 
 impl<'a> Lock<'a> {
+    // The name of the locked resource.
+    pub fn resource_name(&self) -> &str {
+        &self.resource_name
+    }
+
+    // The random value which identifies the owner of the lock.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    // The time the lock expires at. It is computed locally when the lock
+    // is acquired, with the clock drift already subtracted.
+    pub fn expiration(&self) -> SystemTime {
+        self.expiration
+    }
+
+    // The time left until the lock expires, or None if it has already
+    // expired. Like expiration, it includes the clock drift.
+    pub fn remaining_ttl(&self) -> Option<Duration> {
+        self.redlock.remaining_ttl(self.valid_until)
+    }
+
+    // How long the successful attempt took to reach the quorum.
+    pub fn acquisition_time(&self) -> Duration {
+        self.acquisition_time
+    }
+
+    // The number of attempts made to acquire the lock, 1 means it was
+    // acquired on the first try.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    // What the lock guarantees, see LockStrength.
+    pub fn strength(&self) -> LockStrength {
+        LockStrength::of(&self.advisory)
+    }
+
     // Release the acquired lock.
     pub fn unlock(&self) -> RedlockResult<()> {
         self.redlock.unlock(&self.resource_name, &self.value)
     }
 
-    // Extend the TTL of acquired lock.
-    pub fn extend(&self, ttl: Duration) -> RedlockResult<Lock> {
-        if self.expiration < SystemTime::now() {
-            return Err(RedlockError::LockExpired);
+    // Get the remaining TTL of the lock from the servers, or None if it is
+    // not held on a quorum of them anymore. The lock is valid as long as a
+    // quorum of servers hold it, so it's the TTL of the server of the
+    // quorum whose lock expires first. An error is returned if less than a
+    // quorum of servers have answered.
+    pub fn pttl(&self) -> RedlockResult<Option<Duration>> {
+        self.redlock.pttl(&self.resource_name, &self.value)
+    }
+
+    // Check against the servers that the lock is still held, i.e. that a
+    // quorum of them still have its value. If not, the lock has been lost
+    // and must not be relied on anymore. An error is returned if less than
+    // a quorum of servers have answered.
+    pub fn is_valid(&self) -> RedlockResult<bool> {
+        self.redlock.is_held(&self.resource_name, &self.value)
+    }
+
+    // Release the acquired lock with a single request to each server, and
+    // report what each server has found. Unlike unlock, it does not stop at
+    // a quorum of servers, so the servers which failed are the ones which
+    // may still hold the lock until it expires.
+    pub fn unlock_detailed(&self) -> UnlockReport {
+        self.redlock.unlock_report(&self.resource_name, &self.value)
+    }
+
+    // The record of the lock, to release it without the lock itself. It
+    // fails with TimeError if the lock expires before the unix epoch.
+    pub fn to_record(&self) -> RedlockResult<LockRecord> {
+        LockRecord::new(&self.resource_name, &self.value, self.expiration)
+    }
+
+    // Extend the TTL of acquired lock.
+    pub fn extend(&self, ttl: Duration) -> RedlockResult<Lock<'a>> {
+        self.redlock.check_unexpired(self.valid_until)?;
+
+        self.redlock.extend(&self.resource_name, &self.value, ttl)
+    }
+
+    // Extend the TTL of acquired lock like extend, but only if the time
+    // left until it expires, with the clock drift subtracted, is below the
+    // threshold, e.g. to check the lock often in a renewal loop without
+    // requesting the servers each time. None is returned if it's not.
+    pub fn extend_if_below(&self, threshold: Duration, ttl: Duration) -> RedlockResult<Option<Lock<'a>>> {
+        match self.remaining_ttl() {
+            Some(remaining) if remaining >= threshold => Ok(None),
+            _ => self.extend(ttl).map(Some),
+        }
+    }
+
+    // Extend the TTL of acquired lock like extend, and set the companion
+    // key of the resource, see Redlock::data_key, to the given data with
+    // the same TTL in the same script, e.g. the address of a leader for its
+    // followers. The data is left after an unlock until it expires, the
+    // lock itself tells whether it's still held.
+    pub fn extend_with_data(&self, ttl: Duration, data: &[u8]) -> RedlockResult<Lock<'a>> {
+        self.redlock.check_unexpired(self.valid_until)?;
+
+        self.redlock.extend_with_data(&self.resource_name, &self.value, ttl, data)
+    }
+
+    // Extend the TTL of acquired lock like extend, but update this lock
+    // instead of returning a new one, e.g. to keep renewing it in a loop.
+    // The lock is left as is if the extension fails.
+    pub fn extend_in_place(&mut self, ttl: Duration) -> RedlockResult<()> {
+        let extended = self.extend(ttl)?;
+        self.expiration = extended.expiration;
+        self.valid_until = extended.valid_until;
+        // Extended on a quorum of servers again
+        self.advisory.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Hand the lock over to the given value with a fresh TTL, without
+    // releasing it in between, e.g. to another worker which then owns it.
+    // The returned lock carries the new value, this one can no longer
+    // unlock nor extend it.
+    pub fn transfer(&self, new_value: &str, ttl: Duration) -> RedlockResult<Lock<'a>> {
+        self.redlock.check_unexpired(self.valid_until)?;
+        if new_value.is_empty() {
+            return Err(RedlockError::InvalidValue);
+        }
+
+        let info = RequestInfo::Transfer {
+            resource_value: &self.value,
+            new_value,
+        };
+        self.redlock.request(info, &self.resource_name, ttl, None)
+    }
+
+    // Wrap the acquired lock into a guard which releases it when dropped.
+    pub fn into_guard(self) -> LockGuard<'a> {
+        LockGuard {
+            lock: Some(self),
+            on_unlock_error: None,
+        }
+    }
+}
+
+// Two locks are equal if they hold the same resource with the same value,
+// whatever their expiration, e.g. a lock and its extension, so that they can
+// be deduplicated in a HashSet. The Redlock they come from is ignored.
+impl<'a> PartialEq for Lock<'a> {
+    fn eq(&self, other: &Lock<'a>) -> bool {
+        self.resource_name == other.resource_name && self.value == other.value
+    }
+}
+
+impl<'a> Eq for Lock<'a> {}
+
+impl<'a> Hash for Lock<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.resource_name.hash(state);
+        self.value.hash(state);
+    }
+}
+
+// LockStrength tells what a lock guarantees. A lock is acquired on a
+// quorum of servers, and only downgraded to advisory by the watch of its
+// quorum, see OwnedLock::watch_quorum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockStrength {
+    // The lock has been acquired on a quorum of servers, it's exclusive as
+    // long as it's valid.
+    Quorum,
+    // The lock has been found on less than a quorum of servers, or they
+    // could not tell: another client may hold it too. It's left to the
+    // owner to carry on in a degraded mode, e.g. for work which is safe to
+    // run twice, or to stop.
+    Advisory,
+}
+
+impl LockStrength {
+    fn of(advisory: &AtomicBool) -> LockStrength {
+        if advisory.load(Ordering::SeqCst) {
+            LockStrength::Advisory
+        } else {
+            LockStrength::Quorum
+        }
+    }
+}
+
+// UnlockOutcome is the result of releasing a lock on a single redis server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnlockOutcome {
+    // The lock has been released.
+    Released,
+    // The lock is not present, e.g. it has already expired.
+    NotPresent,
+    // The resource is locked with another value, the lock has been lost and
+    // acquired by someone else.
+    HeldByOther,
+}
+
+// UnlockReport is the result of releasing a lock on every redis server,
+// see Lock::unlock_detailed.
+#[derive(Debug)]
+pub struct UnlockReport {
+    // The number of servers which have released the lock.
+    pub deleted: usize,
+    // The number of servers which did not hold the lock anymore.
+    pub not_present: usize,
+    // The number of servers which hold the resource with another value.
+    pub held_by_other: usize,
+    // The number of servers which failed, the lock may be left on them.
+    pub errors: usize,
+    // The result of each server, in the order of the servers.
+    pub node_results: Vec<RedlockResult<UnlockOutcome>>,
+}
+
+impl UnlockReport {
+    fn new(node_results: Vec<RedlockResult<UnlockOutcome>>) -> UnlockReport {
+        let count = |expected: UnlockOutcome| {
+            node_results
+                .iter()
+                .filter(|result| matches!(**result, Ok(outcome) if outcome == expected))
+                .count()
+        };
+        UnlockReport {
+            deleted: count(UnlockOutcome::Released),
+            not_present: count(UnlockOutcome::NotPresent),
+            held_by_other: count(UnlockOutcome::HeldByOther),
+            errors: node_results.iter().filter(|result| result.is_err()).count(),
+            node_results,
+        }
+    }
+}
+
+// LockRecord identifies a lock outside of the process which holds it, e.g.
+// persisted to release the lock after a crash, see Lock::to_record and
+// Redlock::unlock_record. With the serde feature, it can be serialized.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockRecord {
+    pub resource_name: String,
+    pub value: String,
+    // The time the lock expires at, with the clock drift subtracted, in
+    // milliseconds since the unix epoch (1970-01-01T00:00:00Z).
+    pub expiration_unix_ms: u64,
+}
+
+impl LockRecord {
+    fn new(resource_name: &str, value: &str, expiration: SystemTime) -> RedlockResult<LockRecord> {
+        let since_epoch = expiration.duration_since(UNIX_EPOCH)?;
+        Ok(LockRecord {
+               resource_name: String::from(resource_name),
+               value: String::from(value),
+               expiration_unix_ms: util::num_milliseconds(&since_epoch),
+           })
+    }
+
+    // The time the lock expires at.
+    pub fn expiration(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.expiration_unix_ms)
+    }
+}
+
+// PollResult is the result of a single attempt to lock a resource with
+// poll_lock.
+#[derive(Debug)]
+pub enum PollResult<'a> {
+    // The lock has been acquired.
+    Acquired(Lock<'a>),
+    // The lock could not be acquired, the caller should try again after the
+    // given delay.
+    Retry { after: Duration },
+}
+
+// OwnedLock is a lock which owns a reference to its Redlock instead of
+// borrowing it, so that it can be moved across threads.
+#[derive(Debug)]
+pub struct OwnedLock {
+    pub(crate) redlock: Arc<Redlock>,
+    resource_name: String,
+    value: String,
+    expiration: SystemTime,
+    valid_until: Instant,
+    acquisition_time: Duration,
+    attempts: u32,
+    pub(crate) advisory: Arc<AtomicBool>,
+}
+
+impl OwnedLock {
+    fn new(redlock: Arc<Redlock>, lock: Lock<'_>) -> OwnedLock {
+        OwnedLock {
+            redlock,
+            resource_name: lock.resource_name,
+            value: lock.value,
+            expiration: lock.expiration,
+            valid_until: lock.valid_until,
+            acquisition_time: lock.acquisition_time,
+            attempts: lock.attempts,
+            advisory: lock.advisory,
+        }
+    }
+
+    // Like Lock::resource_name.
+    pub fn resource_name(&self) -> &str {
+        &self.resource_name
+    }
+
+    // Like Lock::value.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    // Like Lock::expiration.
+    pub fn expiration(&self) -> SystemTime {
+        self.expiration
+    }
+
+    // Like Lock::remaining_ttl.
+    pub fn remaining_ttl(&self) -> Option<Duration> {
+        self.redlock.remaining_ttl(self.valid_until)
+    }
+
+    // Like Lock::acquisition_time.
+    pub fn acquisition_time(&self) -> Duration {
+        self.acquisition_time
+    }
+
+    // Like Lock::attempts.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    // Like Lock::strength.
+    pub fn strength(&self) -> LockStrength {
+        LockStrength::of(&self.advisory)
+    }
+
+    // Like Lock::unlock.
+    pub fn unlock(&self) -> RedlockResult<()> {
+        self.redlock.unlock(&self.resource_name, &self.value)
+    }
+
+    // Like Lock::pttl.
+    pub fn pttl(&self) -> RedlockResult<Option<Duration>> {
+        self.redlock.pttl(&self.resource_name, &self.value)
+    }
+
+    // Like Lock::is_valid.
+    pub fn is_valid(&self) -> RedlockResult<bool> {
+        self.redlock.is_held(&self.resource_name, &self.value)
+    }
+
+    // Like Lock::unlock_detailed.
+    pub fn unlock_detailed(&self) -> UnlockReport {
+        self.redlock.unlock_report(&self.resource_name, &self.value)
+    }
+
+    // Like Lock::to_record.
+    pub fn to_record(&self) -> RedlockResult<LockRecord> {
+        LockRecord::new(&self.resource_name, &self.value, self.expiration)
+    }
+
+    // Like Lock::extend.
+    pub fn extend(&self, ttl: Duration) -> RedlockResult<OwnedLock> {
+        self.redlock.check_unexpired(self.valid_until)?;
+        let lock = self.redlock.extend(&self.resource_name, &self.value, ttl)?;
+        Ok(OwnedLock::new(self.redlock.clone(), lock))
+    }
+}
+
+// FencedLock is a lock which also carries a fencing token, which increases
+// each time the resource is locked. The downstream storage can reject the
+// writes with a token smaller than the last one it has seen, in case the
+// lock has expired while its owner was paused.
+//
+// Each redis server keeps its own counter, the token is the largest one
+// returned by the servers which granted the lock. It is only guaranteed to
+// be monotonic when there is a single authoritative counter, with several
+// servers a server losing its counter (e.g. a restart without persistence)
+// may break it.
+#[derive(Debug)]
+pub struct FencedLock<'a> {
+    lock: Lock<'a>,
+    fence: u64,
+}
+
+impl<'a> FencedLock<'a> {
+    // The fencing token of the lock.
+    pub fn fence_token(&self) -> u64 {
+        self.fence
+    }
+
+    // Drop the fencing token and get the underlying lock.
+    pub fn into_lock(self) -> Lock<'a> {
+        self.lock
+    }
+}
+
+impl<'a> Deref for FencedLock<'a> {
+    type Target = Lock<'a>;
+
+    fn deref(&self) -> &Lock<'a> {
+        &self.lock
+    }
+}
+
+// LockGuard releases the wrapped lock when it goes out of scope. It is
+// Send, but not Sync because of its unlock error callback.
+pub struct LockGuard<'a> {
+    lock: Option<Lock<'a>>,
+    on_unlock_error: Option<Box<dyn FnOnce(RedlockError) + Send + 'a>>,
+}
+
+impl<'a> LockGuard<'a> {
+    // Register a callback invoked with the error if the release on drop fails.
+    pub fn on_unlock_error<F>(mut self, callback: F) -> LockGuard<'a>
+        where F: FnOnce(RedlockError) + Send + 'a
+    {
+        self.on_unlock_error = Some(Box::new(callback));
+        self
+    }
+
+    // Extend the TTL of the guarded lock, keeping it guarded.
+    pub fn extend(&mut self, ttl: Duration) -> RedlockResult<()> {
+        let extended = self.lock.as_ref().unwrap().extend(ttl)?;
+        self.lock = Some(extended);
+        Ok(())
+    }
+
+    // Release the guarded lock now, returning the result of the unlock.
+    pub fn unlock(mut self) -> RedlockResult<()> {
+        self.lock.take().unwrap().unlock()
+    }
+
+    // Give up the guard without releasing the lock, the lock will then
+    // outlive the scope until it is unlocked or expires.
+    pub fn into_unguarded(mut self) -> Lock<'a> {
+        self.lock.take().unwrap()
+    }
+}
+
+impl<'a> Deref for LockGuard<'a> {
+    type Target = Lock<'a>;
+
+    fn deref(&self) -> &Lock<'a> {
+        self.lock.as_ref().unwrap()
+    }
+}
+
+impl<'a> Drop for LockGuard<'a> {
+    fn drop(&mut self) {
+        if let Some(lock) = self.lock.take() {
+            if let Err(err) = lock.unlock() {
+                if let Some(callback) = self.on_unlock_error.take() {
+                    callback(err);
+                }
+            }
+        }
+    }
+}
+
+impl<'a> fmt::Debug for LockGuard<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LockGuard").field("lock", &self.lock).finish()
+    }
+}
+
+// BackoffStrategy tells how the delay between two attempts grows, the
+// jitter is applied on top of it.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackoffStrategy {
+    // Wait for retry_delay before every retry.
+    Constant,
+    // Multiply the delay by base after every retry, starting from
+    // retry_delay, without exceeding cap.
+    Exponential {
+        base: u32,
+        #[cfg_attr(feature = "serde", serde(with = "util::millis"))]
+        cap: Duration,
+    },
+}
+
+// Drift tells how the clock drift subtracted from the validity of a lock is
+// computed.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drift {
+    // drift_factor * ttl + clock_drift_ms, rounded to the millisecond, so
+    // that it grows with the TTL.
+    Factor,
+    // The given duration whatever the TTL, e.g. when the clocks of the
+    // servers are known to be synchronized within a bound.
+    Absolute(#[cfg_attr(feature = "serde", serde(with = "util::millis"))] Duration),
+}
+
+// PartialRetryPolicy tells what to do when an attempt is granted by some of
+// the servers, but not by a quorum of them.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PartialRetryPolicy {
+    // Release the servers which granted the attempt, and start over with a
+    // new attempt after the retry delay.
+    #[default]
+    Reset,
+    // Keep the servers which granted the attempt, and request the other
+    // ones again after delay, up to retries times, as long as the lock
+    // would still be valid for min_validity. The lock is valid from the
+    // start of the attempt, the servers granting it later only hold it
+    // longer. The servers are released and the request starts over if the
+    // quorum is still not reached.
+    RetryOthers {
+        retries: u32,
+        #[cfg_attr(feature = "serde", serde(with = "util::millis"))]
+        delay: Duration,
+    },
+}
+
+// The callback of OrphanPolicy::Record, given the resource name and the
+// value of an orphaned lock.
+pub type OrphanRecorder = Arc<dyn Fn(&str, &str) + Send + Sync>;
+
+// The function mapping a resource name to its key, see Config::key_mapper.
+pub type KeyMapper = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+// The function giving the current addresses of the servers, see
+// Config::resolver.
+pub type AddrResolver = Arc<dyn Fn() -> redis::RedisResult<Vec<redis::ConnectionInfo>> + Send + Sync>;
+
+// OrphanPolicy tells what to do when the locks acquired by a failed
+// attempt can not be released, they are then left until they expire.
+#[derive(Clone, Default)]
+pub enum OrphanPolicy {
+    // Ignore the orphaned lock and retry.
+    #[default]
+    Ignore,
+    // Abort the request with the OrphanedLock error.
+    Abort,
+    // Give the resource name and the value of the orphaned lock to the
+    // callback, e.g. to release it later, and retry.
+    Record(OrphanRecorder),
+}
+
+// Mapper wraps the key mapper of Redlock, since a function is not Debug.
+#[derive(Clone)]
+struct Mapper(KeyMapper);
+
+impl fmt::Debug for Mapper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("KeyMapper")
+    }
+}
+
+// Resolver wraps the address resolver of Redlock, along with the
+// credentials merged into the addresses it gives.
+#[derive(Clone)]
+struct Resolver {
+    resolve: AddrResolver,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("AddrResolver")
+    }
+}
+
+impl fmt::Debug for OrphanPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OrphanPolicy::Ignore => f.write_str("Ignore"),
+            OrphanPolicy::Abort => f.write_str("Abort"),
+            OrphanPolicy::Record(_) => f.write_str("Record"),
+        }
+    }
+}
+
+// Configuration of Redlock. With the serde feature, it can be loaded from
+// a configuration file, the durations are given in milliseconds and the
+// settings other than addrs can be omitted.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Config<T>
+    where T: redis::IntoConnectionInfo
+{
+    pub addrs: Vec<T>,
+    // The number of attempts to lock, the first one included, so at least 1.
+    #[cfg_attr(feature = "serde", serde(default = "defaults::retry_count"))]
+    pub retry_count: u32,
+    #[cfg_attr(feature = "serde",
+               serde(default = "defaults::retry_delay", with = "util::millis"))]
+    pub retry_delay: Duration,
+    #[cfg_attr(feature = "serde", serde(default = "defaults::retry_jitter"))]
+    pub retry_jitter: u32,
+    #[cfg_attr(feature = "serde", serde(default = "defaults::backoff"))]
+    pub backoff: BackoffStrategy,
+    // The seed of the random generator of the retry jitters, to make them
+    // reproducible in tests. The jitters are truly random if it's None.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub rng_seed: Option<u64>,
+    // The clock drift subtracted from the validity of a lock is
+    // drift_factor * ttl + clock_drift_ms, rounded to the millisecond, with
+    // Drift::Factor, otherwise the absolute drift.
+    #[cfg_attr(feature = "serde", serde(default = "defaults::drift_factor"))]
+    pub drift_factor: f32,
+    #[cfg_attr(feature = "serde", serde(default = "defaults::clock_drift_ms"))]
+    pub clock_drift_ms: u64,
+    #[cfg_attr(feature = "serde", serde(default = "defaults::drift"))]
+    pub drift: Drift,
+    #[cfg_attr(feature = "serde", serde(default = "defaults::pool_size"))]
+    pub pool_size: u32,
+    // The number of random bytes of the lock value.
+    #[cfg_attr(feature = "serde", serde(default = "defaults::token_len"))]
+    pub token_len: usize,
+    // The time a lock must still be valid for once acquired, otherwise it
+    // is released and the request retried.
+    #[cfg_attr(feature = "serde",
+               serde(default = "defaults::min_validity", with = "util::millis"))]
+    pub min_validity: Duration,
+    // The timeout of connecting and sending a command to a redis server,
+    // so that a hung server fails fast. There is no timeout if it's None.
+    #[cfg_attr(feature = "serde", serde(default, with = "util::option_millis"))]
+    pub command_timeout: Option<Duration>,
+    // The prefix prepended to every resource name, so that the services
+    // sharing the same servers do not lock each other's resources.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub key_prefix: String,
+    // The labels of the redis servers, in the same order as addrs, used to
+    // tell which server has failed. The servers are labelled with their
+    // index if it's empty.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub labels: Vec<String>,
+    // The maximum number of requests to the servers a single lock request
+    // may make across all its attempts. There is no limit if it's None.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub max_operations: Option<u32>,
+    // What to do with the locks of a failed attempt which can not be
+    // released. It can not be deserialized and defaults to Ignore.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub orphan_policy: OrphanPolicy,
+    // The retry count and delay of unlocking, so that it can give up
+    // sooner than locking, e.g. when releasing on drop. An unlock failing
+    // is usually benign, the lock expires after its TTL anyway. The lock
+    // settings are used if they are None.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub unlock_retry_count: Option<u32>,
+    #[cfg_attr(feature = "serde", serde(default, with = "util::option_millis"))]
+    pub unlock_retry_delay: Option<Duration>,
+    // Whether to keep track of the locks handed out and not unlocked yet,
+    // so that they can be listed and released, e.g. before a shutdown. It
+    // costs a map update on every lock and unlock, and the locks which
+    // expire or are released by other means are not removed.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub track_locks: bool,
+    // The scripts to run instead of the built-in ones, e.g. to check more
+    // conditions atomically. They are given the resource as KEYS[1], the
+    // value of the lock as ARGV[1] and the TTL in milliseconds as ARGV[2],
+    // and must reply:
+    //   lock: the status OK if the lock is set, nil otherwise,
+    //   unlock: 1 if the lock is released, -1 if the resource is held with
+    //   another value, 0 if it is not locked,
+    //   extend: 1 if the TTL of the lock is set, 0 otherwise.
+    // An override is loaded on the reachable servers when creating the
+    // Redlock, which fails with InvalidScript if it does not compile. The
+    // built-in script is kept if it's None or empty. The fenced locks and
+    // lock_or_existing always use the built-in scripts.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub lock_script: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub unlock_script: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub extend_script: Option<String>,
+    // Whether to reject an even number of servers, which tolerates no more
+    // failures than one server less, e.g. 4 servers have a quorum of 3 and
+    // tolerate 1 failure like 3 servers.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub strict_odd_nodes: bool,
+    // The number of failures in a row after which a server is skipped for
+    // breaker_cooldown, before a single request probes it again, so that a
+    // server which keeps failing does not slow every attempt down. The
+    // servers are never skipped if it's None. The async requests do not
+    // skip the servers.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub breaker_threshold: Option<u32>,
+    #[cfg_attr(feature = "serde",
+               serde(default = "defaults::breaker_cooldown", with = "util::millis"))]
+    pub breaker_cooldown: Duration,
+    // The function mapping each resource name to its key on the servers,
+    // after which the key prefix is prepended, e.g. to hash the long or
+    // binary resource names to a key of a fixed length. The resource names
+    // are used as is if it's None. It must be the same for every instance
+    // locking the same resources. The hash tag of the resource name, if
+    // any, is lost unless the mapper keeps it.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub key_mapper: Option<KeyMapper>,
+    // The credentials of the redis servers, merged into each address so
+    // that they do not have to be encoded in every one, e.g. the ACL user
+    // of redis 6 and its password. They replace the credentials of the
+    // addresses, but not those of the redis cluster nodes.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub username: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub password: Option<String>,
+    // The largest random reduction of the TTL of each lock and extension,
+    // so that the locks taken at the same time do not all expire at once,
+    // and their owners do not all try to lock again together. The TTL is
+    // never reduced by more than half, and the validity of the lock is
+    // computed from the reduced TTL, which is the one sent to redis.
+    #[cfg_attr(feature = "serde", serde(default, with = "util::millis"))]
+    pub expiration_jitter: Duration,
+    // What to do when an attempt is granted by less than a quorum of the
+    // servers, see PartialRetryPolicy. The async requests always reset.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub partial_retry: PartialRetryPolicy,
+    // The function giving the current addresses of the servers, e.g. the
+    // masters monitored by Redis Sentinel, see sentinel_resolver. The
+    // addresses are taken from it instead of addrs when Redlock is created,
+    // then it's called again after an attempt in which a server could not
+    // be reached or refused to write, so that the servers follow the
+    // failovers. It must give as many addresses each time. Each of them
+    // must be an independent master, not a replica of another one, or the
+    // quorum does not hold.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub resolver: Option<AddrResolver>,
+    // The TTLs used by lock_default, by prefix of the resource names. The
+    // longest prefix of a resource name gives its TTL, so a whole resource
+    // name gives the TTL of that resource, and the empty prefix the TTL of
+    // the resources which do not match any other.
+    #[cfg_attr(feature = "serde", serde(default, with = "util::millis_map"))]
+    pub default_ttls: HashMap<String, Duration>,
+}
+
+impl<T: redis::IntoConnectionInfo> Config<T> {
+    // Create a configuration with the given addresses, and the default
+    // values for the other settings.
+    pub fn with_addrs(addrs: Vec<T>) -> Config<T> {
+        Config {
+            addrs,
+            retry_count: 10,
+            retry_delay: Duration::from_millis(400),
+            retry_jitter: 400,
+            backoff: BackoffStrategy::Constant,
+            rng_seed: None,
+            drift_factor: 0.01,
+            clock_drift_ms: 2,
+            drift: Drift::Factor,
+            pool_size: 8,
+            token_len: 20,
+            min_validity: Duration::from_millis(0),
+            command_timeout: None,
+            key_prefix: String::new(),
+            labels: Vec::new(),
+            max_operations: None,
+            orphan_policy: OrphanPolicy::Ignore,
+            unlock_retry_count: None,
+            unlock_retry_delay: None,
+            track_locks: false,
+            lock_script: None,
+            unlock_script: None,
+            extend_script: None,
+            strict_odd_nodes: false,
+            breaker_threshold: None,
+            breaker_cooldown: Duration::from_millis(1000),
+            key_mapper: None,
+            username: None,
+            password: None,
+            expiration_jitter: Duration::from_millis(0),
+            partial_retry: PartialRetryPolicy::Reset,
+            resolver: None,
+            default_ttls: HashMap::new(),
+        }
+    }
+}
+
+impl Default for Config<&'static str> {
+    fn default() -> Self {
+        Config::with_addrs(vec!["redis://127.0.0.1"])
+    }
+}
+
+// The default values of the settings omitted from a deserialized Config.
+#[cfg(feature = "serde")]
+mod defaults {
+    use std::time::Duration;
+    use super::{BackoffStrategy, Config, Drift};
+
+    fn config() -> Config<String> {
+        Config::with_addrs(Vec::new())
+    }
+
+    pub fn retry_count() -> u32 {
+        config().retry_count
+    }
+
+    pub fn retry_delay() -> Duration {
+        config().retry_delay
+    }
+
+    pub fn retry_jitter() -> u32 {
+        config().retry_jitter
+    }
+
+    pub fn backoff() -> BackoffStrategy {
+        config().backoff
+    }
+
+    pub fn drift_factor() -> f32 {
+        config().drift_factor
+    }
+
+    pub fn clock_drift_ms() -> u64 {
+        config().clock_drift_ms
+    }
+
+    pub fn drift() -> Drift {
+        config().drift
+    }
+
+    pub fn pool_size() -> u32 {
+        config().pool_size
+    }
+
+    pub fn token_len() -> usize {
+        config().token_len
+    }
+
+    pub fn min_validity() -> Duration {
+        config().min_validity
+    }
+
+    pub fn breaker_cooldown() -> Duration {
+        config().breaker_cooldown
+    }
+}
+
+// Redlock can be cloned cheaply, the clones share the configuration and the
+// observer, but each one keeps its own connection pools. It is Send and
+// Sync, so it can be shared between threads, e.g. in a static or an Arc,
+// which is why the observers and the backends must be Send and Sync too.
+#[derive(Debug, Clone)]
+pub struct Redlock {
+    pub(crate) nodes: Vec<Node>,
+    pub(crate) retry_count: u32,
+    retry_delay: Duration,
+    retry_jitter: u32,
+    backoff: BackoffStrategy,
+    rng: Option<Arc<Mutex<StdRng>>>,
+    drift_factor: f32,
+    clock_drift_ms: u64,
+    drift: Drift,
+    pub(crate) token_len: usize,
+    pub(crate) min_validity: Duration,
+    command_timeout: Option<Duration>,
+    expiration_jitter: Duration,
+    partial_retry: PartialRetryPolicy,
+    pub(crate) key_prefix: String,
+    key_mapper: Option<Mapper>,
+    resolver: Option<Resolver>,
+    // The default TTLs, the longest prefixes first.
+    default_ttls: Vec<(String, Duration)>,
+    labels: Vec<String>,
+    max_operations: Option<u32>,
+    orphan_policy: OrphanPolicy,
+    pub(crate) unlock_retry_count: u32,
+    unlock_retry_delay: Option<Duration>,
+    // The values of the locks handed out by resource name, shared by the
+    // clones, if they are tracked.
+    pub(crate) held_locks: Option<Arc<Mutex<HashMap<String, String>>>>,
+    // The number of locks which could not be released, shared by the
+    // clones.
+    orphaned: Arc<AtomicU64>,
+    // The circuit breakers of the servers, shared by the clones, if any.
+    breakers: Option<Arc<Vec<Breaker>>>,
+    pub(crate) quorum: usize,
+    observer: Arc<dyn Observer>,
+    pub(crate) clock: Arc<dyn Clock>,
+}
+
+impl Redlock {
+    // Create a new redlock instance.
+    pub fn new<T: redis::IntoConnectionInfo>(mut config: Config<T>) -> RedlockResult<Redlock> {
+        let addrs = mem::take(&mut config.addrs);
+        let mut clients = Vec::with_capacity(addrs.len());
+        if let Some(ref resolve) = config.resolver {
+            for (index, info) in resolve().map_err(RedlockError::ResolveFailed)?.into_iter().enumerate() {
+                let client = redis::Client::open(info)
+                    .map_err(|err| RedlockError::InvalidAddress(index, err))?;
+                clients.push(Client::from(client));
+            }
+        } else {
+            for (index, addr) in addrs.into_iter().enumerate() {
+                let client = redis::Client::open(addr)
+                    .map_err(|err| RedlockError::InvalidAddress(index, err))?;
+                clients.push(Client::from(client));
+            }
+        }
+
+        Redlock::with_clients(clients, config)
+    }
+
+    // Create a new redlock instance reusing the given redis clients, e.g.
+    // when they are already configured elsewhere. The addresses of the
+    // configuration are ignored.
+    pub fn from_clients<T>(clients: Vec<redis::Client>, config: Config<T>) -> RedlockResult<Redlock>
+        where T: redis::IntoConnectionInfo
+    {
+        Redlock::with_clients(clients.into_iter().map(Client::from).collect(), config)
+    }
+
+    // Create a new redlock instance with the given clients, the addresses
+    // of the configuration are ignored.
+    pub(crate) fn with_clients<T>(clients: Vec<Client>, config: Config<T>) -> RedlockResult<Redlock>
+        where T: redis::IntoConnectionInfo
+    {
+        let clients = clients
+            .into_iter()
+            .enumerate()
+            .map(|(index, client)| {
+                     client
+                         .with_credentials(config.username.as_deref(), config.password.as_deref())
+                         .map_err(|err| RedlockError::InvalidAddress(index, err))
+                 })
+            .collect::<RedlockResult<Vec<Client>>>()?;
+        let scripts = Arc::new(Scripts::new(config.lock_script.as_deref(),
+                                            config.unlock_script.as_deref(),
+                                            config.extend_script.as_deref()));
+        let pools: Vec<ConnectionPool> = clients
+            .into_iter()
+            .map(|client| {
+                     ConnectionPool::new(client, config.pool_size as usize, config.command_timeout)
+                         .with_scripts(scripts.clone())
+                 })
+            .collect();
+
+        // Make sure the overrides compile, the unreachable servers can not
+        // tell and are skipped
+        let overrides = [("lock", &config.lock_script, &scripts.lock),
+                         ("unlock", &config.unlock_script, &scripts.unlock),
+                         ("extend", &config.extend_script, &scripts.extend)];
+        for &(name, code, script) in &overrides {
+            if code.as_ref().is_none_or(|code| code.trim().is_empty()) {
+                continue;
+            }
+            for pool in &pools {
+                if let Ok(mut con) = pool.get() {
+                    script
+                        .load(&mut *con)
+                        .map_err(|err| RedlockError::InvalidScript(name, err))?;
+                }
+            }
+        }
+
+        let nodes = pools.into_iter().map(Node::Redis).collect();
+
+        Redlock::with_nodes(nodes, config)
+    }
+
+    // Create a new redlock instance running against the given backends
+    // instead of redis servers, the addresses of the configuration are
+    // ignored.
+    pub fn with_backends<T>(backends: Vec<Arc<dyn LockBackend>>,
+                            config: Config<T>)
+                            -> RedlockResult<Redlock>
+        where T: redis::IntoConnectionInfo
+    {
+        Redlock::with_nodes(backends.into_iter().map(Node::Backend).collect(), config)
+    }
+
+    fn with_nodes<T>(nodes: Vec<Node>, config: Config<T>) -> RedlockResult<Redlock>
+        where T: redis::IntoConnectionInfo
+    {
+        if nodes.is_empty() {
+            return Err(RedlockError::NoServerError);
+        }
+        if config.strict_odd_nodes && nodes.len().is_multiple_of(2) {
+            return Err(RedlockError::EvenNodeCount(nodes.len()));
+        }
+        if config.retry_jitter as u64 > util::num_milliseconds(&config.retry_delay) {
+            return Err(RedlockError::DelayJitterError);
+        }
+        if !(0.0..1.0).contains(&config.drift_factor) {
+            return Err(RedlockError::InvalidDriftFactor(config.drift_factor));
+        }
+        // No attempt would ever be made, locking would fail right away
+        if config.retry_count == 0 || config.unlock_retry_count == Some(0) {
+            return Err(RedlockError::InvalidRetryCount);
+        }
+        if config.token_len == 0 {
+            return Err(RedlockError::InvalidConfig("token_len must be at least 1"));
+        }
+        if config.breaker_threshold == Some(0) {
+            return Err(RedlockError::InvalidConfig("breaker_threshold must be at least 1"));
+        }
+        if !config.labels.is_empty() && config.labels.len() != nodes.len() {
+            return Err(RedlockError::InvalidConfig("labels must have one label per server"));
+        }
+
+        let quorum = (nodes.len() as f64 / 2_f64).floor() as usize + 1;
+        let labels = if config.labels.is_empty() {
+            (0..nodes.len()).map(|index| index.to_string()).collect()
+        } else {
+            config.labels
+        };
+        let cooldown = config.breaker_cooldown;
+        let breakers = config.breaker_threshold.map(|threshold| {
+            Arc::new((0..nodes.len())
+                         .map(|_| Breaker::new(threshold, cooldown))
+                         .collect())
+        });
+
+        let mut default_ttls: Vec<(String, Duration)> = config.default_ttls.into_iter().collect();
+        default_ttls.sort_by_key(|(prefix, _)| cmp::Reverse(prefix.len()));
+        let (username, password) = (config.username, config.password);
+        let resolver = config.resolver.map(|resolve| {
+                                               Resolver {
+                                                   resolve,
+                                                   username,
+                                                   password,
+                                               }
+                                           });
+
+        Ok(Redlock {
+               nodes,
+               retry_count: config.retry_count,
+               retry_delay: config.retry_delay,
+               retry_jitter: config.retry_jitter,
+               backoff: config.backoff,
+               // Both halves of the seed, since usize may only be 32 bits
+               rng: config
+                   .rng_seed
+                   .map(|seed| {
+                            let seed = [seed as usize, (seed >> 32) as usize];
+                            Arc::new(Mutex::new(StdRng::from_seed(&seed[..])))
+                        }),
+               drift_factor: config.drift_factor,
+               clock_drift_ms: config.clock_drift_ms,
+               drift: config.drift,
+               token_len: config.token_len,
+               min_validity: config.min_validity,
+               command_timeout: config.command_timeout,
+               expiration_jitter: config.expiration_jitter,
+               partial_retry: config.partial_retry,
+               key_prefix: config.key_prefix,
+               key_mapper: config.key_mapper.map(Mapper),
+               resolver,
+               default_ttls,
+               labels,
+               max_operations: config.max_operations,
+               orphan_policy: config.orphan_policy,
+               unlock_retry_count: config.unlock_retry_count.unwrap_or(config.retry_count),
+               unlock_retry_delay: config.unlock_retry_delay,
+               held_locks: if config.track_locks {
+                   Some(Arc::new(Mutex::new(HashMap::new())))
+               } else {
+                   None
+               },
+               orphaned: Arc::new(AtomicU64::new(0)),
+               breakers,
+               quorum,
+               observer: Arc::new(NoopObserver),
+               clock: Arc::new(SystemClock),
+           })
+    }
+
+    // Ask the resolver for the current addresses of the servers, and
+    // connect to them from now on, see Config::resolver. The number of
+    // servers which have moved is returned, it's 0 without a resolver. The
+    // clones of this instance keep their own connections, and still connect
+    // to the previous addresses until they resolve them too.
+    pub fn resolve_addrs(&self) -> RedlockResult<usize> {
+        let resolver = match self.resolver {
+            Some(ref resolver) => resolver,
+            None => return Ok(0),
+        };
+        let infos = (resolver.resolve)().map_err(RedlockError::ResolveFailed)?;
+        if infos.len() != self.nodes.len() {
+            return Err(RedlockError::ResolvedNodeCount(self.nodes.len(), infos.len()));
+        }
+
+        let mut clients = Vec::with_capacity(infos.len());
+        for (index, info) in infos.into_iter().enumerate() {
+            let client = redis::Client::open(info)
+                .and_then(|client| {
+                              Client::from(client).with_credentials(resolver.username.as_deref(),
+                                                                    resolver.password.as_deref())
+                          })
+                .map_err(|err| RedlockError::InvalidAddress(index, err))?;
+            clients.push(client);
+        }
+
+        let mut moved = 0;
+        for (node, client) in self.nodes.iter().zip(clients) {
+            if let Node::Redis(ref pool) = *node {
+                if pool.replace_client(client) {
+                    moved += 1;
+                }
+            }
+        }
+        trace_event!(moved, "addresses resolved");
+        Ok(moved)
+    }
+
+    // The number of servers which must grant a request.
+    pub fn quorum(&self) -> usize {
+        self.quorum
+    }
+
+    // The number of redis servers.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    // Whether this instance runs against a single server, in which case the
+    // quorum is 1 and the servers are requested from the calling thread. A
+    // single server offers weaker guarantees: the lock is lost if it fails
+    // over or restarts without persistence.
+    pub fn is_single_node(&self) -> bool {
+        self.nodes.len() == 1
+    }
+
+    // The label of the redis server at the given index.
+    pub fn node_label(&self, index: usize) -> &str {
+        &self.labels[index]
+    }
+
+    // The time left until a lock valid until the given instant expires.
+    pub(crate) fn remaining_ttl(&self, valid_until: Instant) -> Option<Duration> {
+        valid_until.checked_duration_since(self.clock.instant())
+    }
+
+    // Fail with LockExpired if a lock valid until the given instant has
+    // expired, before requesting the servers for it.
+    pub(crate) fn check_unexpired(&self, valid_until: Instant) -> RedlockResult<()> {
+        if valid_until < self.clock.instant() {
+            return Err(RedlockError::LockExpired);
+        }
+        Ok(())
+    }
+
+    // The key of the given resource on the servers.
+    pub(crate) fn key(&self, resource_name: &str) -> String {
+        match self.key_mapper {
+            Some(Mapper(ref mapper)) => format!("{}{}", self.key_prefix, mapper(resource_name)),
+            None => format!("{}{}", self.key_prefix, resource_name),
+        }
+    }
+
+    // Set the clock which the expiration and the validity of the locks are
+    // computed with, e.g. a TestClock in the tests.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Redlock {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    // Set the observer notified of the lock requests.
+    pub fn with_observer<O: Observer + 'static>(mut self, observer: O) -> Redlock {
+        self.observer = Arc::new(observer);
+        self
+    }
+
+    // Locks the given resource using the Redlock algorithm.
+    pub fn lock(&self, resource_name: &str, ttl: Duration) -> RedlockResult<Lock<'_>> {
+        let info = RequestInfo::Lock {
+            resource_value: None,
+            fence: None,
+        };
+        self.request(info, resource_name, ttl, None)
+    }
+
+    // Locks the given resource like lock, for its default TTL, see
+    // Config::default_ttls. It fails with NoDefaultTtl if none matches.
+    pub fn lock_default(&self, resource_name: &str) -> RedlockResult<Lock<'_>> {
+        match self.default_ttl(resource_name) {
+            Some(ttl) => self.lock(resource_name, ttl),
+            None => Err(RedlockError::NoDefaultTtl(String::from(resource_name))),
+        }
+    }
+
+    // The default TTL of the given resource, the one of its longest prefix
+    // in Config::default_ttls, if any.
+    pub fn default_ttl(&self, resource_name: &str) -> Option<Duration> {
+        self.default_ttls
+            .iter()
+            .find(|(prefix, _)| resource_name.starts_with(prefix.as_str()))
+            .map(|&(_, ttl)| ttl)
+    }
+
+    // Locks the given resource using the Redlock algorithm, and gets a
+    // fencing token along with the lock.
+    pub fn lock_fenced(&self, resource_name: &str, ttl: Duration) -> RedlockResult<FencedLock<'_>> {
+        let fence = AtomicU64::new(0);
+        let info = RequestInfo::Lock {
+            resource_value: None,
+            fence: Some(&fence),
+        };
+        let lock = self.request(info, resource_name, ttl, None)?;
+
+        Ok(FencedLock {
+               lock,
+               fence: fence.load(Ordering::SeqCst),
+           })
+    }
+
+    // Locks all the given resources, or none of them. The resources are
+    // locked in sorted order, so that two callers locking overlapping sets
+    // can not deadlock each other. If any of them can not be locked, the
+    // ones already acquired are released and the error is returned.
+    pub fn lock_many(&self, resource_names: &[&str], ttl: Duration) -> RedlockResult<Vec<Lock<'_>>> {
+        let mut resource_names = resource_names.to_vec();
+        resource_names.sort();
+        resource_names.dedup();
+
+        let mut locks = Vec::with_capacity(resource_names.len());
+        for resource_name in resource_names {
+            match self.lock(resource_name, ttl) {
+                Ok(lock) => locks.push(lock),
+                Err(err) => {
+                    for lock in &locks {
+                        let _ = self.release_best_effort(&lock.resource_name, &lock.value);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(locks)
+    }
+
+    // Locks the given resource like lock, but the TTL is computed by the
+    // given function right before each attempt, e.g. from the load of the
+    // system, so that it reflects the latest state. See validity to tell
+    // how long a lock with a given TTL is valid for.
+    pub fn lock_with<F>(&self, resource_name: &str, ttl: F) -> RedlockResult<Lock<'_>>
+        where F: Fn() -> Duration
+    {
+        let info = RequestInfo::Lock {
+            resource_value: None,
+            fence: None,
+        };
+        self.request_with(info, resource_name, &ttl, None)
+    }
+
+    // The time a lock with the given TTL is valid for once acquired, that is
+    // the TTL minus the clock drift. The lock is valid for the acquisition
+    // time less, which is counted from the start of the successful attempt,
+    // see Lock::acquisition_time.
+    pub fn validity(&self, ttl: Duration) -> Duration {
+        ttl.saturating_sub(self.get_drift(&ttl))
+    }
+
+    // An estimate of the smallest TTL which leaves a lock valid for at least
+    // min_validity, once the clock drift and the acquisition time are
+    // subtracted. A shorter TTL may hand out locks which are expired, or
+    // about to, by the time they are returned, or never acquire them.
+    //
+    // The acquisition time is not measured, it assumes the worst case of
+    // the configuration: twice the command timeout if there is one, to
+    // connect and then send the command, since the servers are requested
+    // concurrently. Otherwise the requests are not bounded, and it takes
+    // retry_delay * retry_count as a conservative guess. It does not account
+    // for the pauses of the process, e.g. GC or swapping in the caller, nor
+    // for the time the lock is meant to be held: the TTL must be larger.
+    pub fn min_recommended_ttl(&self) -> Duration {
+        let acquisition = match self.command_timeout {
+            Some(command_timeout) => command_timeout.saturating_mul(2),
+            None => self.retry_delay.saturating_mul(self.retry_count),
+        };
+        let needed = acquisition.saturating_add(self.min_validity);
+
+        // Solve ttl - drift(ttl) > needed, the drift may grow with the TTL
+        let mut ttl = match self.drift {
+            Drift::Factor => {
+                let needed_ms = util::num_milliseconds(&needed) as f64 + self.clock_drift_ms as f64;
+                Duration::from_millis((needed_ms / (1.0 - self.drift_factor as f64)).ceil() as u64)
+            }
+            Drift::Absolute(drift) => needed.saturating_add(drift),
+        };
+        // Round up past the rounding of the drift to the millisecond
+        while self.validity(ttl) <= needed {
+            ttl += Duration::from_millis(1);
+        }
+        ttl
+    }
+
+    // Locks the given resource using the Redlock algorithm, with the given
+    // value instead of a random one.
+    pub fn lock_with_value(&self,
+                           resource_name: &str,
+                           value: &str,
+                           ttl: Duration)
+                           -> RedlockResult<Lock<'_>> {
+        if value.is_empty() {
+            return Err(RedlockError::InvalidValue);
+        }
+
+        let info = RequestInfo::Lock {
+            resource_value: Some(value),
+            fence: None,
+        };
+        self.request(info, resource_name, ttl, None)
+    }
+
+    // Locks the given resource with the given value, or refreshes the TTL
+    // if it is already locked with the same value, so that retrying a lock
+    // request is safe. This is not reentrancy: the lock is not counted, a
+    // single unlock releases it.
+    pub fn lock_or_existing(&self,
+                            resource_name: &str,
+                            value: &str,
+                            ttl: Duration)
+                            -> RedlockResult<Lock<'_>> {
+        if value.is_empty() {
+            return Err(RedlockError::InvalidValue);
+        }
+
+        self.request(RequestInfo::LockOrExisting { resource_value: value },
+                     resource_name,
+                     ttl,
+                     None)
+    }
+
+    // Locks the given resource, returning a lock which holds a reference
+    // to this shared Redlock.
+    pub fn lock_owned(self: &Arc<Self>, resource_name: &str, ttl: Duration) -> RedlockResult<OwnedLock> {
+        let lock = self.lock(resource_name, ttl)?;
+        Ok(OwnedLock::new(self.clone(), lock))
+    }
+
+    // Wrap the Redlock into an Arc, to share it and acquire owned locks.
+    pub fn into_arc(self) -> Arc<Redlock> {
+        Arc::new(self)
+    }
+
+    // Locks the given resource, and releases it once the returned guard
+    // is dropped.
+    pub fn lock_guard(&self, resource_name: &str, ttl: Duration) -> RedlockResult<LockGuard<'_>> {
+        Ok(self.lock(resource_name, ttl)?.into_guard())
+    }
+
+    // Locks the given resource and runs the critical section with the lock
+    // held. The lock is released afterwards, even if the closure panics.
+    // An error is returned only if the lock can not be acquired, the
+    // result of the closure is passed through untouched.
+    pub fn with_lock<F, T>(&self, resource_name: &str, ttl: Duration, f: F) -> RedlockResult<T>
+        where F: FnOnce(&Lock) -> T
+    {
+        let guard = self.lock_guard(resource_name, ttl)?;
+        Ok(f(&guard))
+    }
+
+    fn extend(&self, resource_name: &str, value: &str, ttl: Duration) -> RedlockResult<Lock<'_>> {
+        let info = RequestInfo::Extend {
+            resource_value: value,
+            data: None,
+        };
+        self.request(info, resource_name, ttl, None)
+    }
+
+    fn extend_with_data(&self,
+                        resource_name: &str,
+                        value: &str,
+                        ttl: Duration,
+                        data: &[u8])
+                        -> RedlockResult<Lock<'_>> {
+        let info = RequestInfo::Extend {
+            resource_value: value,
+            data: Some(data),
+        };
+        self.request(info, resource_name, ttl, None)
+    }
+
+    // The companion key of the resource, which Lock::extend_with_data sets
+    // to the data of the lock, e.g. for the followers to read it. It's in
+    // the same hash slot as the resource.
+    pub fn data_key(&self, resource_name: &str) -> String {
+        data_key(&self.key(resource_name))
+    }
+
+    // Locks the given resource like lock, but gives up with the Cancelled
+    // error as soon as the cancel flag is set, which is checked before each
+    // attempt and before each sleep.
+    pub fn lock_cancellable(&self,
+                            resource_name: &str,
+                            ttl: Duration,
+                            cancel: &AtomicBool)
+                            -> RedlockResult<Lock<'_>> {
+        let info = RequestInfo::Lock {
+            resource_value: None,
+            fence: None,
+        };
+        self.request(info, resource_name, ttl, Some(cancel))
+    }
+
+    pub(crate) fn request(&self,
+                          info: RequestInfo,
+                          resource_name: &str,
+                          ttl: Duration,
+                          cancel: Option<&AtomicBool>)
+                          -> RedlockResult<Lock<'_>> {
+        self.request_with(info, resource_name, &|| ttl, cancel)
+    }
+
+    // Like request, but the TTL is computed by the given function right
+    // before each attempt.
+    fn request_with(&self,
+                    info: RequestInfo,
+                    resource_name: &str,
+                    ttl: &dyn Fn() -> Duration,
+                    cancel: Option<&AtomicBool>)
+                    -> RedlockResult<Lock<'_>> {
+        let mut attempts = 0;
+        let start = Instant::now();
+        let cancelled = || cancel.is_some_and(|cancel| cancel.load(Ordering::SeqCst));
+
+        #[cfg(feature = "tracing")]
+        let span = match info {
+            RequestInfo::Lock { .. } |
+            RequestInfo::LockOrExisting { .. } |
+            RequestInfo::LockExclusive { .. } => {
+                tracing::info_span!("redlock.lock",
+                                    resource = resource_name,
+                                    ttl_ms = tracing::field::Empty,
+                                    quorum = self.quorum,
+                                    attempts = tracing::field::Empty,
+                                    outcome = tracing::field::Empty)
+            }
+            RequestInfo::Extend { .. } => {
+                tracing::info_span!("redlock.extend",
+                                    resource = resource_name,
+                                    ttl_ms = tracing::field::Empty,
+                                    quorum = self.quorum,
+                                    attempts = tracing::field::Empty,
+                                    outcome = tracing::field::Empty)
+            }
+            RequestInfo::Transfer { .. } => {
+                tracing::info_span!("redlock.transfer",
+                                    resource = resource_name,
+                                    ttl_ms = tracing::field::Empty,
+                                    quorum = self.quorum,
+                                    attempts = tracing::field::Empty,
+                                    outcome = tracing::field::Empty)
+            }
+        };
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+
+        // The per-server results of the last attempt
+        let mut node_results = Vec::new();
+        let mut timings = Vec::new();
+        // The indexes of the servers which failed the previous attempt
+        let mut previously_failed = None;
+
+        let mut operations = 0;
+
+        while attempts < self.retry_count && !cancelled() {
+            // Every attempt requests all the servers
+            operations += self.nodes.len() as u32;
+            if self.max_operations.is_some_and(|max_operations| operations > max_operations) {
+                #[cfg(feature = "tracing")]
+                span.record("attempts", attempts).record("outcome", "failed");
+                self.observer.on_failed(resource_name, attempts);
+                return Err(RedlockError::BudgetExhausted);
+            }
+
+            attempts += 1;
+            self.observer.on_attempt(resource_name, attempts);
+
+            let ttl = self.jitter_ttl(ttl());
+            #[cfg(feature = "tracing")]
+            span.record("ttl_ms", util::num_milliseconds(&ttl));
+
+            let attempt_start = start.elapsed();
+            match self.attempt(&info, resource_name, ttl) {
+                Attempt::Acquired(mut lock) => {
+                    #[cfg(feature = "tracing")]
+                    span.record("attempts", attempts).record("outcome", "acquired");
+                    self.observer.on_acquired(resource_name, attempts, start.elapsed());
+                    lock.attempts = attempts;
+                    return Ok(lock);
+                }
+                Attempt::Failed(results) => {
+                    // Give up if the same servers have failed twice in a
+                    // row, the quorum can not be reached without them
+                    let failed = failed_nodes(&results);
+                    let decision = evaluate_quorum(&results, self.quorum);
+                    if decision == Decision::Fail && previously_failed.as_ref() == Some(&failed) {
+                        #[cfg(feature = "tracing")]
+                        span.record("attempts", attempts).record("outcome", "failed");
+                        self.observer.on_failed(resource_name, attempts);
+                        return Err(self.unreachable(results));
+                    }
+                    previously_failed = Some(failed);
+
+                    let mut timing = AttemptTiming {
+                        start: attempt_start,
+                        duration: start.elapsed() - attempt_start,
+                        votes: results
+                            .iter()
+                            .filter(|outcome| matches!(**outcome, NodeOutcome::Acquired))
+                            .count(),
+                        sleep: Duration::from_millis(0),
+                    };
+                    node_results = results;
+                    // No need to wait after the last attempt, and a resource
+                    // held by another client is retried right away
+                    if attempts < self.retry_count && decision != Decision::Retry && !cancelled() {
+                        timing.sleep = self.get_retry_timeout(attempts);
+                        thread::sleep(timing.sleep)
+                    }
+                    timings.push(timing);
+                }
+                Attempt::Aborted(err) => {
+                    #[cfg(feature = "tracing")]
+                    span.record("attempts", attempts).record("outcome", "failed");
+                    self.observer.on_failed(resource_name, attempts);
+                    return Err(err);
+                }
+            }
+        }
+        #[cfg(feature = "tracing")]
+        span.record("attempts", attempts).record("outcome", "failed");
+        self.observer.on_failed(resource_name, attempts);
+
+        if cancelled() {
+            return Err(RedlockError::Cancelled);
+        }
+
+        let resource_name = String::from(resource_name);
+        match info {
+            RequestInfo::Lock { .. } |
+            RequestInfo::LockOrExisting { .. } |
+            RequestInfo::LockExclusive { .. } => {
+                return Err(self.lock_failed(resource_name, attempts, node_results, timings));
+            }
+            RequestInfo::Extend { .. } |
+            RequestInfo::Transfer { .. } => {}
+        }
+
+        // Every server failed, return the first error as the cause
+        if !node_results.is_empty() &&
+           node_results.iter().all(|outcome| matches!(*outcome, NodeOutcome::Error(..))) {
+            if let NodeOutcome::Error(_, err) = node_results.swap_remove(0) {
+                return Err(RedlockError::AllNodesFailed(Box::new(err)));
+            }
+        }
+
+        // Exceed the retry count, return the error
+        let votes = node_results
+            .iter()
+            .filter(|outcome| matches!(**outcome, NodeOutcome::Acquired))
+            .count();
+        match info {
+            RequestInfo::Transfer { .. } => {
+                Err(RedlockError::UnableToTransfer(resource_name, votes, self.quorum, attempts))
+            }
+            _ => Err(RedlockError::UnableToExtend(resource_name, votes, self.quorum, attempts)),
+        }
+    }
+
+    // The error of a lock request giving up after the given attempts, from
+    // the results of the last one: AllNodesFailed with the first error as
+    // the cause if every server has failed, QuorumNotReached otherwise.
+    // Every way to lock a resource fails the same way.
+    pub(crate) fn lock_failed(&self,
+                              resource_name: String,
+                              attempts: u32,
+                              mut node_results: Vec<NodeOutcome>,
+                              timings: Vec<AttemptTiming>)
+                              -> RedlockError {
+        if !node_results.is_empty() &&
+           node_results.iter().all(|outcome| matches!(*outcome, NodeOutcome::Error(..))) {
+            if let NodeOutcome::Error(_, err) = node_results.swap_remove(0) {
+                return RedlockError::AllNodesFailed(Box::new(err));
+            }
+        }
+
+        let votes = node_results
+            .iter()
+            .filter(|outcome| matches!(**outcome, NodeOutcome::Acquired))
+            .count();
+        RedlockError::QuorumNotReached(resource_name,
+                                       votes,
+                                       self.quorum,
+                                       attempts,
+                                       node_results,
+                                       timings)
+    }
+
+    // Make a single attempt to request the servers.
+    fn attempt(&self, info: &RequestInfo, resource_name: &str, ttl: Duration) -> Attempt<'_> {
+        let drift = self.get_drift(&ttl);
+
+        let value: String = match *info {
+            RequestInfo::Lock { resource_value: None, .. } |
+            RequestInfo::LockExclusive { .. } => util::get_random_token(self.token_len),
+            RequestInfo::Lock { resource_value: Some(resource_value), .. } |
+            RequestInfo::LockOrExisting { resource_value } |
+            RequestInfo::Extend { resource_value, .. } => String::from(resource_value),
+            RequestInfo::Transfer { new_value, .. } => String::from(new_value),
+        };
+
+        // Start time of this attempt, the validity of the lock is counted
+        // from the moment the requests are sent, for an extension as well.
+        // The expiration exposed to the user is a wall clock time, but the
+        // validity is checked against the monotonic clock.
+        let start = self.clock.now();
+        let start_instant = self.clock.instant();
+        let (expiration, valid_until) = match expirations(start, start_instant, ttl, drift) {
+            Ok(expirations) => expirations,
+            Err(err) => return Attempt::Aborted(err),
+        };
+
+        let key = self.key(resource_name);
+        let request = |backend: &dyn LockBackend| match *info {
+            RequestInfo::Lock { fence: None, .. } => {
+                Ok(backend.set_nx_px(&key, &value, &ttl)? == SetOutcome::Acquired)
+            }
+            RequestInfo::Lock { fence: Some(fence), .. } => {
+                lock_fenced(backend, &key, &value, &ttl, fence)
+            }
+            RequestInfo::LockOrExisting { .. } => backend.set_nx_px_or_existing(&key, &value, &ttl),
+            RequestInfo::LockExclusive { readers_key } => {
+                backend.set_nx_px_exclusive(&key, readers_key, &value, &ttl)
+            }
+            RequestInfo::Extend { data: None, .. } => {
+                backend.compare_and_pexpire(&key, &value, &ttl)
+            }
+            RequestInfo::Extend { data: Some(data), .. } => {
+                backend.compare_and_pexpire_with_data(&key, &data_key(&key), &value, data, &ttl)
+            }
+            RequestInfo::Transfer { resource_value, .. } => {
+                backend.compare_and_swap(&key, resource_value, &value, &ttl)
+            }
+        };
+
+        let mut node_results = self.node_outcomes(self.request_all(request));
+        let mut votes = node_results
+            .iter()
+            .filter(|outcome| matches!(**outcome, NodeOutcome::Acquired))
+            .count();
+        let mut decision = evaluate_quorum(&node_results, self.quorum);
+
+        // Keep the servers which granted the attempt, and request the others
+        // again while the lock would still be valid
+        if let PartialRetryPolicy::RetryOthers { retries, delay } = self.partial_retry {
+            let mut retried = 0;
+            while retried < retries && votes > 0 && decision != Decision::Acquire &&
+                  self.clock
+                      .instant()
+                      .checked_add(delay + self.min_validity)
+                      .is_some_and(|retry_valid_until| retry_valid_until < valid_until) {
+                retried += 1;
+                thread::sleep(delay);
+
+                let others: Vec<usize> = (0..node_results.len())
+                    .filter(|&index| !matches!(node_results[index], NodeOutcome::Acquired))
+                    .collect();
+                let results = self.request_nodes(&others, request);
+                for (index, result) in others.into_iter().zip(results) {
+                    node_results[index] = self.node_outcome(index, result);
+                }
+                votes = node_results
+                    .iter()
+                    .filter(|outcome| matches!(**outcome, NodeOutcome::Acquired))
+                    .count();
+                decision = evaluate_quorum(&node_results, self.quorum);
+            }
+        }
+        trace_event!(votes, ?decision, "attempt decided");
+
+        // suceess: aquire the lock
+        let now = self.clock.instant();
+        let min_valid_until = now.checked_add(self.min_validity);
+        if decision == Decision::Acquire &&
+           min_valid_until.is_some_and(|min_valid_until| valid_until > min_valid_until) {
+            self.track(resource_name, &value);
+            // The lock is only built once acquired, to not allocate on
+            // every server result
+            return Attempt::Acquired(Lock {
+                                         redlock: self,
+                                         resource_name: String::from(resource_name),
+                                         value,
+                                         expiration,
+                                         valid_until,
+                                         acquisition_time: now
+                                             .saturating_duration_since(start_instant),
+                                         attempts: 1,
+                                         advisory: Arc::new(AtomicBool::new(false)),
+                                     });
+        }
+
+        // fail: releases the locks acquired with a fresh value, the others
+        // are left to expire like on a failed extension, the lock they
+        // extend is still valid
+        if votes > 0 && info.writes_fresh_value() {
+            if let Err(err) = self.release(resource_name, &value, &mut node_results) {
+                return Attempt::Aborted(err);
+            }
+        }
+        // A server may have failed over, the next attempt is made against
+        // the new one. The current ones are kept if they can't be resolved.
+        if self.resolver.is_some() && node_results.iter().any(is_failover) {
+            let _ = self.resolve_addrs();
+        }
+        Attempt::Failed(node_results)
+    }
+
+    // The error of a request giving up since too many servers have failed,
+    // AllNodesFailed with the first error if every server has failed.
+    fn unreachable(&self, mut node_results: Vec<NodeOutcome>) -> RedlockError {
+        let failed = failed_nodes(&node_results).len();
+        if failed == node_results.len() {
+            if let NodeOutcome::Error(_, err) = node_results.swap_remove(0) {
+                return RedlockError::AllNodesFailed(Box::new(err));
+            }
+        }
+        RedlockError::QuorumUnreachable(failed, self.quorum, node_results)
+    }
+
+    // Turn the results of the servers into outcomes, reporting the errors
+    // to the observer.
+    pub(crate) fn node_outcomes(&self, request_results: Vec<RedlockResult<bool>>) -> Vec<NodeOutcome> {
+        request_results
+            .into_iter()
+            .enumerate()
+            .map(|(index, request_result)| self.node_outcome(index, request_result))
+            .collect()
+    }
+
+    fn node_outcome(&self, index: usize, request_result: RedlockResult<bool>) -> NodeOutcome {
+        match request_result {
+            Ok(true) => NodeOutcome::Acquired,
+            Ok(false) => NodeOutcome::Held,
+            Err(err) => {
+                self.observer.on_node_error(index, &self.labels[index], &err);
+                NodeOutcome::Error(self.labels[index].clone(), err)
+            }
+        }
+    }
+
+    // Locks the given resource, retrying until the lock is acquired or the
+    // deadline has passed, regardless of the retry count.
+    pub fn lock_until(&self,
+                      resource_name: &str,
+                      ttl: Duration,
+                      deadline: SystemTime)
+                      -> RedlockResult<Lock<'_>> {
+        let info = RequestInfo::Lock {
+            resource_value: None,
+            fence: None,
+        };
+
+        let mut attempts = 0;
+        let start = Instant::now();
+
+        loop {
+            attempts += 1;
+            self.observer.on_attempt(resource_name, attempts);
+
+            match self.attempt(&info, resource_name, ttl) {
+                Attempt::Acquired(mut lock) => {
+                    self.observer.on_acquired(resource_name, attempts, start.elapsed());
+                    lock.attempts = attempts;
+                    return Ok(lock);
+                }
+                Attempt::Aborted(err) => {
+                    self.observer.on_failed(resource_name, attempts);
+                    return Err(err);
+                }
+                Attempt::Failed(_) => {}
+            }
+
+            // Never sleep beyond the deadline
+            match deadline.duration_since(self.clock.now()) {
+                Ok(remaining) if remaining > Duration::from_millis(0) => {
+                    thread::sleep(cmp::min(self.get_retry_timeout(attempts), remaining))
+                }
+                _ => {
+                    self.observer.on_failed(resource_name, attempts);
+                    return Err(RedlockError::TimeoutError);
+                }
+            }
+        }
+    }
+
+    // Make a single attempt to lock the given resource, without retrying.
+    // Returns None if the lock can not be acquired on a quorum of servers.
+    pub fn try_lock(&self, resource_name: &str, ttl: Duration) -> RedlockResult<Option<Lock<'_>>> {
+        Ok(self.try_lock_with_results(resource_name, ttl)?.ok())
+    }
+
+    // Like try_lock, but gives the result of each server if the lock can
+    // not be acquired.
+    pub(crate) fn try_lock_with_results(&self,
+                                        resource_name: &str,
+                                        ttl: Duration)
+                                        -> RedlockResult<Result<Lock<'_>, Vec<NodeOutcome>>> {
+        let info = RequestInfo::Lock {
+            resource_value: None,
+            fence: None,
+        };
+        let start = Instant::now();
+        self.observer.on_attempt(resource_name, 1);
+
+        match self.attempt(&info, resource_name, ttl) {
+            Attempt::Acquired(lock) => {
+                self.observer.on_acquired(resource_name, 1, start.elapsed());
+                Ok(Ok(lock))
+            }
+            Attempt::Failed(node_results) => {
+                self.observer.on_failed(resource_name, 1);
+                Ok(Err(node_results))
+            }
+            Attempt::Aborted(err) => {
+                self.observer.on_failed(resource_name, 1);
+                Err(err)
+            }
+        }
+    }
+
+    // Make a single attempt to lock the given resource, without sleeping.
+    // If the lock can not be acquired, the delay to wait before the next
+    // attempt is returned, so that the caller can drive the retries itself.
+    // An error is returned if every server has failed.
+    pub fn poll_lock(&self, resource_name: &str, ttl: Duration) -> RedlockResult<PollResult<'_>> {
+        let info = RequestInfo::Lock {
+            resource_value: None,
+            fence: None,
+        };
+        let start = Instant::now();
+        self.observer.on_attempt(resource_name, 1);
+
+        match self.attempt(&info, resource_name, ttl) {
+            Attempt::Acquired(lock) => {
+                self.observer.on_acquired(resource_name, 1, start.elapsed());
+                Ok(PollResult::Acquired(lock))
+            }
+            Attempt::Failed(mut node_results) => {
+                self.observer.on_failed(resource_name, 1);
+                if node_results.iter().all(|outcome| matches!(*outcome, NodeOutcome::Error(..))) {
+                    if let NodeOutcome::Error(_, err) = node_results.swap_remove(0) {
+                        return Err(RedlockError::AllNodesFailed(Box::new(err)));
+                    }
+                }
+                Ok(PollResult::Retry { after: self.get_retry_timeout(1) })
+            }
+            Attempt::Aborted(err) => {
+                self.observer.on_failed(resource_name, 1);
+                Err(err)
+            }
+        }
+    }
+
+    // Release the lock held on the given resource with the given value,
+    // e.g. a value recorded before a restart, without the Lock itself.
+    pub fn unlock_resource(&self, resource_name: &str, value: &str) -> RedlockResult<()> {
+        self.unlock(resource_name, value)
+    }
+
+    // Release the lock of the given record, like unlock_resource. The
+    // release fails with UnableToUnlock if the lock has already expired
+    // from the servers, see LockRecord::expiration.
+    pub fn unlock_record(&self, record: &LockRecord) -> RedlockResult<()> {
+        self.unlock(&record.resource_name, &record.value)
+    }
+
+    // Release the locks of a failed attempt from the servers which granted
+    // it, given the results of the attempt. If it fails, the orphaned lock
+    // is handled according to the orphan policy, an error is returned only
+    // if the request must be aborted.
+    fn release(&self,
+               resource_name: &str,
+               value: &str,
+               node_results: &mut [NodeOutcome])
+               -> RedlockResult<()> {
+        let granted: Vec<usize> = (0..node_results.len())
+            .filter(|&index| matches!(node_results[index], NodeOutcome::Acquired))
+            .collect();
+        let key = self.key(resource_name);
+        let request_results =
+            self.request_nodes(&granted, |backend| backend.compare_and_del(&key, value));
+        let request_results = granted.into_iter().zip(request_results).collect();
+        let err = match self.rolled_back(resource_name, value, node_results, request_results) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+
+        match self.orphan_policy {
+            OrphanPolicy::Ignore => Ok(()),
+            OrphanPolicy::Abort => Err(RedlockError::OrphanedLock(Box::new(err))),
+            OrphanPolicy::Record(ref record) => {
+                record(resource_name, value);
+                Ok(())
+            }
+        }
+    }
+
+    // Check the results of a single round of compare_and_del rolling back a
+    // lock, along with the indexes of the servers they come from, which may
+    // have granted it. It only fails, and reports the lock as orphaned, if
+    // one of them may still hold it. The observer is given the results of
+    // the release for those servers, and the given node results for the
+    // others, which are left as is.
+    pub(crate) fn rolled_back(&self,
+                              resource_name: &str,
+                              value: &str,
+                              node_results: &mut [NodeOutcome],
+                              request_results: Vec<(usize, RedlockResult<UnlockOutcome>)>)
+                              -> RedlockResult<()> {
+        let expected = request_results.len();
+        let released = request_results
+            .iter()
+            .filter(|(_, result)| match *result {
+                        Ok(UnlockOutcome::Released) | Ok(UnlockOutcome::NotPresent) => true,
+                        Ok(UnlockOutcome::HeldByOther) | Err(_) => false,
+                    })
+            .count();
+        let outcomes: Vec<(usize, NodeOutcome)> = request_results
+            .into_iter()
+            .map(|(index, result)| {
+                     let released = result.map(|outcome| outcome != UnlockOutcome::HeldByOther);
+                     (index, self.node_outcome(index, released))
+                 })
+            .collect();
+        if released == expected {
+            return Ok(());
+        }
+
+        let previous: Vec<(usize, NodeOutcome)> = outcomes
+            .into_iter()
+            .map(|(index, outcome)| (index, mem::replace(&mut node_results[index], outcome)))
+            .collect();
+        self.report_orphaned(resource_name, value, node_results);
+        for (index, outcome) in previous {
+            node_results[index] = outcome;
+        }
+        Err(RedlockError::UnableToUnlock(String::from(resource_name), released, expected, 1))
+    }
+
+    // Check the results of a single round of compare_and_del from every
+    // server releasing a lock, when the servers which granted it are not
+    // known, e.g. for an attempt dropped in flight. Any server not held by
+    // another value may have granted it, see rolled_back.
+    pub(crate) fn released_everywhere(&self,
+                                      resource_name: &str,
+                                      value: &str,
+                                      request_results: Vec<RedlockResult<UnlockOutcome>>)
+                                      -> RedlockResult<()> {
+        let mut node_results: Vec<NodeOutcome> =
+            request_results.iter().map(|_| NodeOutcome::Held).collect();
+        let request_results = request_results
+            .into_iter()
+            .enumerate()
+            .filter(|(_, result)| !matches!(*result, Ok(UnlockOutcome::HeldByOther)))
+            .collect();
+        self.rolled_back(resource_name, value, &mut node_results, request_results)
+    }
+
+    // Release a lock given up on in a single round, reporting it as
+    // orphaned if a server may still hold it. The error is returned for the
+    // caller to handle or ignore.
+    pub(crate) fn release_best_effort(&self, resource_name: &str, value: &str) -> RedlockResult<()> {
+        let key = self.key(resource_name);
+        let request_results = self.request_all(|backend| backend.compare_and_del(&key, value));
+        let result = self.released_everywhere(resource_name, value, request_results);
+        if result.is_ok() {
+            self.untrack(resource_name, value);
+        }
+        result
+    }
+
+    pub(crate) fn report_orphaned(&self,
+                                  resource_name: &str,
+                                  value: &str,
+                                  node_results: &[NodeOutcome]) {
+        self.orphaned.fetch_add(1, Ordering::SeqCst);
+        self.observer.on_orphaned(resource_name, value, node_results);
+    }
+
+    // The number of locks given up on which could not be released, and are
+    // left on some servers until they expire, e.g. to alert on leaking
+    // locks. See Observer::on_orphaned to get the details of each one.
+    pub fn orphaned_count(&self) -> u64 {
+        self.orphaned.load(Ordering::SeqCst)
+    }
+
+    fn unlock(&self, resource_name: &str, value: &str) -> RedlockResult<()> {
+        self.unlock_outcomes(resource_name, value).0
+    }
+
+    fn unlock_report(&self, resource_name: &str, value: &str) -> UnlockReport {
+        let key = self.key(resource_name);
+        UnlockReport::new(self.request_all(|backend| backend.compare_and_del(&key, value)))
+    }
+
+    // Unlock the resource, along with the results of the servers of the
+    // last attempt.
+    fn unlock_outcomes(&self,
+                       resource_name: &str,
+                       value: &str)
+                       -> (RedlockResult<()>, Vec<NodeOutcome>) {
+        let mut attempts = 0;
+
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!("redlock.unlock",
+                                       resource = resource_name,
+                                       quorum = self.quorum,
+                                       attempts = tracing::field::Empty,
+                                       outcome = tracing::field::Empty);
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+
+        let key = self.key(resource_name);
+        let mut votes = 0;
+        let mut node_results = Vec::new();
+        while attempts < self.unlock_retry_count {
+            attempts += 1;
+
+            let request_results = self.request_all(|backend| unlock(backend, &key, value));
+            node_results = self.node_outcomes(request_results);
+            votes = node_results
+                .iter()
+                .filter(|outcome| matches!(**outcome, NodeOutcome::Acquired))
+                .count();
+            match evaluate_quorum(&node_results, self.quorum) {
+                Decision::Acquire => {
+                    #[cfg(feature = "tracing")]
+                    span.record("attempts", attempts).record("outcome", "released");
+                    self.untrack(resource_name, value);
+                    return (Ok(()), node_results);
+                }
+                // Too many servers failed, will retry after the timeout
+                Decision::Fail => {
+                    if attempts < self.unlock_retry_count {
+                        thread::sleep(self.get_unlock_retry_timeout(attempts));
+                    }
+                }
+                Decision::Retry => {}
+            }
+        }
+
+        // Exceed the retry count, return the error
+        #[cfg(feature = "tracing")]
+        span.record("attempts", attempts).record("outcome", "failed");
+        (Err(RedlockError::UnableToUnlock(String::from(resource_name), votes, self.quorum, attempts)),
+         node_results)
+    }
+
+    // Check whether the given resource is locked on a quorum of servers.
+    // The answer is only advisory, the resource can be locked or released
+    // right after the check.
+    pub fn is_locked(&self, resource_name: &str) -> RedlockResult<bool> {
+        let values = self.get_values(resource_name)?;
+        Ok(values.iter().filter(|value| value.is_some()).count() >= self.quorum)
+    }
+
+    // Get the value of the lock held on the given resource, if a quorum of
+    // servers agree on it. Like is_locked, the answer is only advisory.
+    pub fn owner(&self, resource_name: &str) -> RedlockResult<Option<String>> {
+        let values = self.get_values(resource_name)?;
+        let owner = values.iter().flatten().find(|value| {
+            values
+                .iter()
+                .filter(|other| other.as_ref() == Some(*value))
+                .count() >= self.quorum
+        });
+        Ok(owner.cloned())
+    }
+
+    fn pttl(&self, resource_name: &str, value: &str) -> RedlockResult<Option<Duration>> {
+        let key = self.key(resource_name);
+        let mut pttls = Vec::with_capacity(self.nodes.len());
+        let mut answers = 0;
+        let mut first_error = None;
+        for request_result in self.request_all(|backend| backend.compare_and_pttl(&key, value)) {
+            match request_result {
+                Ok(pttl) => {
+                    answers += 1;
+                    pttls.extend(pttl);
+                }
+                Err(err) => {
+                    first_error.get_or_insert(err);
+                }
+            }
+        }
+
+        match first_error {
+            Some(err) if answers < self.quorum => Err(err),
+            _ if pttls.len() < self.quorum => Ok(None),
+            _ => {
+                pttls.sort_by(|a, b| b.cmp(a));
+                Ok(Some(pttls[self.quorum - 1]))
+            }
+        }
+    }
+
+    // Whether the given resource is locked with the given value on a quorum
+    // of servers.
+    pub(crate) fn is_held(&self, resource_name: &str, value: &str) -> RedlockResult<bool> {
+        let values = self.get_values(resource_name)?;
+        Ok(values.iter().filter(|other| other.as_deref() == Some(value)).count() >= self.quorum)
+    }
+
+    // Get the value of the resource on each server which has answered, the
+    // first error is returned if less than a quorum of them have answered.
+    fn get_values(&self, resource_name: &str) -> RedlockResult<Vec<Option<String>>> {
+        let mut values = Vec::with_capacity(self.nodes.len());
+        let mut first_error = None;
+        let key = self.key(resource_name);
+        for request_result in self.request_all(|backend| backend.get(&key)) {
+            match request_result {
+                Ok(value) => values.push(value),
+                Err(err) => {
+                    first_error.get_or_insert(err);
+                }
+            }
+        }
+
+        match first_error {
+            Some(err) if values.len() < self.quorum => Err(err),
+            _ => Ok(values),
+        }
+    }
+
+    // PING every redis server, and return the result of each one along
+    // with its index. No lock is touched. A server skipped by its circuit
+    // breaker fails with CircuitOpen, telling when it is probed again.
+    pub fn health_check(&self) -> Vec<(usize, RedlockResult<()>)> {
+        self.request_all(|backend| backend.ping()).into_iter().enumerate().collect()
+    }
+
+    // Whether enough redis servers answer to ever reach the quorum.
+    pub fn has_quorum_available(&self) -> bool {
+        let available = self.health_check()
+            .iter()
+            .filter(|&(_, result)| result.is_ok())
+            .count();
+        available >= self.quorum
+    }
+
+    // The resource names and values of the locks handed out and not
+    // unlocked yet, sorted by resource name. It's empty unless the locks
+    // are tracked, see Config::track_locks.
+    pub fn held_locks(&self) -> Vec<(String, String)> {
+        let mut held_locks: Vec<(String, String)> = match self.held_locks {
+            Some(ref held_locks) => {
+                held_locks
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(resource_name, value)| (resource_name.clone(), value.clone()))
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        held_locks.sort();
+        held_locks
+    }
+
+    // Unlock every tracked lock, and return the result of each one in the
+    // order of held_locks.
+    pub fn unlock_all(&self) -> Vec<RedlockResult<()>> {
+        self.held_locks()
+            .iter()
+            .map(|(resource_name, value)| self.unlock(resource_name, value))
+            .collect()
+    }
+
+    pub(crate) fn track(&self, resource_name: &str, value: &str) {
+        if let Some(ref held_locks) = self.held_locks {
+            held_locks
+                .lock()
+                .unwrap()
+                .insert(String::from(resource_name), String::from(value));
+        }
+    }
+
+    pub(crate) fn untrack(&self, resource_name: &str, value: &str) {
+        if let Some(ref held_locks) = self.held_locks {
+            let mut held_locks = held_locks.lock().unwrap();
+            if held_locks.get(resource_name).is_some_and(|held| held == value) {
+                held_locks.remove(resource_name);
+            }
+        }
+    }
+
+    // DANGER: this is an administration tool, to recover from a crash which
+    // left locks behind, and must not run while the service may hold locks.
+    //
+    // Scan every redis server for the keys starting with the key prefix,
+    // and return them, sorted and without duplicates. Unless dry_run is
+    // set, the keys are then deleted from every server whatever their
+    // value: the locks still held are released under the feet of their
+    // owners, and any other key sharing the prefix is lost too, e.g. the
+    // fence counters, the read/write locks readers and the fair queues. Run
+    // it with dry_run first, and check the keys it would delete.
+    //
+    // The key prefix must not be empty, otherwise every key of the servers
+    // would match. The servers are scanned with SCAN, which does not block
+    // them like KEYS but may miss the keys created during the scan. The
+    // custom backends are skipped, and the redis cluster nodes fail. The
+    // first error stops the cleanup, the keys may then be deleted from some
+    // servers only.
+    pub fn cleanup_prefix(&self, dry_run: bool) -> RedlockResult<Vec<String>> {
+        if self.key_prefix.is_empty() {
+            return Err(RedlockError::EmptyKeyPrefix);
+        }
+
+        let pattern = format!("{}*", escape_glob(&self.key_prefix));
+        let mut matched = Vec::new();
+        for node in &self.nodes {
+            let pool = match *node {
+                Node::Redis(ref pool) => pool,
+                Node::Backend(_) => continue,
+            };
+
+            let keys = pool.scan(&pattern)?;
+            if !dry_run && !keys.is_empty() {
+                redis::cmd("DEL").arg(&keys).query::<()>(&mut *pool.get()?)?;
+            }
+            matched.extend(keys);
+        }
+
+        matched.sort();
+        matched.dedup();
+        Ok(matched)
+    }
+
+    // Open a connection to every redis server ahead of the first request,
+    // and keep it in the pool so that the first lock does not pay for it.
+    // The servers which fail are reported to the observer, an error is
+    // returned only if less than a quorum of servers are ready, or if a
+    // server has refused the credentials: AuthenticationFailed is returned
+    // then, since it's a misconfiguration rather than an outage, which the
+    // lock requests would only report as a failed server. Redlock::new does
+    // not connect to the servers, call warmup to check the credentials.
+    pub fn warmup(&self) -> RedlockResult<()> {
+        let mut ready = 0;
+        let mut first_error = None;
+        let mut auth_error = None;
+        for (index, result) in self.health_check() {
+            match result {
+                Ok(()) => ready += 1,
+                Err(err) => {
+                    self.observer.on_node_error(index, &self.labels[index], &err);
+                    match err {
+                        RedlockError::RedisError(err) if is_auth_error(&err) => {
+                            auth_error.get_or_insert((index, err));
+                        }
+                        err => {
+                            first_error.get_or_insert(err);
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some((index, err)) = auth_error {
+            return Err(RedlockError::AuthenticationFailed(self.labels[index].clone(), err));
+        }
+        match first_error {
+            Some(err) if ready < self.quorum => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    // Send the request to all the servers concurrently, the results are
+    // returned in the same order as the servers. The servers are contacted
+    // in a random order, so that the same one is not always the first.
+    pub(crate) fn request_all<F, T>(&self, request: F) -> Vec<RedlockResult<T>>
+        where F: Fn(&dyn LockBackend) -> RedlockResult<T> + Sync,
+              T: Send
+    {
+        let indexes: Vec<usize> = (0..self.nodes.len()).collect();
+        self.request_nodes(&indexes, request)
+    }
+
+    // Send the request to the servers at the given indexes like
+    // request_all, the results are returned in the order of the indexes.
+    fn request_nodes<F, T>(&self, indexes: &[usize], request: F) -> Vec<RedlockResult<T>>
+        where F: Fn(&dyn LockBackend) -> RedlockResult<T> + Sync,
+              T: Send
+    {
+        // Skip the servers whose circuit breaker is open
+        let request = |index: usize| match self.breakers {
+            Some(ref breakers) => {
+                breakers[index].check().map_err(RedlockError::CircuitOpen)?;
+                let result = request(self.nodes[index].backend());
+                breakers[index].record(result.is_ok());
+                result
+            }
+            None => request(self.nodes[index].backend()),
+        };
+
+        // With a single server there is nothing to run concurrently, request
+        // it from the calling thread
+        if let [index] = *indexes {
+            self.observer.on_node_request(index, &self.labels[index]);
+            return vec![request(index)];
+        }
+
+        let mut order: Vec<usize> = (0..indexes.len()).collect();
+        match self.rng {
+            Some(ref rng) => rng.lock().unwrap().shuffle(&mut order),
+            None => thread_rng().shuffle(&mut order),
+        }
+
+        let request = &request;
+        let mut results = thread::scope(|scope| {
+            let handles: Vec<_> = order
+                .iter()
+                .map(|&position| {
+                    let index = indexes[position];
+                    self.observer.on_node_request(index, &self.labels[index]);
+                    (position, scope.spawn(move || request(index)))
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|(position, handle)| {
+                    (position, handle.join().unwrap_or_else(|err| panic::resume_unwind(err)))
+                })
+                .collect::<Vec<_>>()
+        });
+
+        results.sort_by_key(|&(position, _)| position);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    // Get the clock drift to subtract from the validity of a lock with the
+    // given TTL, that is drift_factor * ttl rounded to the millisecond, plus
+    // clock_drift_ms, unless the drift is absolute.
+    pub(crate) fn get_drift(&self, ttl: &Duration) -> Duration {
+        match self.drift {
+            Drift::Factor => {
+                Duration::from_millis((self.drift_factor as f64 *
+                                       util::num_milliseconds(ttl) as f64)
+                                              .round() as u64 +
+                                      self.clock_drift_ms)
+            }
+            Drift::Absolute(drift) => drift,
+        }
+    }
+
+    // Get the time to wait before retrying to unlock, after the given
+    // attempt which starts from 1.
+    pub(crate) fn get_unlock_retry_timeout(&self, attempt: u32) -> Duration {
+        match self.unlock_retry_delay {
+            Some(delay) => delay,
+            None => self.get_retry_timeout(attempt),
+        }
+    }
+
+    // Get the time to wait before retrying, after the given attempt which
+    // starts from 1.
+    pub(crate) fn get_retry_timeout(&self, attempt: u32) -> Duration {
+        let delay = match self.backoff {
+            BackoffStrategy::Constant => self.retry_delay,
+            BackoffStrategy::Exponential { base, cap } => {
+                self.retry_delay
+                    .checked_mul(base.saturating_pow(attempt.saturating_sub(1)))
+                    .map_or(cap, |delay| cmp::min(delay, cap))
+            }
+        };
+
+        let max_jitter = self.retry_jitter as i64;
+        let jitter = match self.rng {
+            Some(ref rng) => rng.lock().unwrap().gen_range(-max_jitter, max_jitter + 1),
+            None => thread_rng().gen_range(-max_jitter, max_jitter + 1),
+        };
+        if jitter >= 0 {
+            delay.add(Duration::from_millis(jitter as u64))
+        } else {
+            delay.saturating_sub(Duration::from_millis(-jitter as u64))
+        }
+    }
+
+    // Reduce the given TTL by a random part of the expiration jitter, by
+    // half of it at most.
+    pub(crate) fn jitter_ttl(&self, ttl: Duration) -> Duration {
+        let max_jitter = cmp::min(util::num_milliseconds(&self.expiration_jitter),
+                                  util::num_milliseconds(&ttl) / 2);
+        if max_jitter == 0 {
+            return ttl;
+        }
+
+        let jitter = match self.rng {
+            Some(ref rng) => rng.lock().unwrap().gen_range(0, max_jitter + 1),
+            None => thread_rng().gen_range(0, max_jitter + 1),
+        };
+        ttl - Duration::from_millis(jitter)
+    }
+}
+
+// The wall clock expiration and the monotonic validity of a lock with the
+// given TTL requested at the given times, or InvalidTtl if the TTL overflows
+// either clock or does not fit the milliseconds redis accepts.
+pub(crate) fn expirations(start: SystemTime,
+                          start_instant: Instant,
+                          ttl: Duration,
+                          drift: Duration)
+                          -> RedlockResult<(SystemTime, Instant)> {
+    if ttl.as_millis() > i64::MAX as u128 {
+        return Err(RedlockError::InvalidTtl(ttl));
+    }
+    let expiration = start.checked_add(ttl).and_then(|end| end.checked_sub(drift));
+    let valid_until = start_instant.checked_add(ttl).and_then(|end| end.checked_sub(drift));
+    match (expiration, valid_until) {
+        (Some(expiration), Some(valid_until)) => Ok((expiration, valid_until)),
+        _ => Err(RedlockError::InvalidTtl(ttl)),
+    }
+}
+
+fn lock_fenced(backend: &dyn LockBackend,
+               resource_name: &str,
+               value: &str,
+               ttl: &Duration,
+               fence: &AtomicU64)
+               -> RedlockResult<bool> {
+    match backend.set_nx_px_fenced(resource_name, &fence_key(resource_name), value, ttl)? {
+        Some(token) => {
+            fence.fetch_max(token, Ordering::SeqCst);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// The fence counter must be in the same hash slot as the resource, for the
+// script to work against a redis cluster. If the resource name already
+// has a hash tag, it is kept as it is. A name with a "}" but without any
+// hash tag can not be colocated with its counter.
+fn fence_key(resource_name: &str) -> String {
+    if has_hash_tag(resource_name) {
+        format!("{}:fence", resource_name)
+    } else {
+        format!("{{{}}}:fence", resource_name)
+    }
+}
+
+// The companion data key must be in the same hash slot as the resource,
+// like the fence counter.
+fn data_key(key: &str) -> String {
+    if has_hash_tag(key) {
+        format!("{}:data", key)
+    } else {
+        format!("{{{}}}:data", key)
+    }
+}
+
+// Whether the server has refused the credentials, or asks for some.
+fn is_auth_error(err: &redis::RedisError) -> bool {
+    err.kind() == redis::ErrorKind::AuthenticationFailed || err.code() == Some("NOAUTH")
+}
+
+// Whether a server could not be reached or refused to write, as a master
+// which has failed over to a replica does.
+fn is_failover(outcome: &NodeOutcome) -> bool {
+    match *outcome {
+        NodeOutcome::Error(_, RedlockError::RedisError(ref err)) => {
+            err.is_io_error() || err.is_connection_refusal() || err.is_timeout() ||
+            err.kind() == redis::ErrorKind::ReadOnly
+        }
+        _ => false,
+    }
+}
+
+// Escape the special characters of the glob patterns of redis, so that the
+// pattern matches the string as is.
+fn escape_glob(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
+    for c in string.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\' | '^' | '-') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Whether only a part of the key is hashed by redis cluster, which is the
+// case if it contains a non empty substring between "{" and "}".
+pub(crate) fn has_hash_tag(key: &str) -> bool {
+    match key.find('{') {
+        Some(start) => {
+            match key[start + 1..].find('}') {
+                Some(len) => len > 0,
+                None => false,
+            }
+        }
+        None => false,
+    }
+}
+
+// The indexes of the servers which have failed.
+fn failed_nodes(node_results: &[NodeOutcome]) -> Vec<usize> {
+    node_results
+        .iter()
+        .enumerate()
+        .filter(|&(_, outcome)| matches!(*outcome, NodeOutcome::Error(..)))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+fn unlock(backend: &dyn LockBackend, resource_name: &str, value: &str) -> RedlockResult<bool> {
+    Ok(backend.compare_and_del(resource_name, value)? == UnlockOutcome::Released)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::env;
+    use std::sync::Mutex;
+    use redis::{Commands, IntoConnectionInfo};
+    use crate::clock::TestClock;
+
+    lazy_static! {
+        static ref REDLOCK: Redlock = Redlock::new(Config::default()).unwrap();
+
+        static ref REDIS_CLI: redis::Client = redis::Client::open("redis://127.0.0.1").unwrap();
+    }
+
+    #[test]
+    fn test_config_default() {
+        let default_config = Config::default();
+        assert_eq!(default_config.addrs, vec!["redis://127.0.0.1"]);
+        assert_eq!(default_config.retry_count, 10);
+        assert_eq!(default_config.retry_delay, Duration::from_millis(400));
+        assert_eq!(default_config.retry_jitter, 400);
+        assert_eq!(default_config.drift_factor, 0.01);
+        assert_eq!(default_config.pool_size, 8);
+    }
+
+    #[test]
+    fn test_config_with_addrs() {
+        let config = Config::with_addrs(vec![String::from("redis://127.0.0.1")]);
+        assert_eq!(config.addrs, vec![String::from("redis://127.0.0.1")]);
+        assert_eq!(config.retry_count, 10);
+        assert_eq!(config.retry_delay, Duration::from_millis(400));
+        assert_eq!(config.retry_jitter, 400);
+        assert_eq!(config.drift_factor, 0.01);
+        assert_eq!(config.pool_size, 8);
+
+        let redlock = Redlock::new(Config {
+                                       retry_count: 5,
+                                       ..Config::with_addrs(vec![String::from("redis://127.0.0.1")])
+                                   })
+                .unwrap();
+        assert_eq!(redlock.retry_count, 5);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_new_with_no_server() {
+        Redlock::new::<&str>(Config::with_addrs(vec![])).unwrap();
+    }
+
+    #[test]
+    fn test_from_clients() {
+        let clients = vec![redis::Client::open("redis://127.0.0.1/0").unwrap(),
+                           redis::Client::open("redis://127.0.0.1/1").unwrap(),
+                           redis::Client::open("redis://127.0.0.1/2").unwrap()];
+        let redlock = Redlock::from_clients(clients, Config::<&str>::with_addrs(vec![])).unwrap();
+        assert_eq!(redlock.node_count(), 3);
+        assert_eq!(redlock.quorum(), 2);
+
+        let lock = redlock
+            .lock("test_from_clients", Duration::from_millis(1000))
+            .unwrap();
+        lock.unlock().unwrap();
+
+        assert!(Redlock::from_clients(vec![], Config::default()).is_err());
+    }
+
+    #[test]
+    fn test_new_with_strict_odd_nodes() {
+        let strict = |addrs| {
+            Redlock::new(Config {
+                             strict_odd_nodes: true,
+                             ..Config::with_addrs(addrs)
+                         })
+        };
+        assert_eq!(strict(vec!["redis://127.0.0.1"; 3]).unwrap().node_count(), 3);
+        assert!(strict(vec!["redis://127.0.0.1"]).is_ok());
+        match strict(vec!["redis://127.0.0.1"; 4]) {
+            Err(RedlockError::EvenNodeCount(4)) => {}
+            res => panic!("expected EvenNodeCount, got {:?}", res),
+        }
+
+        // Even counts are accepted unless strict
+        assert!(Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1"; 4])).is_ok());
+    }
+
+    #[test]
+    fn test_new_with_jitter_larger_than_delay() {
+        let result = Redlock::new::<&str>(Config {
+                                              retry_jitter: 401,
+                                              ..Config::default()
+                                          });
+        match result {
+            Err(RedlockError::DelayJitterError) => {}
+            _ => panic!("expected DelayJitterError"),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_config_serde() {
+        let config = Config {
+            retry_delay: Duration::from_millis(200),
+            retry_jitter: 100,
+            drift: Drift::Absolute(Duration::from_millis(5)),
+            command_timeout: Some(Duration::from_millis(50)),
+            default_ttls: vec![(String::from("jobs:"), Duration::from_millis(3000))]
+                .into_iter()
+                .collect(),
+            ..Config::with_addrs(vec![String::from("redis://127.0.0.1")])
+        };
+        let json = serde_json::to_string(&config).unwrap();
+        assert!(json.contains("\"retry_delay\":200"));
+        assert!(json.contains("\"default_ttls\":{\"jobs:\":3000}"));
+        assert!(json.contains("\"command_timeout\":50"));
+        assert!(json.contains("\"drift\":{\"Absolute\":5}"));
+
+        let decoded: Config<String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.addrs, config.addrs);
+        assert_eq!(decoded.retry_delay, config.retry_delay);
+        assert_eq!(decoded.command_timeout, config.command_timeout);
+        assert_eq!(decoded.drift, config.drift);
+        assert_eq!(decoded.token_len, config.token_len);
+        assert_eq!(decoded.default_ttls, config.default_ttls);
+        assert!(Redlock::new(decoded).is_ok());
+    }
+
+    #[test]
+    fn test_default_ttl() {
+        let redlock = Redlock::builder()
+            .add_address("redis://127.0.0.1")
+            .default_ttl("jobs:", Duration::from_millis(3000))
+            .default_ttl("jobs:report", Duration::from_millis(60000))
+            .default_ttl("cache:", Duration::from_millis(500))
+            .build()
+            .unwrap();
+        assert_eq!(redlock.default_ttl("jobs:import"), Some(Duration::from_millis(3000)));
+        assert_eq!(redlock.default_ttl("jobs:report"), Some(Duration::from_millis(60000)));
+        assert_eq!(redlock.default_ttl("cache:users"), Some(Duration::from_millis(500)));
+        assert_eq!(redlock.default_ttl("sessions:42"), None);
+
+        // The empty prefix is the fallback
+        let redlock = Redlock::new(Config {
+                                       default_ttls: vec![(String::from(""),
+                                                           Duration::from_millis(1000)),
+                                                          (String::from("jobs:"),
+                                                           Duration::from_millis(3000))]
+                                           .into_iter()
+                                           .collect(),
+                                       ..Config::default()
+                                   })
+                .unwrap();
+        assert_eq!(redlock.default_ttl("jobs:import"), Some(Duration::from_millis(3000)));
+        assert_eq!(redlock.default_ttl("sessions:42"), Some(Duration::from_millis(1000)));
+    }
+
+    #[test]
+    fn test_lock_default() {
+        let redlock = Redlock::builder()
+            .add_address("redis://127.0.0.1")
+            .default_ttl("test_lock_default:", Duration::from_millis(2000))
+            .build()
+            .unwrap();
+        let lock = redlock.lock_default("test_lock_default:matched").unwrap();
+        let ttl = lock.remaining_ttl().unwrap();
+        assert!(ttl > Duration::from_millis(1900) && ttl <= Duration::from_millis(2000));
+        lock.unlock().unwrap();
+
+        match redlock.lock_default("test_lock_default_unmatched") {
+            Err(RedlockError::NoDefaultTtl(resource_name)) => {
+                assert_eq!(resource_name, "test_lock_default_unmatched")
+            }
+            res => panic!("expected NoDefaultTtl, got {:?}", res),
+        }
+        assert!(!redlock.is_locked("test_lock_default_unmatched").unwrap());
+    }
+
+    #[test]
+    fn test_lock_record() {
+        let lock = REDLOCK
+            .lock("test_lock_record", Duration::from_millis(2000))
+            .unwrap();
+        let record = lock.to_record().unwrap();
+        assert_eq!(record.resource_name, lock.resource_name());
+        assert_eq!(record.value, lock.value());
+        let truncated = lock.expiration().duration_since(record.expiration()).unwrap();
+        assert!(truncated < Duration::from_millis(1));
+
+        REDLOCK.unlock_record(&record).unwrap();
+        assert!(!REDLOCK.is_locked("test_lock_record").unwrap());
+
+        let before_epoch = UNIX_EPOCH - Duration::from_millis(1);
+        match LockRecord::new("test_lock_record", "value", before_epoch) {
+            Err(RedlockError::TimeError(_)) => {}
+            res => panic!("expected TimeError, got {:?}", res),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_lock_record_serde() {
+        let resource_name = "test_lock_record_serde";
+        let json = {
+            let lock = REDLOCK.lock(resource_name, Duration::from_millis(2000)).unwrap();
+            let json = serde_json::to_string(&lock.to_record().unwrap()).unwrap();
+            assert!(json.contains(&format!("\"value\":\"{}\"", lock.value())));
+            assert!(json.contains("\"expiration_unix_ms\":"));
+            json
+        };
+
+        // The lock has been dropped, without releasing it
+        assert!(REDLOCK.is_locked(resource_name).unwrap());
+        let record: LockRecord = serde_json::from_str(&json).unwrap();
+        assert!(record.expiration() > SystemTime::now());
+        REDLOCK.unlock_record(&record).unwrap();
+        assert!(!REDLOCK.is_locked(resource_name).unwrap());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_config_serde_defaults() {
+        let config: Config<String> =
+            serde_json::from_str(r#"{"addrs": ["redis://127.0.0.1"], "retry_count": 3}"#)
+                .unwrap();
+        assert_eq!(config.retry_count, 3);
+        assert_eq!(config.retry_delay, Duration::from_millis(400));
+        assert_eq!(config.retry_jitter, 400);
+        assert_eq!(config.pool_size, 8);
+        assert_eq!(config.drift, Drift::Factor);
+        assert!(config.command_timeout.is_none());
+    }
+
+    #[test]
+    fn test_new_with_empty_token() {
+        let res = Redlock::new(Config {
+                                   token_len: 0,
+                                   ..Config::default()
+                               });
+        match res {
+            Err(err @ RedlockError::InvalidConfig(_)) => {
+                assert_eq!(err.to_string(), "Invalid configuration: token_len must be at least 1")
+            }
+            _ => panic!("expected InvalidConfig"),
+        }
+    }
+
+    #[test]
+    fn test_new_with_invalid_address() {
+        let result = Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1",
+                                                          "http://127.0.0.1"]));
+        match result {
+            Err(RedlockError::InvalidAddress(1, _)) => {}
+            _ => panic!("expected InvalidAddress"),
+        }
+    }
+
+    #[test]
+    fn test_new_with_unix_socket() {
+        let redlock = Redlock::new(Config::with_addrs(vec!["unix:///tmp/redis.sock"])).unwrap();
+        assert_eq!(redlock.nodes.len(), 1);
+    }
+
+    #[test]
+    fn test_new() {
+        let redlock = Redlock::new(Config::default()).unwrap();
+        assert_eq!(redlock.nodes.len(), 1);
+        assert_eq!(redlock.retry_count, 10);
+        assert_eq!(redlock.retry_delay, Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_request_all() {
+        let redlock = Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1",
+                                                           "redis://127.0.0.1:1",
+                                                           "redis://127.0.0.1"]))
+                .unwrap();
+        let results = redlock.request_all(|backend| Ok(backend.ping().is_ok()));
+
+        assert_eq!(results.len(), 3);
+        assert!(results[0].as_ref().unwrap());
+        assert!(!results[1].as_ref().unwrap());
+        assert!(results[2].as_ref().unwrap());
+    }
+
+    #[test]
+    fn test_get_retry_timeout() {
+        let redlock = Redlock::new(Config::default()).unwrap();
+        let timeouts: Vec<Duration> = (0..1000).map(|_| redlock.get_retry_timeout(1)).collect();
+
+        assert!(timeouts.iter().all(|t| *t <= Duration::from_millis(800)));
+        assert!(timeouts.iter().any(|t| *t < Duration::from_millis(400)));
+        assert!(timeouts.iter().any(|t| *t > Duration::from_millis(400)));
+    }
+
+    #[test]
+    fn test_retry_timeout_seed() {
+        let new_redlock = |rng_seed| {
+            Redlock::new(Config {
+                             rng_seed: Some(rng_seed),
+                             ..Config::default()
+                         })
+                    .unwrap()
+        };
+        let timeouts = |redlock: &Redlock| -> Vec<u64> {
+            (1..6)
+                .map(|attempt| util::num_milliseconds(&redlock.get_retry_timeout(attempt)))
+                .collect()
+        };
+
+        let expected = vec![85, 395, 776, 253, 423];
+        assert_eq!(timeouts(&new_redlock(42)), expected);
+        assert_eq!(timeouts(&new_redlock(42)), expected);
+        assert_ne!(timeouts(&new_redlock(43)), expected);
+    }
+
+    #[test]
+    fn test_backoff_schedule() {
+        let redlock = Redlock::new(Config {
+                                       retry_delay: Duration::from_millis(100),
+                                       retry_jitter: 0,
+                                       ..Config::default()
+                                   })
+                .unwrap();
+        let delays: Vec<Duration> = (1..6).map(|attempt| redlock.get_retry_timeout(attempt)).collect();
+        assert_eq!(delays, vec![Duration::from_millis(100); 5]);
+
+        let redlock = Redlock::new(Config {
+                                       retry_delay: Duration::from_millis(100),
+                                       retry_jitter: 0,
+                                       backoff: BackoffStrategy::Exponential {
+                                           base: 2,
+                                           cap: Duration::from_millis(1000),
+                                       },
+                                       ..Config::default()
+                                   })
+                .unwrap();
+        let delays: Vec<u64> = (1..7)
+            .map(|attempt| util::num_milliseconds(&redlock.get_retry_timeout(attempt)))
+            .collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(redlock.get_retry_timeout(100), Duration::from_millis(1000));
+
+        // The jitter is applied on top of the grown delay
+        let redlock = Redlock::new(Config {
+                                       retry_delay: Duration::from_millis(100),
+                                       retry_jitter: 50,
+                                       backoff: BackoffStrategy::Exponential {
+                                           base: 3,
+                                           cap: Duration::from_millis(1000),
+                                       },
+                                       ..Config::default()
+                                   })
+                .unwrap();
+        let timeout = redlock.get_retry_timeout(3);
+        assert!(timeout >= Duration::from_millis(850) && timeout <= Duration::from_millis(950));
+    }
+
+    #[test]
+    fn test_unlock_retry() {
+        let resource_name = "test_unlock_retry";
+        let addrs = vec!["redis://127.0.0.1/0", "redis://127.0.0.1:1", "redis://127.0.0.1:1"];
+        let config = Config {
+            retry_count: 5,
+            retry_delay: Duration::from_millis(2000),
+            retry_jitter: 0,
+            ..Config::with_addrs(addrs.clone())
+        };
+        assert_eq!(Redlock::new(config).unwrap().unlock_retry_count, 5);
+
+        let redlock = Redlock::new(Config {
+                                       retry_count: 5,
+                                       retry_delay: Duration::from_millis(2000),
+                                       retry_jitter: 0,
+                                       unlock_retry_count: Some(2),
+                                       unlock_retry_delay: Some(Duration::from_millis(10)),
+                                       ..Config::with_addrs(addrs)
+                                   })
+                .unwrap();
+        let lock = REDLOCK
+            .lock(resource_name, Duration::from_millis(1000))
+            .unwrap();
+
+        // Only one of the three servers can be released
+        let start = Instant::now();
+        match redlock.unlock_resource(resource_name, lock.value()) {
+            Err(err @ RedlockError::UnableToUnlock(..)) => {
+                assert_eq!(err.to_string(),
+                           "Unable to unlock 'test_unlock_retry': 0 of 2 required votes after 2 \
+                            attempts");
+            }
+            res => panic!("expected UnableToUnlock, got {:?}", res),
+        }
+        assert!(start.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_set_outcome() {
+        let resource_name = "test_set_outcome";
+        let one_second = Duration::from_millis(1000);
+        let backend = REDLOCK.nodes[0].backend();
+
+        assert_eq!(backend.set_nx_px(resource_name, "first", &one_second).unwrap(),
+                   SetOutcome::Acquired);
+        assert_eq!(backend.set_nx_px(resource_name, "second", &one_second).unwrap(),
+                   SetOutcome::AlreadyHeld);
+        assert_eq!(backend.compare_and_del(resource_name, "first").unwrap(),
+                   UnlockOutcome::Released);
+    }
+
+    // Lock the resource on the servers 0 and 1 with another value, so that
+    // an attempt only acquires the server 2, and releases it on failure.
+    fn lock_majority_by_other(resource_name: &str) -> Vec<redis::Connection> {
+        let mut cons: Vec<redis::Connection> = ["redis://127.0.0.1/0", "redis://127.0.0.1/1"]
+            .iter()
+            .map(|addr| redis::Client::open(*addr).unwrap().get_connection().unwrap())
+            .collect();
+        for con in &mut cons {
+            let _: () = con.set(resource_name, "other").unwrap();
+        }
+        cons
+    }
+
+    // A server going down right after granting a lock, so that the lock
+    // can't be released and is left on it until it expires.
+    struct DownAfterGrant {
+        pool: ConnectionPool,
+        down: AtomicBool,
+    }
+
+    impl DownAfterGrant {
+        fn new(addr: &str) -> DownAfterGrant {
+            DownAfterGrant {
+                pool: ConnectionPool::new(redis::Client::open(addr).unwrap(), 1, None),
+                down: AtomicBool::new(false),
+            }
+        }
+
+        fn check(&self) -> RedlockResult<()> {
+            if self.down.load(Ordering::SeqCst) {
+                let err = (redis::ErrorKind::IoError, "The server is down");
+                return Err(redis::RedisError::from(err).into());
+            }
+            Ok(())
+        }
+    }
+
+    impl LockBackend for DownAfterGrant {
+        fn set_nx_px(&self, key: &str, value: &str, ttl: &Duration) -> RedlockResult<SetOutcome> {
+            self.check()?;
+            let outcome = self.pool.set_nx_px(key, value, ttl)?;
+            self.down.store(outcome == SetOutcome::Acquired, Ordering::SeqCst);
+            Ok(outcome)
+        }
+
+        fn compare_and_del(&self, key: &str, value: &str) -> RedlockResult<UnlockOutcome> {
+            self.check()?;
+            self.pool.compare_and_del(key, value)
+        }
+
+        fn compare_and_pexpire(&self,
+                               key: &str,
+                               value: &str,
+                               ttl: &Duration)
+                               -> RedlockResult<bool> {
+            self.check()?;
+            self.pool.compare_and_pexpire(key, value, ttl)
+        }
+
+        fn compare_and_pttl(&self, key: &str, value: &str) -> RedlockResult<Option<Duration>> {
+            self.check()?;
+            self.pool.compare_and_pttl(key, value)
+        }
+
+        fn get(&self, key: &str) -> RedlockResult<Option<String>> {
+            self.check()?;
+            LockBackend::get(&self.pool, key)
+        }
+
+        fn set_nx_px_fenced(&self,
+                            key: &str,
+                            fence_key: &str,
+                            value: &str,
+                            ttl: &Duration)
+                            -> RedlockResult<Option<u64>> {
+            self.check()?;
+            self.pool.set_nx_px_fenced(key, fence_key, value, ttl)
+        }
+    }
+
+    // A redlock whose attempts only acquire the server 2, which goes down
+    // right after, so that the lock is orphaned on it.
+    fn orphan_redlock(resource_name: &str, orphan_policy: OrphanPolicy) -> Redlock {
+        let pool = |addr: &str| ConnectionPool::new(redis::Client::open(addr).unwrap(), 1, None);
+        let backends: Vec<Arc<dyn LockBackend>> = vec![Arc::new(pool("redis://127.0.0.1/0")),
+                                                       Arc::new(pool("redis://127.0.0.1/1")),
+                                                       Arc::new(DownAfterGrant::new("redis://127.0.0.1/2"))];
+        let redlock = Redlock::with_backends(backends, orphan_config(orphan_policy)).unwrap();
+        for db in 0..2 {
+            let _: () = redis::Client::open(format!("redis://127.0.0.1/{}", db))
+                .unwrap()
+                .get_connection()
+                .unwrap()
+                .set(resource_name, "other")
+                .unwrap();
+        }
+        redlock
+    }
+
+    fn clear(resource_name: &str) {
+        for db in 0..3 {
+            let _: () = redis::Client::open(format!("redis://127.0.0.1/{}", db))
+                .unwrap()
+                .get_connection()
+                .unwrap()
+                .del(resource_name)
+                .unwrap();
+        }
+    }
+
+    fn orphan_config(orphan_policy: OrphanPolicy) -> Config<&'static str> {
+        Config {
+            retry_count: 1,
+            orphan_policy,
+            ..Config::with_addrs(vec!["redis://127.0.0.1/0",
+                                      "redis://127.0.0.1/1",
+                                      "redis://127.0.0.1/2"])
+        }
+    }
+
+    #[test]
+    fn test_failed_attempt_released() {
+        let resource_name = "test_failed_attempt_released";
+        let mut cons = lock_majority_by_other(resource_name);
+        let orphans = Arc::new(Mutex::new(Vec::new()));
+        let recorded = orphans.clone();
+        let record = move |resource_name: &str, _: &str| {
+            recorded.lock().unwrap().push(String::from(resource_name));
+        };
+        let redlock = Redlock::new(orphan_config(OrphanPolicy::Record(Arc::new(record)))).unwrap();
+
+        // Released from the only server which granted it, a single server
+        // can't make a quorum but nothing is left behind
+        match redlock.lock(resource_name, Duration::from_millis(1000)) {
+            Err(RedlockError::QuorumNotReached(..)) => {}
+            res => panic!("expected QuorumNotReached, got {:?}", res),
+        }
+        assert!(orphans.lock().unwrap().is_empty());
+        let mut con = redis::Client::open("redis://127.0.0.1/2").unwrap().get_connection().unwrap();
+        let value: Option<String> = con.get(resource_name).unwrap();
+        assert_eq!(value, None);
+
+        for con in &mut cons {
+            let _: () = con.del(resource_name).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_failed_extend_kept() {
+        let resource_name = "test_failed_extend_kept";
+        let redlock = Redlock::new(orphan_config(OrphanPolicy::Ignore)).unwrap();
+        let lock = redlock.lock(resource_name, Duration::from_millis(2000)).unwrap();
+        let mut cons = lock_majority_by_other(resource_name);
+
+        // The extension fails, but the lock is left on the last server
+        // until it expires rather than released
+        assert!(lock.extend(Duration::from_millis(2000)).is_err());
+        let mut con = redis::Client::open("redis://127.0.0.1/2").unwrap().get_connection().unwrap();
+        let value: Option<String> = con.get(resource_name).unwrap();
+        assert_eq!(value.as_deref(), Some(lock.value()));
+
+        cons.push(con);
+        for con in &mut cons {
+            let _: () = con.del(resource_name).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_orphan_policy_record() {
+        let resource_name = "test_orphan_policy_record";
+        let orphans = Arc::new(Mutex::new(Vec::new()));
+        let recorded = orphans.clone();
+        let record = move |resource_name: &str, value: &str| {
+            recorded.lock().unwrap().push((String::from(resource_name), String::from(value)));
+        };
+        let redlock = orphan_redlock(resource_name, OrphanPolicy::Record(Arc::new(record)));
+
+        assert!(redlock.lock(resource_name, Duration::from_millis(1000)).is_err());
+        let orphans = orphans.lock().unwrap();
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].0, resource_name);
+        assert_eq!(orphans[0].1.len(), 40);
+
+        clear(resource_name);
+    }
+
+    #[test]
+    fn test_orphan_policy_abort() {
+        let resource_name = "test_orphan_policy_abort";
+        let redlock = orphan_redlock(resource_name, OrphanPolicy::Abort);
+
+        match redlock.lock(resource_name, Duration::from_millis(1000)) {
+            Err(RedlockError::OrphanedLock(_)) => {}
+            res => panic!("expected OrphanedLock, got {:?}", res),
+        }
+
+        clear(resource_name);
+    }
+
+    // The resource name, the value and the node results of an orphan.
+    type Orphan = (String, String, Vec<String>);
+
+    #[derive(Default)]
+    struct OrphanObserver {
+        orphans: Arc<Mutex<Vec<Orphan>>>,
+    }
+
+    impl Observer for OrphanObserver {
+        fn on_orphaned(&self, resource_name: &str, value: &str, node_results: &[NodeOutcome]) {
+            let node_results = node_results
+                .iter()
+                .map(|outcome| format!("{:?}", outcome))
+                .collect();
+            self.orphans
+                .lock()
+                .unwrap()
+                .push((String::from(resource_name), String::from(value), node_results));
+        }
+    }
+
+    #[test]
+    fn test_observer_orphaned() {
+        let resource_name = "test_observer_orphaned";
+        let observer = OrphanObserver::default();
+        let orphans = observer.orphans.clone();
+        let redlock = orphan_redlock(resource_name, OrphanPolicy::Ignore).with_observer(observer);
+
+        assert!(redlock.lock(resource_name, Duration::from_millis(1000)).is_err());
+        assert_eq!(redlock.orphaned_count(), 1);
+        {
+            let orphans = orphans.lock().unwrap();
+            assert_eq!(orphans.len(), 1);
+            assert_eq!(orphans[0].0, resource_name);
+            assert_eq!(orphans[0].1.len(), 40);
+            // Held by the other value on the first two servers, the last
+            // one went down after granting it
+            assert_eq!(orphans[0].2[..2], ["Held", "Held"]);
+            assert!(orphans[0].2[2].starts_with("Error"));
+        }
+        // Left on the last server until it expires
+        let mut con = redis::Client::open("redis://127.0.0.1/2").unwrap().get_connection().unwrap();
+        let value: Option<String> = con.get(resource_name).unwrap();
+        assert_eq!(value.as_ref(), Some(&orphans.lock().unwrap()[0].1));
+
+        // The clones share the count
+        assert_eq!(redlock.clone().orphaned_count(), 1);
+        clear(resource_name);
+    }
+
+    #[test]
+    fn test_observer_not_orphaned() {
+        let resource_name = "test_observer_not_orphaned";
+        let mut cons = lock_majority_by_other(resource_name);
+        let observer = OrphanObserver::default();
+        let orphans = observer.orphans.clone();
+        let redlock = Redlock::new(orphan_config(OrphanPolicy::Ignore))
+            .unwrap()
+            .with_observer(observer);
+
+        // Released from the only server which granted it, the others are
+        // held by the other value and never were ours
+        assert!(redlock.lock(resource_name, Duration::from_millis(1000)).is_err());
+        assert_eq!(redlock.orphaned_count(), 0);
+        assert!(orphans.lock().unwrap().is_empty());
+
+        for con in &mut cons {
+            let _: () = con.del(resource_name).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_max_operations() {
+        let resource_name = "test_max_operations";
+        let one_second = Duration::from_millis(1000);
+        let redlock = Redlock::new(Config {
+                                       max_operations: Some(2),
+                                       ..Config::default()
+                                   })
+                .unwrap();
+
+        let lock = redlock.lock(resource_name, one_second).unwrap();
+        match redlock.lock(resource_name, one_second) {
+            Err(RedlockError::BudgetExhausted) => {}
+            res => panic!("expected BudgetExhausted, got {:?}", res),
+        }
+        lock.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_lock() {
+        let resource_name = "test_lock";
+        let one_second = Duration::from_millis(1000);
+
+        let lock = REDLOCK.lock(resource_name, one_second).unwrap();
+        assert!(lock.expiration < SystemTime::now().add(one_second));
+    }
+
+    #[test]
+    fn test_lock_with() {
+        let resource_name = "test_lock_with";
+        let redlock = Redlock::new(Config {
+                                       retry_count: 3,
+                                       retry_delay: Duration::from_millis(100),
+                                       retry_jitter: 0,
+                                       ..Config::default()
+                                   })
+                .unwrap();
+
+        for &ttl in &[Duration::from_millis(1000), Duration::from_millis(5000)] {
+            let before = SystemTime::now();
+            let lock = redlock.lock_with(resource_name, || ttl).unwrap();
+            let after = SystemTime::now();
+            assert!(lock.expiration() >= before + redlock.validity(ttl));
+            assert!(lock.expiration() <= after + redlock.validity(ttl));
+            lock.unlock().unwrap();
+        }
+
+        // The TTL is computed again before each attempt, the resource is
+        // released by its other holder before the second one
+        let con = Mutex::new(redis::Client::open("redis://127.0.0.1")
+                                 .unwrap()
+                                 .get_connection()
+                                 .unwrap());
+        let _: () = con.lock().unwrap().set(resource_name, "other").unwrap();
+        let ttls = Mutex::new(vec![Duration::from_millis(4000), Duration::from_millis(3000)]);
+        let lock = redlock
+            .lock_with(resource_name, || {
+                let mut ttls = ttls.lock().unwrap();
+                if ttls.len() == 1 {
+                    let _: () = con.lock().unwrap().del(resource_name).unwrap();
+                }
+                ttls.pop().unwrap()
+            })
+            .unwrap();
+        assert_eq!(lock.attempts(), 2);
+        let remaining_ttl = lock.remaining_ttl().unwrap();
+        assert!(remaining_ttl > Duration::from_millis(3000));
+        assert!(remaining_ttl <= redlock.validity(Duration::from_millis(4000)));
+        lock.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_validity() {
+        let redlock = Redlock::new(Config::default()).unwrap();
+        assert_eq!(redlock.validity(Duration::from_millis(1000)), Duration::from_millis(988));
+        assert_eq!(redlock.validity(Duration::from_millis(1)), Duration::from_millis(0));
+    }
+
+    #[test]
+    fn test_min_recommended_ttl() {
+        // 10 retries of 400ms, plus a drift of 1% and 2ms
+        let redlock = Redlock::new(Config::default()).unwrap();
+        let ttl = redlock.min_recommended_ttl();
+        assert_eq!(ttl, Duration::from_millis(4043));
+        assert!(redlock.validity(ttl) > Duration::from_millis(4000));
+        assert!(redlock.validity(ttl - Duration::from_millis(1)) <= Duration::from_millis(4000));
+
+        let redlock = Redlock::new(Config {
+                                       command_timeout: Some(Duration::from_millis(100)),
+                                       min_validity: Duration::from_millis(50),
+                                       drift: Drift::Absolute(Duration::from_millis(10)),
+                                       ..Config::default()
+                                   })
+                .unwrap();
+        assert_eq!(redlock.min_recommended_ttl(), Duration::from_millis(261));
+    }
+
+    #[test]
+    fn test_lock_cancellable() {
+        let resource_name = "test_lock_cancellable";
+        let one_second = Duration::from_millis(1000);
+        let addrs = ["redis://127.0.0.1/0", "redis://127.0.0.1/1", "redis://127.0.0.1/2"];
+        // Granted, but never valid for long enough, every attempt fails and
+        // is retried
+        let redlock = Redlock::new(Config {
+                                       retry_delay: Duration::from_millis(100),
+                                       retry_jitter: 50,
+                                       min_validity: one_second * 2,
+                                       ..Config::with_addrs(addrs.to_vec())
+                                   })
+                .unwrap();
+        let cancel = AtomicBool::new(false);
+
+        let start = Instant::now();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(200));
+                cancel.store(true, Ordering::SeqCst);
+            });
+            match redlock.lock_cancellable(resource_name, one_second, &cancel) {
+                Err(RedlockError::Cancelled) => {}
+                res => panic!("expected Cancelled, got {:?}", res),
+            }
+        });
+        // The retries would take about a second
+        assert!(start.elapsed() < Duration::from_millis(500));
+        // Nothing is left locked by the cancelled request
+        for addr in &addrs {
+            let value: Option<String> = redis::Client::open(*addr)
+                .unwrap()
+                .get_connection()
+                .unwrap()
+                .get(resource_name)
+                .unwrap();
+            assert!(value.is_none());
+        }
+        assert!(matches!(redlock.lock_cancellable(resource_name, one_second, &cancel),
+                         Err(RedlockError::Cancelled)));
+    }
+
+    #[test]
+    fn test_unlock_resource() {
+        let resource_name = "test_unlock_resource";
+        let redlock = Redlock::new(Config::default()).unwrap();
+        let value = {
+            let lock = redlock.lock(resource_name, Duration::from_millis(2000)).unwrap();
+            lock.value().to_string()
+        };
+        assert!(redlock.is_locked(resource_name).unwrap());
+
+        redlock.unlock_resource(resource_name, &value).unwrap();
+        assert!(!redlock.is_locked(resource_name).unwrap());
+    }
+
+    #[test]
+    fn test_poll_lock() {
+        let resource_name = "test_poll_lock";
+        let one_second = Duration::from_millis(1000);
+        let redlock = Redlock::new(Config::default()).unwrap();
+
+        let lock = match redlock.poll_lock(resource_name, one_second).unwrap() {
+            PollResult::Acquired(lock) => lock,
+            res => panic!("expected Acquired, got {:?}", res),
+        };
+        match redlock.poll_lock(resource_name, one_second).unwrap() {
+            PollResult::Retry { after } => {
+                assert!(after >= Duration::from_millis(0));
+                assert!(after <= Duration::from_millis(800));
+            }
+            res => panic!("expected Retry, got {:?}", res),
+        }
+        lock.unlock().unwrap();
+
+        let redlock = Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1:1"])).unwrap();
+        assert!(matches!(redlock.poll_lock(resource_name, one_second),
+                         Err(RedlockError::AllNodesFailed(_))));
+    }
+
+    #[test]
+    fn test_drift_modes() {
+        let ttl = Duration::from_millis(1000);
+        // (drift, expected) with a factor of 0.01 and 2ms of clock drift
+        for &(drift, expected) in &[(Drift::Factor, 12),
+                                    (Drift::Absolute(Duration::from_millis(5)), 5),
+                                    (Drift::Absolute(Duration::from_millis(0)), 0)] {
+            let redlock = Redlock::new(Config {
+                                           drift,
+                                           ..Config::default()
+                                       })
+                    .unwrap();
+            assert_eq!(redlock.get_drift(&ttl), Duration::from_millis(expected));
+            // The absolute drift does not grow with the TTL
+            if let Drift::Absolute(drift) = drift {
+                assert_eq!(redlock.get_drift(&(ttl * 100)), drift);
+            }
+
+            let before = SystemTime::now();
+            let lock = redlock.lock("test_drift_modes", ttl).unwrap();
+            let after = SystemTime::now();
+            let expected = Duration::from_millis(expected);
+            assert!(lock.expiration() >= before + ttl - expected);
+            assert!(lock.expiration() <= after + ttl - expected);
+            lock.unlock().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_invalid_drift_factor() {
+        for &drift_factor in &[-0.01, f32::NAN, f32::INFINITY, 1.0, 1.5] {
+            let res = Redlock::new(Config {
+                                       drift_factor,
+                                       ..Config::default()
+                                   });
+            assert!(matches!(res, Err(RedlockError::InvalidDriftFactor(_))));
+        }
+    }
+
+    #[test]
+    fn test_invalid_retry_count() {
+        let res = Redlock::new(Config {
+                                   retry_count: 0,
+                                   ..Config::default()
+                               });
+        assert!(matches!(res, Err(RedlockError::InvalidRetryCount)));
+
+        let res = Redlock::new(Config {
+                                   unlock_retry_count: Some(0),
+                                   ..Config::default()
+                               });
+        assert!(matches!(res, Err(RedlockError::InvalidRetryCount)));
+
+        let res = Redlock::builder()
+            .add_address("redis://127.0.0.1")
+            .retry_count(0)
+            .build();
+        assert!(matches!(res, Err(RedlockError::InvalidRetryCount)));
+
+        // A single attempt is made with a retry count of 1
+        let redlock = Redlock::new(Config {
+                                       retry_count: 1,
+                                       ..Config::default()
+                                   })
+                .unwrap();
+        let lock = redlock
+            .lock("test_invalid_retry_count", Duration::from_millis(1000))
+            .unwrap();
+        match redlock.lock("test_invalid_retry_count", Duration::from_millis(1000)) {
+            Err(RedlockError::QuorumNotReached(_, 0, 1, 1, _, timings)) => assert_eq!(timings.len(), 1),
+            res => panic!("expected QuorumNotReached, got {:?}", res),
+        }
+        lock.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_drift() {
+        for &(ttl, drift_factor, clock_drift_ms, drift) in
+            &[(1000, 0.01, 2, 12), (100, 0.001, 5, 5), (10000, 0.05, 0, 500)] {
+            let redlock = Redlock::new(Config {
+                                           drift_factor,
+                                           clock_drift_ms,
+                                           ..Config::default()
+                                       })
+                    .unwrap();
+            let ttl = Duration::from_millis(ttl);
+            assert_eq!(redlock.get_drift(&ttl), Duration::from_millis(drift));
+
+            let before = SystemTime::now();
+            let lock = redlock.lock("test_drift", ttl).unwrap();
+            let after = SystemTime::now();
+            let drift = Duration::from_millis(drift);
+            assert!(lock.expiration() >= before + ttl - drift);
+            assert!(lock.expiration() <= after + ttl - drift);
+            lock.unlock().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_lock_accessors() {
+        let resource_name = "test_lock_accessors";
+        let one_second = Duration::from_millis(1000);
+        let clock = TestClock::new();
+        let redlock = Redlock::new(Config::default()).unwrap().with_clock(clock.clone());
+        let lock = redlock.lock(resource_name, one_second).unwrap();
+
+        assert_eq!(lock.resource_name(), resource_name);
+        assert_eq!(lock.value().len(), 40);
+        assert_eq!(lock.expiration(), clock.now() + one_second - Duration::from_millis(12));
+        assert_eq!(lock.acquisition_time(), Duration::from_millis(0));
+        assert_eq!(lock.attempts(), 1);
+        assert_eq!(lock.remaining_ttl(), Some(Duration::from_millis(988)));
+
+        clock.advance(Duration::from_millis(900));
+        assert_eq!(lock.remaining_ttl(), Some(Duration::from_millis(88)));
+        clock.advance(Duration::from_millis(100));
+        assert!(lock.remaining_ttl().is_none());
+        lock.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_lock_with_value() {
+        let resource_name = "test_lock_with_value";
+        let lock = REDLOCK
+            .lock_with_value(resource_name, "request-42", Duration::from_millis(1000))
+            .unwrap();
+        assert_eq!(lock.value(), "request-42");
+
+        let value: String = REDIS_CLI
+            .get_connection()
+            .unwrap()
+            .get(resource_name)
+            .unwrap();
+        assert_eq!(value, "request-42");
+        lock.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_lock_with_empty_value() {
+        match REDLOCK.lock_with_value("test_lock_with_empty_value", "", Duration::from_millis(1000)) {
+            Err(RedlockError::InvalidValue) => {}
+            _ => panic!("expected InvalidValue"),
+        }
+    }
+
+    #[test]
+    fn test_fence_key() {
+        assert_eq!(fence_key("resource"), "{resource}:fence");
+        assert_eq!(fence_key("user:{42}:profile"), "user:{42}:profile:fence");
+        assert_eq!(fence_key("{}resource"), "{{}resource}:fence");
+    }
+
+    #[test]
+    fn test_lock_or_existing() {
+        let resource_name = "test_lock_or_existing";
+        let one_second = Duration::from_millis(1000);
+        let lock = REDLOCK
+            .lock_or_existing(resource_name, "value", one_second)
+            .unwrap();
+        thread::sleep(Duration::from_millis(500));
+
+        // Locking again with the same value refreshes the TTL
+        let relocked = REDLOCK
+            .lock_or_existing(resource_name, "value", one_second)
+            .unwrap();
+        assert!(relocked.expiration > lock.expiration);
+        let ttl: i64 = REDIS_CLI
+            .get_connection()
+            .unwrap()
+            .pttl(resource_name)
+            .unwrap();
+        assert!(ttl > 900);
+
+        let redlock = Redlock::new(Config {
+                                       retry_count: 2,
+                                       retry_delay: Duration::from_millis(100),
+                                       retry_jitter: 50,
+                                       ..Config::default()
+                                   })
+                .unwrap();
+        assert!(redlock
+                    .lock_or_existing(resource_name, "other", one_second)
+                    .is_err());
+        assert!(REDLOCK
+                    .lock_or_existing(resource_name, "", one_second)
+                    .is_err());
+
+        relocked.unlock().unwrap();
+        assert!(!REDLOCK.is_locked(resource_name).unwrap());
+    }
+
+    #[test]
+    fn test_lock_fenced() {
+        let resource_name = "test_lock_fenced";
+        let one_second = Duration::from_millis(1000);
+
+        let lock = REDLOCK.lock_fenced(resource_name, one_second).unwrap();
+        let first_token = lock.fence_token();
+        assert!(first_token > 0);
+        lock.unlock().unwrap();
+
+        let lock = REDLOCK.lock_fenced(resource_name, one_second).unwrap();
+        assert!(lock.fence_token() > first_token);
+        lock.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_try_lock() {
+        let resource_name = "test_try_lock";
+        let one_second = Duration::from_millis(1000);
+
+        let lock = REDLOCK.try_lock(resource_name, one_second).unwrap().unwrap();
+        let start = SystemTime::now();
+        assert!(REDLOCK.try_lock(resource_name, one_second).unwrap().is_none());
+        assert!(start.elapsed().unwrap() < Duration::from_millis(400));
+
+        lock.unlock().unwrap();
+        assert!(REDLOCK.try_lock(resource_name, one_second).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_lock_with_huge_ttl() {
+        let resource_name = "test_lock_with_huge_ttl";
+        for ttl in &[Duration::from_secs(u64::MAX), Duration::from_millis(i64::MAX as u64 + 1)] {
+            match REDLOCK.lock(resource_name, *ttl) {
+                Err(RedlockError::InvalidTtl(invalid)) => assert_eq!(invalid, *ttl),
+                res => panic!("expected InvalidTtl, got {:?}", res),
+            }
+            assert!(matches!(REDLOCK.try_lock(resource_name, *ttl),
+                             Err(RedlockError::InvalidTtl(_))));
+        }
+
+        let lock = REDLOCK
+            .lock(resource_name, Duration::from_millis(1000))
+            .unwrap();
+        assert!(matches!(lock.extend(Duration::from_secs(u64::MAX)),
+                         Err(RedlockError::InvalidTtl(_))));
+        lock.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_lock_until() {
+        let resource_name = "test_lock_until";
+        let lock = REDLOCK
+            .lock(resource_name, Duration::from_millis(5000))
+            .unwrap();
+
+        let start = SystemTime::now();
+        let deadline = start + Duration::from_millis(1000);
+        match REDLOCK.lock_until(resource_name, Duration::from_millis(1000), deadline) {
+            Err(RedlockError::TimeoutError) => {}
+            _ => panic!("expected TimeoutError"),
+        }
+        let elapsed = start.elapsed().unwrap();
+        assert!(elapsed >= Duration::from_millis(1000));
+        assert!(elapsed < Duration::from_millis(1500));
+
+        lock.unlock().unwrap();
+        let deadline = SystemTime::now() + Duration::from_millis(1000);
+        assert!(REDLOCK
+                    .lock_until(resource_name, Duration::from_millis(1000), deadline)
+                    .is_ok());
+    }
+
+    #[test]
+    fn test_lock_until_clock() {
+        let resource_name = "test_lock_until_clock";
+        let clock = TestClock::new();
+        let redlock = Redlock::new(Config::default()).unwrap().with_clock(clock.clone());
+        let lock = redlock
+            .lock(resource_name, Duration::from_millis(5000))
+            .unwrap();
+
+        // The deadline has already passed by the clock of the redlock
+        clock.advance(Duration::from_millis(10000));
+        let start = Instant::now();
+        let deadline = SystemTime::now() + Duration::from_millis(3000);
+        match redlock.lock_until(resource_name, Duration::from_millis(1000), deadline) {
+            Err(RedlockError::TimeoutError) => {}
+            _ => panic!("expected TimeoutError"),
+        }
+        assert!(start.elapsed() < Duration::from_millis(1000));
+        lock.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_lock_attempts() {
+        let resource_name = "test_lock_attempts";
+        let _lock = REDLOCK
+            .lock(resource_name, Duration::from_millis(300))
+            .unwrap();
+
+        let deadline = SystemTime::now() + Duration::from_millis(3000);
+        let lock = REDLOCK
+            .lock_until(resource_name, Duration::from_millis(1000), deadline)
+            .unwrap();
+        assert!(lock.attempts() > 1);
+    }
+
+    #[test]
+    fn test_lock_twice() {
+        let resource_name = "test_lock_twice";
+        let one_second = Duration::from_millis(1000);
+        let start = SystemTime::now();
+        let lock = REDLOCK.lock(resource_name, one_second).unwrap();
+
+        assert!(lock.expiration > start);
+        assert!(lock.expiration < start.add(one_second));
+        assert!(REDLOCK.lock(resource_name, one_second).is_err());
+
+        thread::sleep(one_second);
+
+        assert!(REDLOCK.lock(resource_name, one_second).is_ok());
+    }
+
+    #[test]
+    fn test_lock_quorum_not_reached() {
+        let redlock = Redlock::new::<&str>(Config {
+                                               retry_count: 1,
+                                               retry_delay: Duration::from_millis(100),
+                                               retry_jitter: 50,
+                                               ..Config::with_addrs(vec!["redis://127.0.0.1",
+                                                                         "redis://127.0.0.1:1"])
+                                           })
+                .unwrap();
+
+        let err = redlock
+            .lock("test_lock_quorum_not_reached", Duration::from_millis(1000))
+            .unwrap_err();
+        assert_eq!(err.to_string(),
+                   "Failed to lock 'test_lock_quorum_not_reached': got 1 of 2 required votes \
+                    after 1 attempts");
+        match err {
+            RedlockError::QuorumNotReached(resource_name, votes, quorum, attempts, node_results, _) => {
+                assert_eq!(resource_name, "test_lock_quorum_not_reached");
+                assert_eq!(attempts, 1);
+                assert_eq!(votes, 1);
+                assert_eq!(quorum, 2);
+                assert_eq!(node_results.len(), 2);
+                assert!(matches!(node_results[0], NodeOutcome::Acquired));
+                assert!(matches!(node_results[1], NodeOutcome::Error(ref label, _) if label == "1"));
+            }
+            _ => panic!("expected QuorumNotReached"),
+        }
+    }
+
+    #[test]
+    fn test_lock_attempt_timings() {
+        let resource_name = "test_lock_attempt_timings";
+        let ttl = Duration::from_millis(2000);
+        let redlock = Redlock::new(Config {
+                                       retry_count: 3,
+                                       retry_delay: Duration::from_millis(100),
+                                       retry_jitter: 0,
+                                       min_validity: Duration::from_millis(500),
+                                       ..Config::default()
+                                   })
+                .unwrap();
+
+        // Granted, but too short to be valid
+        match redlock.lock(resource_name, Duration::from_millis(300)) {
+            Err(RedlockError::QuorumNotReached(_, _, _, _, _, timings)) => {
+                assert_eq!(timings.len(), 3);
+                for (index, timing) in timings.iter().enumerate() {
+                    assert_eq!(timing.votes, 1);
+                    if index > 0 {
+                        let previous = &timings[index - 1];
+                        assert!(timing.start >= previous.start + previous.duration + previous.sleep);
+                    }
+                }
+                assert_eq!(timings[0].sleep, Duration::from_millis(100));
+                assert_eq!(timings[1].sleep, Duration::from_millis(100));
+                assert_eq!(timings[2].sleep, Duration::from_millis(0));
+            }
+            res => panic!("expected QuorumNotReached, got {:?}", res),
+        }
+
+        // Held by another client, retried right away
+        let lock = redlock.lock(resource_name, ttl).unwrap();
+        match redlock.lock(resource_name, ttl) {
+            Err(RedlockError::QuorumNotReached(_, _, _, _, _, timings)) => {
+                assert_eq!(timings.len(), 3);
+                for timing in &timings {
+                    assert_eq!(timing.votes, 0);
+                    assert_eq!(timing.sleep, Duration::from_millis(0));
+                }
+            }
+            res => panic!("expected QuorumNotReached, got {:?}", res),
+        }
+        lock.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_lock_min_validity() {
+        let redlock = Redlock::new(Config {
+                                       retry_count: 2,
+                                       retry_delay: Duration::from_millis(100),
+                                       retry_jitter: 50,
+                                       min_validity: Duration::from_millis(500),
+                                       ..Config::default()
+                                   })
+                .unwrap();
+        let resource_name = "test_lock_min_validity";
+
+        assert!(redlock
+                    .lock(resource_name, Duration::from_millis(300))
+                    .is_err());
+        let lock = redlock
+            .lock(resource_name, Duration::from_millis(1000))
+            .unwrap();
+        assert!(lock.remaining_ttl().unwrap() > Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_lock_all_nodes_failed() {
+        let redlock = Redlock::new::<&str>(Config {
+                                               retry_count: 1,
+                                               retry_delay: Duration::from_millis(100),
+                                               retry_jitter: 50,
+                                               ..Config::with_addrs(vec!["redis://127.0.0.1:1",
+                                                                         "redis://127.0.0.1:1"])
+                                           })
+                .unwrap();
+
+        match redlock.lock("test_lock_all_nodes_failed", Duration::from_millis(1000)) {
+            Err(RedlockError::AllNodesFailed(err)) => {
+                assert!(matches!(*err, RedlockError::RedisError(_)));
+            }
+            _ => panic!("expected AllNodesFailed"),
+        }
+    }
+
+    #[test]
+    fn test_is_locked() {
+        let resource_name = "test_is_locked";
+        assert!(!REDLOCK.is_locked(resource_name).unwrap());
+        assert!(REDLOCK.owner(resource_name).unwrap().is_none());
+
+        let lock = REDLOCK
+            .lock(resource_name, Duration::from_millis(1000))
+            .unwrap();
+        assert!(REDLOCK.is_locked(resource_name).unwrap());
+        assert_eq!(REDLOCK.owner(resource_name).unwrap().unwrap(), lock.value);
+
+        lock.unlock().unwrap();
+        assert!(!REDLOCK.is_locked(resource_name).unwrap());
+    }
+
+    #[test]
+    fn test_is_locked_unreachable() {
+        let redlock = Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1",
+                                                           "redis://127.0.0.1:1",
+                                                           "redis://127.0.0.1:1"]))
+                .unwrap();
+        assert!(redlock.is_locked("test_is_locked_unreachable").is_err());
+    }
+
+    #[test]
+    fn test_lock_many() {
+        let one_second = Duration::from_millis(1000);
+        let locks = REDLOCK
+            .lock_many(&["test_lock_many_b", "test_lock_many_a", "test_lock_many_b"],
+                       one_second)
+            .unwrap();
+        assert_eq!(locks.len(), 2);
+        assert_eq!(locks[0].resource_name(), "test_lock_many_a");
+        assert_eq!(locks[1].resource_name(), "test_lock_many_b");
+
+        // The first resource is released once the second one fails
+        let redlock = Redlock::new(Config {
+                                       retry_count: 2,
+                                       retry_delay: Duration::from_millis(100),
+                                       retry_jitter: 50,
+                                       ..Config::default()
+                                   })
+                .unwrap();
+        assert!(redlock
+                    .lock_many(&["test_lock_many_b", "test_lock_many_0"], one_second)
+                    .is_err());
+        assert!(!redlock.is_locked("test_lock_many_0").unwrap());
+    }
+
+    #[test]
+    fn test_lock_many_concurrently() {
+        let redlock = Redlock::new(Config {
+                                       retry_count: 2,
+                                       retry_delay: Duration::from_millis(100),
+                                       retry_jitter: 50,
+                                       ..Config::default()
+                                   })
+                .unwrap();
+        let two_seconds = Duration::from_millis(2000);
+
+        let (res1, res2) = thread::scope(|scope| {
+            let handle1 = scope.spawn(|| {
+                redlock.lock_many(&["test_lock_many_concurrently_x",
+                                    "test_lock_many_concurrently_y"],
+                                  two_seconds)
+            });
+            let handle2 = scope.spawn(|| {
+                redlock.lock_many(&["test_lock_many_concurrently_y",
+                                    "test_lock_many_concurrently_x"],
+                                  two_seconds)
+            });
+            (handle1.join().unwrap(), handle2.join().unwrap())
+        });
+
+        assert!(res1.is_ok() != res2.is_ok());
+    }
+
+    #[test]
+    fn test_lock_aborts_without_quorum() {
+        // With 3 servers, 2 errors make the quorum of 2 unreachable, so the
+        // attempt is aborted and the lock on the first server released
+        let resource_name = "test_lock_aborts_without_quorum";
+        let redlock = Redlock::new(Config {
+                                       retry_count: 1,
+                                       ..Config::with_addrs(vec!["redis://127.0.0.1",
+                                                                 "redis://127.0.0.1:1",
+                                                                 "redis://127.0.0.1:1"])
+                                   })
+                .unwrap();
+        assert!(redlock
+                    .lock(resource_name, Duration::from_millis(2000))
+                    .is_err());
+
+        let res: Option<String> = REDIS_CLI
+            .get_connection()
+            .unwrap()
+            .get(resource_name)
+            .unwrap();
+        assert!(res.is_none());
+    }
+
+    #[test]
+    fn test_lock_with_hung_server() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let hung = format!("redis://{}", listener.local_addr().unwrap());
+        let redlock = Redlock::new(Config {
+                                       retry_count: 1,
+                                       command_timeout: Some(Duration::from_millis(100)),
+                                       ..Config::with_addrs(vec![String::from("redis://127.0.0.1"),
+                                                                 String::from("redis://127.0.0.1/1"),
+                                                                 hung])
+                                   })
+                .unwrap();
+
+        let start = Instant::now();
+        let _ = redlock.lock("test_lock_with_hung_server", Duration::from_millis(2000));
+        assert!(start.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_key_prefix() {
+        let resource_name = "test_key_prefix";
+        let one_second = Duration::from_millis(1000);
+        let redlock_a = Redlock::new(Config {
+                                         retry_count: 1,
+                                         key_prefix: String::from("service_a:"),
+                                         ..Config::default()
+                                     })
+                .unwrap();
+        let redlock_b = Redlock::new(Config {
+                                         retry_count: 1,
+                                         key_prefix: String::from("service_b:"),
+                                         ..Config::default()
+                                     })
+                .unwrap();
+
+        let lock_a = redlock_a.lock(resource_name, one_second).unwrap();
+        let lock_b = redlock_b.lock(resource_name, one_second).unwrap();
+        assert_eq!(lock_a.resource_name(), resource_name);
+        assert!(redlock_a.is_locked(resource_name).unwrap());
+
+        let mut con = redis::Client::open("redis://127.0.0.1")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let value: Option<String> = con.get("service_a:test_key_prefix").unwrap();
+        assert_eq!(value.as_deref(), Some(lock_a.value()));
+        let value: Option<String> = con.get(resource_name).unwrap();
+        assert!(value.is_none());
+
+        lock_a.extend(one_second).unwrap();
+        lock_a.unlock().unwrap();
+        lock_b.unlock().unwrap();
+        let value: Option<String> = con.get("service_b:test_key_prefix").unwrap();
+        assert!(value.is_none());
+    }
+
+    // A resolver giving the addresses of the given calls in turn, the last
+    // ones once they are exhausted, along with the number of calls.
+    fn mock_resolver(calls: Vec<Vec<&'static str>>) -> (AddrResolver, Arc<AtomicU64>) {
+        let count = Arc::new(AtomicU64::new(0));
+        let counted = count.clone();
+        let resolver: AddrResolver = Arc::new(move || {
+            let call = counted.fetch_add(1, Ordering::SeqCst) as usize;
+            calls[cmp::min(call, calls.len() - 1)]
+                .iter()
+                .map(|addr| addr.into_connection_info())
+                .collect()
+        });
+        (resolver, count)
+    }
+
+    #[test]
+    fn test_resolver_failover() {
+        let resource_name = "test_resolver_failover";
+        // The first server fails over from an unreachable master to a new one
+        let (resolver, calls) = mock_resolver(vec![vec!["redis://127.0.0.1:1",
+                                                        "redis://127.0.0.1:1",
+                                                        "redis://127.0.0.1/2"],
+                                                   vec!["redis://127.0.0.1/0",
+                                                        "redis://127.0.0.1/1",
+                                                        "redis://127.0.0.1/2"]]);
+        let redlock = Redlock::new(Config {
+                                       retry_count: 2,
+                                       retry_delay: Duration::from_millis(50),
+                                       retry_jitter: 0,
+                                       resolver: Some(resolver),
+                                       ..Config::with_addrs(vec!["redis://127.0.0.1:1"])
+                                   })
+                .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(redlock.nodes.len(), 3);
+
+        let lock = redlock
+            .lock(resource_name, Duration::from_millis(1000))
+            .unwrap();
+        assert_eq!(lock.attempts(), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // The addresses are left as is while the servers are reachable
+        lock.unlock().unwrap();
+        assert_eq!(redlock.resolve_addrs().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_resolver_node_count() {
+        let (resolver, _) = mock_resolver(vec![vec!["redis://127.0.0.1/0"],
+                                               vec!["redis://127.0.0.1/0", "redis://127.0.0.1/1"]]);
+        let redlock = Redlock::builder()
+            .add_address("redis://127.0.0.1:1")
+            .resolver(move || resolver())
+            .build()
+            .unwrap();
+        match redlock.resolve_addrs() {
+            Err(RedlockError::ResolvedNodeCount(1, 2)) => {}
+            res => panic!("expected ResolvedNodeCount, got {:?}", res),
+        }
+        // Without a resolver, there is nothing to resolve
+        assert_eq!(Redlock::new(Config::default()).unwrap().resolve_addrs().unwrap(), 0);
+
+        let res = Redlock::new(Config {
+                                   resolver: Some(Arc::new(|| {
+            Err(redis::RedisError::from((redis::ErrorKind::IoError, "sentinel down")))
+        })),
+                                   ..Config::default()
+                               });
+        assert!(matches!(res, Err(RedlockError::ResolveFailed(_))));
+    }
+
+    #[test]
+    fn test_key_mapper() {
+        let resource_name = "test_key_mapper/a very long resource name, which is hashed";
+        let key = format!("mapped:{}", sha1_smol::Sha1::from(resource_name).digest());
+        let redlock = Redlock::new(Config {
+                                       retry_count: 1,
+                                       key_prefix: String::from("mapped:"),
+                                       key_mapper: Some(Arc::new(|resource_name: &str| {
+                                           sha1_smol::Sha1::from(resource_name)
+                                               .digest()
+                                               .to_string()
+                                       })),
+                                       ..Config::default()
+                                   })
+                .unwrap();
+
+        let lock = redlock.lock(resource_name, Duration::from_millis(1000)).unwrap();
+        assert_eq!(lock.resource_name(), resource_name);
+        let mut con = redis::Client::open("redis://127.0.0.1")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let value: Option<String> = con.get(&key).unwrap();
+        assert_eq!(value.as_deref(), Some(lock.value()));
+        assert!(redlock.is_locked(resource_name).unwrap());
+
+        let lock = lock.extend(Duration::from_millis(1000)).unwrap();
+        lock.unlock().unwrap();
+        let value: Option<String> = con.get(&key).unwrap();
+        assert!(value.is_none());
+    }
+
+    #[test]
+    fn test_lock_pttl() {
+        let resource_name = "test_lock_pttl";
+        let redlock = Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1/0",
+                                                           "redis://127.0.0.1/1",
+                                                           "redis://127.0.0.1/2"]))
+                .unwrap();
+        let lock = redlock.lock(resource_name, Duration::from_millis(10000)).unwrap();
+
+        let mut con = redis::Client::open("redis://127.0.0.1/0")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let _: () = con.pexpire(resource_name, 5000).unwrap();
+        let pttl = lock.pttl().unwrap().unwrap();
+        assert!(pttl > Duration::from_millis(5000) && pttl <= Duration::from_millis(10000));
+
+        // Still owned on a quorum of two servers, the lock expires with the
+        // first of them
+        let mut con = redis::Client::open("redis://127.0.0.1/1")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let _: () = con.set(resource_name, "other").unwrap();
+        let pttl = lock.pttl().unwrap().unwrap();
+        assert!(pttl <= Duration::from_millis(5000));
+        let mut con = redis::Client::open("redis://127.0.0.1/2")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let _: () = con.del(resource_name).unwrap();
+        // Not owned on a quorum of servers anymore
+        assert!(lock.pttl().unwrap().is_none());
+
+        lock.unlock_detailed();
+        let mut con = redis::Client::open("redis://127.0.0.1/1")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let _: () = con.del(resource_name).unwrap();
+    }
+
+    #[test]
+    fn test_lock_is_valid() {
+        let resource_name = "test_lock_is_valid";
+        let redlock = Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1/0",
+                                                           "redis://127.0.0.1/1",
+                                                           "redis://127.0.0.1/2"]))
+                .unwrap();
+        let lock = redlock.lock(resource_name, Duration::from_millis(2000)).unwrap();
+        assert!(lock.is_valid().unwrap());
+
+        // Still held on a quorum of servers
+        let mut con = redis::Client::open("redis://127.0.0.1/1")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let _: () = con.set(resource_name, "other").unwrap();
+        assert!(lock.is_valid().unwrap());
+
+        let mut con = redis::Client::open("redis://127.0.0.1/2")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let _: () = con.del(resource_name).unwrap();
+        assert!(!lock.is_valid().unwrap());
+
+        lock.unlock_detailed();
+        let mut con = redis::Client::open("redis://127.0.0.1/1")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let _: () = con.del(resource_name).unwrap();
+    }
+
+    #[test]
+    fn test_unlock_detailed() {
+        let resource_name = "test_unlock_detailed";
+        let one_second = Duration::from_millis(1000);
+        let redlock = Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1/0",
+                                                           "redis://127.0.0.1/1",
+                                                           "redis://127.0.0.1/2"]))
+                .unwrap();
+        let lock = redlock.lock(resource_name, one_second).unwrap();
+
+        let mut con = redis::Client::open("redis://127.0.0.1/1")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let _: () = con.del(resource_name).unwrap();
+        let mut con = redis::Client::open("redis://127.0.0.1/2")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let _: () = con.set(resource_name, "other").unwrap();
+
+        let report = lock.unlock_detailed();
+        assert_eq!((report.deleted, report.not_present, report.held_by_other, report.errors),
+                   (1, 1, 1, 0));
+        let outcomes: Vec<UnlockOutcome> = report
+            .node_results
+            .into_iter()
+            .map(|outcome| outcome.unwrap())
+            .collect();
+        assert_eq!(outcomes,
+                   vec![UnlockOutcome::Released,
+                        UnlockOutcome::NotPresent,
+                        UnlockOutcome::HeldByOther]);
+        let _: () = con.del(resource_name).unwrap();
+    }
+
+    #[test]
+    fn test_unlock_detailed_straggler() {
+        let resource_name = "test_unlock_detailed_straggler";
+        let redlock = Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1/0",
+                                                           "redis://127.0.0.1/1",
+                                                           "redis://127.0.0.1/2"]))
+                .unwrap();
+        let lock = redlock.lock(resource_name, Duration::from_millis(2000)).unwrap();
+
+        // The same lock seen through a partition: the third server is
+        // unreachable and still holds the lock
+        let partitioned = Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1/0",
+                                                               "redis://127.0.0.1/1",
+                                                               "redis://127.0.0.1:1"]))
+                .unwrap();
+        let report = partitioned.unlock_report(resource_name, lock.value());
+        assert_eq!((report.deleted, report.not_present, report.held_by_other, report.errors),
+                   (2, 0, 0, 1));
+        assert!(report.node_results[2].is_err());
+
+        let mut con = redis::Client::open("redis://127.0.0.1/2")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let straggler: Option<String> = con.get(resource_name).unwrap();
+        assert_eq!(straggler.as_deref(), Some(lock.value()));
+
+        let report = lock.unlock_detailed();
+        assert_eq!((report.deleted, report.not_present, report.errors), (1, 2, 0));
+    }
+
+    #[test]
+    fn test_unlock() {
+        let resource_name = "test_unlock";
+        let lock = REDLOCK
+            .lock(resource_name, Duration::from_millis(2000))
+            .unwrap();
+
+        let value: String = REDIS_CLI
+            .get_connection()
+            .unwrap()
+            .get(resource_name)
+            .unwrap();
+        assert_eq!(value.len(), 40);
+
+        lock.unlock().unwrap();
+        let res: Option<String> = REDIS_CLI
+            .get_connection()
+            .unwrap()
+            .get(resource_name)
+            .unwrap();
+        assert!(res.is_none());
+    }
+
+    #[test]
+    fn test_extend() {
+        let resource_name = "test_extend";
+        let lock = REDLOCK
+            .lock(resource_name, Duration::from_millis(2000))
+            .unwrap();
+        let lock_extended = lock.extend(Duration::from_millis(2000)).unwrap();
+
+        assert!(lock_extended.expiration < SystemTime::now().add(Duration::from_millis(2000)));
+    }
+
+    #[test]
+    fn test_transfer() {
+        let resource_name = "test_transfer";
+        let ttl = Duration::from_millis(2000);
+        let redlock = Redlock::new(Config {
+                                       retry_count: 2,
+                                       retry_delay: Duration::from_millis(100),
+                                       retry_jitter: 0,
+                                       unlock_retry_count: Some(1),
+                                       ..Config::with_addrs(vec!["redis://127.0.0.1/0",
+                                                                 "redis://127.0.0.1/1",
+                                                                 "redis://127.0.0.1/2"])
+                                   })
+                .unwrap();
+        let lock = redlock.lock(resource_name, ttl).unwrap();
+        let transferred = lock.transfer("new owner", ttl).unwrap();
+        assert_eq!(transferred.value(), "new owner");
+        assert_eq!(transferred.resource_name(), resource_name);
+
+        // The old value can no longer unlock nor transfer the lock
+        match lock.unlock() {
+            Err(RedlockError::UnableToUnlock(..)) => {}
+            res => panic!("expected UnableToUnlock, got {:?}", res),
+        }
+        match lock.transfer("thief", ttl) {
+            Err(RedlockError::UnableToTransfer(_, 0, 2, 2)) => {}
+            res => panic!("expected UnableToTransfer, got {:?}", res),
+        }
+        assert!(transferred.is_valid().unwrap());
+        transferred.unlock().unwrap();
+        assert!(!redlock.is_locked(resource_name).unwrap());
+    }
+
+    #[test]
+    fn test_transfer_to_empty_value() {
+        let lock = REDLOCK
+            .lock("test_transfer_to_empty_value", Duration::from_millis(2000))
+            .unwrap();
+        assert!(matches!(lock.transfer("", Duration::from_millis(2000)),
+                         Err(RedlockError::InvalidValue)));
+        lock.unlock().unwrap();
+    }
+
+    // The hash of a lock ignores its Redlock, which is mutable inside
+    #[allow(clippy::mutable_key_type)]
+    #[test]
+    fn test_lock_eq() {
+        let resource_name = "test_lock_eq";
+        let ttl = Duration::from_millis(2000);
+        let redlock = Redlock::new(Config::default()).unwrap();
+        let lock = redlock.lock(resource_name, ttl).unwrap();
+        let extended = lock.extend(ttl).unwrap();
+        assert_eq!(lock, extended);
+
+        let mut locks = HashSet::new();
+        assert!(locks.insert(lock));
+        assert!(!locks.insert(extended));
+        assert_eq!(locks.len(), 1);
+
+        let other = redlock.lock("test_lock_eq_other", ttl).unwrap();
+        assert!(!locks.contains(&other));
+        locks.insert(other);
+        assert_eq!(locks.len(), 2);
+        for lock in &locks {
+            lock.unlock().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_single_node() {
+        let resource_name = "test_single_node";
+        let ttl = Duration::from_millis(2000);
+        let single = Redlock::new(Config {
+                                      retry_count: 2,
+                                      retry_delay: Duration::from_millis(100),
+                                      retry_jitter: 0,
+                                      ..Config::default()
+                                  })
+                .unwrap();
+        assert!(single.is_single_node());
+        assert_eq!(single.quorum(), 1);
+        assert!(!Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1/0",
+                                                      "redis://127.0.0.1/1"]))
+                         .unwrap()
+                         .is_single_node());
+
+        let lock = single.lock(resource_name, ttl).unwrap();
+        assert_eq!(lock.attempts(), 1);
+        match single.lock(resource_name, ttl) {
+            Err(RedlockError::QuorumNotReached(_, 0, 1, _, _, _)) => {}
+            res => panic!("expected QuorumNotReached, got {:?}", res),
+        }
+
+        let extended = lock.extend(ttl).unwrap();
+        assert_eq!(extended.value(), lock.value());
+        assert!(extended.pttl().unwrap().is_some());
+
+        extended.unlock().unwrap();
+        assert!(!single.is_held(resource_name, lock.value()).unwrap());
+        match lock.extend(ttl) {
+            Err(RedlockError::UnableToExtend(..)) => {}
+            res => panic!("expected UnableToExtend, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_extend_keeps_lock() {
+        let resource_name = "test_extend_keeps_lock";
+        let addrs = vec!["redis://127.0.0.1/0", "redis://127.0.0.1/1", "redis://127.0.0.1/2"];
+        let redlock = Redlock::new(Config::with_addrs(addrs)).unwrap();
+        let lock = redlock
+            .lock(resource_name, Duration::from_millis(1000))
+            .unwrap();
+
+        let mut extended = lock.extend(Duration::from_millis(2000)).unwrap();
+        for _ in 0..3 {
+            extended = extended.extend(Duration::from_millis(2000)).unwrap();
+            assert_eq!(extended.resource_name(), resource_name);
+            assert_eq!(extended.value(), lock.value());
+            assert_eq!(extended.attempts(), 1);
+        }
+        assert!(extended.expiration() > lock.expiration());
+        extended.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_expiration_jitter() {
+        let resource_name = "test_expiration_jitter";
+        let ttl = Duration::from_millis(1000);
+        let redlock = Redlock::new(Config {
+                                       retry_count: 1,
+                                       expiration_jitter: Duration::from_millis(400),
+                                       ..Config::default()
+                                   })
+                .unwrap();
+        let mut con = redis::Client::open("redis://127.0.0.1")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+
+        let mut pttls = HashSet::new();
+        for _ in 0..10 {
+            let lock = redlock.lock(resource_name, ttl).unwrap();
+            let pttl: u64 = con.pttl(resource_name).unwrap();
+            // The validity never outlives the key on redis
+            let remaining_ttl = lock.remaining_ttl().unwrap();
+            assert!(remaining_ttl <= Duration::from_millis(pttl + 1));
+            assert!(pttl <= 1000 && pttl > 550);
+            pttls.insert(pttl);
+            lock.unlock().unwrap();
+        }
+        assert!(pttls.len() > 1);
+
+        // Never more than half of the TTL
+        assert_eq!(redlock.jitter_ttl(Duration::from_millis(0)), Duration::from_millis(0));
+        assert!(redlock.jitter_ttl(Duration::from_millis(100)) >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_extend_in_place() {
+        let resource_name = "test_extend_in_place";
+        let mut lock = REDLOCK
+            .lock(resource_name, Duration::from_millis(300))
+            .unwrap();
+        let value = String::from(lock.value());
+
+        // Outlives the first TTL by renewing the same lock
+        for _ in 0..4 {
+            thread::sleep(Duration::from_millis(100));
+            let expiration = lock.expiration();
+            lock.extend_in_place(Duration::from_millis(300)).unwrap();
+            assert!(lock.expiration() > expiration);
+            assert_eq!(lock.value(), value);
+        }
+        assert!(lock.is_valid().unwrap());
+        lock.unlock().unwrap();
+
+        // A failed extension leaves the lock as is
+        let expiration = lock.expiration();
+        assert!(lock.extend_in_place(Duration::from_millis(300)).is_err());
+        assert_eq!(lock.expiration(), expiration);
+    }
+
+    #[test]
+    fn test_data_key() {
+        assert_eq!(data_key("resource"), "{resource}:data");
+        assert_eq!(data_key("user:{42}:leader"), "user:{42}:leader:data");
+        let redlock = Redlock::builder()
+            .add_address("redis://127.0.0.1")
+            .key_prefix("app:")
+            .build()
+            .unwrap();
+        assert_eq!(redlock.data_key("leader"), "{app:leader}:data");
+    }
+
+    #[test]
+    fn test_extend_with_data() {
+        let resource_name = "test_extend_with_data";
+        let ttl = Duration::from_millis(2000);
+        let new_redlock = || {
+            Redlock::new(Config {
+                             retry_count: 1,
+                             ..Config::default()
+                         })
+                    .unwrap()
+        };
+        let redlock = new_redlock();
+        let leader = redlock.lock(resource_name, ttl).unwrap();
+        let leader = leader
+            .extend_with_data(ttl, b"10.0.0.1:7000")
+            .unwrap();
+
+        // A follower finds the leader while it holds the lock
+        let follower = new_redlock();
+        assert!(follower.lock(resource_name, ttl).is_err());
+        let mut con = redis::Client::open("redis://127.0.0.1")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let data_key = follower.data_key(resource_name);
+        let data: Vec<u8> = con.get(&data_key).unwrap();
+        assert_eq!(data, b"10.0.0.1:7000");
+        let pttl: i64 = con.pttl(&data_key).unwrap();
+        assert!(pttl > 1900 && pttl <= 2000);
+
+        let leader = leader
+            .extend_with_data(ttl, b"10.0.0.1:7001")
+            .unwrap();
+        let data: Vec<u8> = con.get(&data_key).unwrap();
+        assert_eq!(data, b"10.0.0.1:7001");
+
+        // Once the lock is lost, the data is not updated anymore
+        leader.unlock().unwrap();
+        match leader.extend_with_data(ttl, b"10.0.0.1:7002") {
+            Err(RedlockError::UnableToExtend(..)) => {}
+            res => panic!("expected UnableToExtend, got {:?}", res),
+        }
+        let data: Vec<u8> = con.get(&data_key).unwrap();
+        assert_eq!(data, b"10.0.0.1:7001");
+        let _: () = con.del(&data_key).unwrap();
+    }
+
+    #[test]
+    fn test_extend_if_below() {
+        let resource_name = "test_extend_if_below";
+        let clock = TestClock::new();
+        let redlock = Redlock::new(Config::default()).unwrap().with_clock(clock.clone());
+        let lock = redlock
+            .lock(resource_name, Duration::from_millis(2000))
+            .unwrap();
+
+        // Plenty of time is left, the servers are not requested
+        let threshold = Duration::from_millis(500);
+        assert!(lock.extend_if_below(threshold, Duration::from_millis(2000))
+                    .unwrap()
+                    .is_none());
+
+        // The threshold counts from the expiration with the drift subtracted
+        let drift = redlock.get_drift(&Duration::from_millis(2000));
+        clock.advance(Duration::from_millis(2000) - drift - threshold);
+        assert!(lock.extend_if_below(threshold, Duration::from_millis(2000))
+                    .unwrap()
+                    .is_none());
+        clock.advance(Duration::from_millis(1));
+        let extended = lock.extend_if_below(threshold, Duration::from_millis(2000))
+            .unwrap()
+            .unwrap();
+        assert!(extended.expiration() > lock.expiration());
+        assert!(extended.remaining_ttl().unwrap() > threshold);
+        assert!(extended.extend_if_below(threshold, Duration::from_millis(2000))
+                    .unwrap()
+                    .is_none());
+
+        // An expired lock is not extended
+        clock.advance(Duration::from_millis(2000));
+        assert!(matches!(extended.extend_if_below(threshold, Duration::from_millis(2000)),
+                         Err(RedlockError::LockExpired)));
+        extended.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_extend_from_now() {
+        let resource_name = "test_extend_from_now";
+        let clock = TestClock::new();
+        let redlock = Redlock::new(Config::default()).unwrap().with_clock(clock.clone());
+        let lock = redlock
+            .lock(resource_name, Duration::from_millis(1000))
+            .unwrap();
+        clock.advance(Duration::from_millis(500));
+
+        let lock_extended = lock.extend(Duration::from_millis(1000)).unwrap();
+        let drift = redlock.get_drift(&Duration::from_millis(1000));
+        assert_eq!(lock_extended.expiration, clock.now() + Duration::from_millis(1000) - drift);
+        lock_extended.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_extend_without_quorum() {
+        let resource_name = "test_extend_without_quorum";
+        let addrs = vec!["redis://127.0.0.1/0", "redis://127.0.0.1/1", "redis://127.0.0.1/2"];
+        let redlock = Redlock::new(Config {
+                                       retry_count: 2,
+                                       retry_delay: Duration::from_millis(100),
+                                       retry_jitter: 50,
+                                       ..Config::with_addrs(addrs)
+                                   })
+                .unwrap();
+        let lock = redlock
+            .lock(resource_name, Duration::from_millis(2000))
+            .unwrap();
+
+        // Lose the lock on two of the three servers
+        for db in &["redis://127.0.0.1/1", "redis://127.0.0.1/2"] {
+            let _: () = redis::Client::open(*db)
+                .unwrap()
+                .get_connection()
+                .unwrap()
+                .del(resource_name)
+                .unwrap();
         }
 
-        Ok(self.redlock.extend(&self.resource_name, &self.value, ttl)?)
+        match lock.extend(Duration::from_millis(2000)) {
+            Err(RedlockError::UnableToExtend(..)) => {}
+            _ => panic!("expected UnableToExtend"),
+        }
     }
-}
-
-// Configuration of Redlock
-pub struct Config<T>
-    where T: redis::IntoConnectionInfo
-{
-    pub addrs: Vec<T>,
-    pub retry_count: u32,
-    pub retry_delay: Duration,
-    pub retry_jitter: u32,
-    pub drift_factor: f32,
-}
 
-impl Default for Config<&'static str> {
-    fn default() -> Self {
-        Config {
-            addrs: vec!["redis://127.0.0.1"],
-            retry_count: 10,
-            retry_delay: Duration::from_millis(400),
-            retry_jitter: 400,
-            drift_factor: 0.01,
+    #[test]
+    fn test_extend_expired_resource() {
+        let one_second = Duration::from_millis(1000);
+        let resource_name = "test_extend_expired_resource";
+        let clock = TestClock::new();
+        let redlock = Redlock::new(Config::default()).unwrap().with_clock(clock.clone());
+        let lock = redlock.lock(resource_name, one_second).unwrap();
+        clock.advance(one_second * 2);
+        match lock.extend(one_second) {
+            Err(RedlockError::LockExpired) => {}
+            res => panic!("expected LockExpired, got {:?}", res),
         }
+        lock.unlock().unwrap();
     }
-}
 
-#[derive(Debug)]
-pub struct Redlock {
-    clients: Vec<redis::Client>,
-    retry_count: u32,
-    retry_delay: Duration,
-    retry_jitter: u32,
-    drift_factor: f32,
-    quorum: usize,
-}
+    #[test]
+    fn test_lock_reuses_connections() {
+        let resource_name = "test_lock_reuses_connections";
+        let redlock = Redlock::new(Config::default()).unwrap();
 
-impl Redlock {
-    // Create a new redlock instance.
-    pub fn new<T: redis::IntoConnectionInfo>(config: Config<T>) -> RedlockResult<Redlock> {
-        if config.addrs.is_empty() {
-            return Err(RedlockError::NoServerError);
+        for _ in 0..100 {
+            redlock
+                .lock(resource_name, Duration::from_millis(1000))
+                .unwrap()
+                .unlock()
+                .unwrap();
         }
-        let mut clients = Vec::with_capacity(config.addrs.len());
-        for addr in config.addrs {
-            clients.push(redis::Client::open(addr)?)
+        match redlock.nodes[0] {
+            Node::Redis(ref pool) => assert_eq!(pool.idle_count(), 1),
+            Node::Backend(_) => panic!("expected a redis server"),
         }
+    }
 
-        let quorum = (clients.len() as f64 / 2_f64).floor() as usize + 1;
+    #[test]
+    fn test_quorum() {
+        let redlock = Redlock::new(Config::default()).unwrap();
+        assert_eq!(redlock.node_count(), 1);
+        assert_eq!(redlock.quorum(), 1);
 
-        Ok(Redlock {
-               clients,
-               retry_count: config.retry_count,
-               retry_delay: config.retry_delay,
-               retry_jitter: config.retry_jitter,
-               drift_factor: config.drift_factor,
-               quorum,
-           })
-    }
+        let redlock = Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1"; 4])).unwrap();
+        assert_eq!(redlock.node_count(), 4);
+        assert_eq!(redlock.quorum(), 3);
 
-    // Locks the given resource using the Redlock algorithm.
-    pub fn lock(&self, resource_name: &str, ttl: Duration) -> RedlockResult<Lock> {
-        self.request(RequestInfo::Lock, resource_name, ttl)
+        let redlock = Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1"; 5])).unwrap();
+        assert_eq!(redlock.node_count(), 5);
+        assert_eq!(redlock.quorum(), 3);
     }
 
-    fn extend(&self, resource_name: &str, value: &str, ttl: Duration) -> RedlockResult<Lock> {
-        self.request(RequestInfo::Extend { resource_value: value },
-                     resource_name,
-                     ttl)
+    #[test]
+    fn test_health_check() {
+        let redlock = Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1",
+                                                           "redis://127.0.0.1:1",
+                                                           "redis://127.0.0.1"]))
+                .unwrap();
+        let results = redlock.health_check();
+        assert_eq!(results.len(), 3);
+        assert!(results[0].1.is_ok());
+        assert!(results[1].1.is_err());
+        assert_eq!(results[2].0, 2);
+        assert!(redlock.has_quorum_available());
+
+        let redlock = Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1",
+                                                           "redis://127.0.0.1:1",
+                                                           "redis://127.0.0.1:1"]))
+                .unwrap();
+        assert!(!redlock.has_quorum_available());
     }
 
-    fn request(&self,
-               info: RequestInfo,
-               resource_name: &str,
-               ttl: Duration)
-               -> RedlockResult<(Lock)> {
-        let mut attempts = 0;
-        let drift = Duration::from_millis((self.drift_factor as f64 *
-                                           util::num_milliseconds(&ttl) as f64)
-                                                  .round() as
-                                          u64 + 2);
+    #[test]
+    fn test_clone() {
+        let resource_name = "test_clone";
+        let one_second = Duration::from_millis(1000);
+        let redlock = Redlock::new(Config::default()).unwrap();
+        let cloned = redlock.clone();
+        assert_eq!(cloned.quorum, redlock.quorum);
+        assert_eq!(cloned.nodes.len(), redlock.nodes.len());
 
-        'attempts: while attempts < self.retry_count {
-            attempts += 1;
+        let lock = cloned.lock(resource_name, one_second).unwrap();
+        assert!(redlock.lock(resource_name, one_second).is_err());
+        lock.unlock().unwrap();
+        assert!(redlock.lock(resource_name, one_second).is_ok());
+    }
 
-            // Start time of this attempt
-            let start = SystemTime::now();
+    #[test]
+    fn test_lock_owned() {
+        let resource_name = "test_lock_owned";
+        let redlock = Redlock::new(Config::default()).unwrap().into_arc();
+        let lock = redlock
+            .lock_owned(resource_name, Duration::from_millis(2000))
+            .unwrap();
+        assert_eq!(lock.resource_name(), resource_name);
 
-            let mut waitings = self.clients.len();
-            let mut votes = 0;
-            let mut errors = 0;
+        let handle = thread::spawn(move || {
+            let lock = lock.extend(Duration::from_millis(2000)).unwrap();
+            lock.unlock()
+        });
+        handle.join().unwrap().unwrap();
 
-            let value: String = match info {
-                RequestInfo::Lock => util::get_random_string(32),
-                RequestInfo::Extend { resource_value } => String::from(resource_value),
-            };
+        let res: Option<String> = REDIS_CLI
+            .get_connection()
+            .unwrap()
+            .get(resource_name)
+            .unwrap();
+        assert!(res.is_none());
+    }
 
-            for client in &self.clients {
-                let request_result = match info {
-                    RequestInfo::Lock => lock(client, resource_name, &value, &ttl),
-                    RequestInfo::Extend { .. } => extend(client, resource_name, &value, &ttl),
-                };
-
-                let lock = Lock {
-                    redlock: self,
-                    resource_name: String::from(resource_name),
-                    value: value.clone(),
-                    expiration: start + ttl - drift,
-                };
-
-                match request_result {
-                    Ok(success) => {
-                        waitings -= 1;
-                        if !success {
-                            continue;
-                        }
+    #[derive(Default)]
+    struct CountingObserver {
+        attempts: Arc<AtomicU64>,
+        acquired: Arc<AtomicU64>,
+        failed: Arc<AtomicU64>,
+        node_errors: Arc<Mutex<Vec<(usize, String)>>>,
+    }
 
-                        votes += 1;
-                        if waitings > 0 {
-                            continue;
-                        }
-                        // suceess: aquire the lock
-                        if votes >= self.quorum && lock.expiration > SystemTime::now() {
-                            return Ok(lock);
-                        }
+    impl Observer for CountingObserver {
+        fn on_attempt(&self, _resource_name: &str, _attempt: u32) {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+        }
 
-                        // fail: releases all aquired locks and retry
-                        lock.unlock().is_ok(); // Just ingore the result
-                        thread::sleep(self.get_retry_timeout());
-                        continue 'attempts;
-                    }
-                    Err(_) => {
-                        errors += 1;
-                        // This attempt is doomed to fail, will retry after
-                        // the timeout
-                        if errors > self.quorum {
-                            lock.unlock().is_ok(); // Just ingore the result
-                            thread::sleep(self.get_retry_timeout());
-                            continue 'attempts;
-                        }
-                    }
-                }
-            }
+        fn on_acquired(&self, _resource_name: &str, attempts: u32, _elapsed: Duration) {
+            assert_eq!(attempts, 1);
+            self.acquired.fetch_add(1, Ordering::SeqCst);
         }
 
-        // Exceed the retry count, return the error
-        match info {
-            RequestInfo::Lock => Err(RedlockError::UnableToLock),
-            RequestInfo::Extend { .. } => Err(RedlockError::UnableToExtend),
+        fn on_failed(&self, _resource_name: &str, _attempts: u32) {
+            self.failed.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_node_error(&self, index: usize, label: &str, _err: &RedlockError) {
+            self.node_errors.lock().unwrap().push((index, String::from(label)));
         }
     }
 
-    fn unlock(&self, resource_name: &str, value: &str) -> RedlockResult<()> {
-        let mut attempts = 0;
+    #[test]
+    fn test_observer() {
+        let resource_name = "test_observer";
+        let observer = CountingObserver::default();
+        let (attempts, acquired, failed) =
+            (observer.attempts.clone(), observer.acquired.clone(), observer.failed.clone());
+        let redlock = Redlock::new(Config::default()).unwrap().with_observer(observer);
 
-        'attempts: while attempts < self.retry_count {
-            attempts += 1;
+        let lock = redlock.lock(resource_name, Duration::from_millis(1000)).unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        assert_eq!(acquired.load(Ordering::SeqCst), 1);
 
-            let mut waitings = self.clients.len();
-            let mut votes = 0;
-            let mut errors = 0;
+        assert!(redlock.try_lock(resource_name, Duration::from_millis(1000)).unwrap().is_none());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert_eq!(failed.load(Ordering::SeqCst), 1);
+        lock.unlock().unwrap();
+    }
 
-            for client in &self.clients {
-                match unlock(client, resource_name, value) {
-                    Ok(success) => {
-                        waitings -= 1;
-                        if !success {
-                            continue;
-                        }
+    #[test]
+    fn test_observer_node_error() {
+        let observer = CountingObserver::default();
+        let node_errors = observer.node_errors.clone();
+        let redlock = Redlock::new::<&str>(Config::with_addrs(vec!["redis://127.0.0.1",
+                                                                   "redis://127.0.0.1:1",
+                                                                   "redis://127.0.0.1"]))
+                .unwrap()
+                .with_observer(observer);
 
-                        votes += 1;
-                        if waitings > 0 {
-                            continue;
-                        }
-                        if votes >= self.quorum {
-                            return Ok(());
-                        }
-                    }
-                    Err(_) => {
-                        errors += 1;
-                        // This attempt is doomed to fail, will retry after
-                        // the timeout
-                        if errors >= self.quorum {
-                            thread::sleep(self.get_retry_timeout());
-                            continue 'attempts;
-                        }
-                    }
-                }
+        let _ = redlock.try_lock("test_observer_node_error", Duration::from_millis(1000));
+        assert_eq!(*node_errors.lock().unwrap(), vec![(1, String::from("1"))]);
+    }
+
+    #[derive(Default)]
+    struct FirstNodeObserver {
+        requests: AtomicU64,
+        first_nodes: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Observer for FirstNodeObserver {
+        fn on_node_request(&self, index: usize, _label: &str) {
+            // Every request contacts the 3 servers
+            if self.requests.fetch_add(1, Ordering::SeqCst).is_multiple_of(3) {
+                self.first_nodes.lock().unwrap().push(index);
             }
         }
+    }
 
-        // Exceed the retry count, return the error
-        Err(RedlockError::UnableToUnlock)
+    #[derive(Default)]
+    struct RequestCounter {
+        requests: Arc<AtomicU64>,
     }
 
-    fn get_retry_timeout(&self) -> Duration {
-        let jitter = self.retry_jitter as i32 * thread_rng().gen_range(-1, 1);
-        if jitter >= 0 {
-            self.retry_delay.add(Duration::from_millis(jitter as u64))
-        } else {
-            self.retry_delay.sub(Duration::from_millis(-jitter as u64))
+    impl Observer for RequestCounter {
+        fn on_node_request(&self, _index: usize, _label: &str) {
+            self.requests.fetch_add(1, Ordering::SeqCst);
         }
     }
-}
 
-fn lock(client: &redis::Client,
-        resource_name: &str,
-        value: &str,
-        ttl: &Duration)
-        -> RedlockResult<bool> {
-    match LOCK.key(String::from(resource_name))
-              .arg(String::from(value))
-              .arg(util::num_milliseconds(ttl))
-              .invoke::<Option<()>>(&client.get_connection()?)? {
-        Some(_) => Ok(true),
-        _ => Ok(false),
+    // Try to lock the resource while a server is unreachable, and another
+    // one is held by someone else until it expires, returns the number of
+    // requests sent to the servers.
+    fn count_partial_retry_requests(resource_name: &str, partial_retry: PartialRetryPolicy) -> u64 {
+        let observer = RequestCounter::default();
+        let requests = observer.requests.clone();
+        let redlock = Redlock::new(Config {
+                                       retry_count: 3,
+                                       retry_delay: Duration::from_millis(200),
+                                       retry_jitter: 0,
+                                       partial_retry,
+                                       ..Config::with_addrs(vec!["redis://127.0.0.1/0",
+                                                                 "redis://127.0.0.1/1",
+                                                                 "redis://127.0.0.1:1"])
+                                   })
+                .unwrap()
+                .with_observer(observer);
+
+        let mut con = redis::Client::open("redis://127.0.0.1/1")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let _: () = redis::cmd("SET")
+            .arg(resource_name)
+            .arg("other")
+            .arg("PX")
+            .arg(100)
+            .query(&mut con)
+            .unwrap();
+
+        let lock = redlock.lock(resource_name, Duration::from_millis(5000));
+        let count = requests.load(Ordering::SeqCst);
+        if let Ok(lock) = lock {
+            lock.unlock().unwrap();
+        }
+        count
     }
-}
 
-fn unlock(client: &redis::Client, resource_name: &str, value: &str) -> RedlockResult<bool> {
-    match UNLOCK
-              .key(resource_name)
-              .arg(value)
-              .invoke::<i32>(&client.get_connection()?)? {
-        1 => Ok(true),
-        _ => Ok(false),
+    #[test]
+    fn test_partial_retry_policy() {
+        // Released from the server which granted it, then requested again
+        // on every server
+        let reset = count_partial_retry_requests("test_partial_retry_reset",
+                                                 PartialRetryPolicy::Reset);
+        assert!(reset >= 7);
+
+        // Only the 2 other servers are requested again
+        let retry_others =
+            count_partial_retry_requests("test_partial_retry_others",
+                                         PartialRetryPolicy::RetryOthers {
+                                             retries: 2,
+                                             delay: Duration::from_millis(200),
+                                         });
+        assert_eq!(retry_others, 5);
+        assert!(retry_others < reset);
     }
-}
 
-fn extend(client: &redis::Client,
-          resource_name: &str,
-          value: &str,
-          ttl: &Duration)
-          -> RedlockResult<bool> {
-    match EXTEND
-              .key(resource_name)
-              .arg(value)
-              .arg(util::num_milliseconds(ttl))
-              .invoke::<i32>(&client.get_connection()?)? {
-        1 => Ok(true),
-        _ => Ok(false),
+    #[test]
+    fn test_partial_retry_gives_up() {
+        let resource_name = "test_partial_retry_gives_up";
+        let redlock = Redlock::new(Config {
+                                       retry_count: 1,
+                                       partial_retry: PartialRetryPolicy::RetryOthers {
+                                           retries: 2,
+                                           delay: Duration::from_millis(10),
+                                       },
+                                       ..Config::with_addrs(vec!["redis://127.0.0.1/0",
+                                                                 "redis://127.0.0.1/1",
+                                                                 "redis://127.0.0.1/2"])
+                                   })
+                .unwrap();
+        let other = redlock.lock(resource_name, Duration::from_millis(2000)).unwrap();
+        let mut con = redis::Client::open("redis://127.0.0.1/0")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let _: () = con.del(resource_name).unwrap();
+
+        // The first server is released after the retries
+        assert!(redlock.try_lock(resource_name, Duration::from_millis(2000)).unwrap().is_none());
+        let value: Option<String> = con.get(resource_name).unwrap();
+        assert!(value.is_none());
+        other.unlock().unwrap();
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use redis::Commands;
+    #[test]
+    fn test_escape_glob() {
+        assert_eq!(escape_glob("service:"), "service:");
+        assert_eq!(escape_glob("a*b?[c]\\"), "a\\*b\\?\\[c\\]\\\\");
+    }
 
-    lazy_static! {
-        static ref REDLOCK: Redlock = Redlock::new::<&str>(Config {
-            addrs: vec!["redis://127.0.0.1"],
-            retry_count: 10,
-            retry_delay: Duration::from_millis(400),
-            retry_jitter: 400,
-            drift_factor: 0.01,
-        }).unwrap();
+    #[test]
+    fn test_cleanup_prefix() {
+        let prefix = "test_cleanup_prefix*:";
+        let addrs = vec!["redis://127.0.0.1/0", "redis://127.0.0.1/1", "redis://127.0.0.1/2"];
+        let redlock = Redlock::new(Config {
+                                       key_prefix: String::from(prefix),
+                                       ..Config::with_addrs(addrs.clone())
+                                   })
+                .unwrap();
+        let mut cons: Vec<redis::Connection> = addrs
+            .iter()
+            .map(|addr| redis::Client::open(*addr).unwrap().get_connection().unwrap())
+            .collect();
 
-        static ref REDIS_CLI: redis::Client = redis::Client::open("redis://127.0.0.1").unwrap();
+        // A crashed instance left a lock behind, on two servers only
+        let lock = redlock.lock("held", Duration::from_millis(10000)).unwrap();
+        for con in &mut cons[..2] {
+            let _: () = con.set(format!("{}orphan", prefix), "value").unwrap();
+        }
+        // The glob characters of the prefix are matched as is
+        let _: () = cons[0].set("test_cleanup_prefix_other:orphan", "value").unwrap();
+
+        let expected = vec![format!("{}held", prefix), format!("{}orphan", prefix)];
+        assert_eq!(redlock.cleanup_prefix(true).unwrap(), expected);
+        assert!(redlock.is_locked("held").unwrap());
+
+        assert_eq!(redlock.cleanup_prefix(false).unwrap(), expected);
+        assert!(!redlock.is_locked("held").unwrap());
+        assert!(redlock.cleanup_prefix(true).unwrap().is_empty());
+        let other: Option<String> = cons[0].get("test_cleanup_prefix_other:orphan").unwrap();
+        assert!(other.is_some());
+        let _: () = cons[0].del("test_cleanup_prefix_other:orphan").unwrap();
+        drop(lock);
     }
 
     #[test]
-    fn test_config_default() {
-        let default_config = Config::default();
-        assert_eq!(default_config.addrs, vec!["redis://127.0.0.1"]);
-        assert_eq!(default_config.retry_count, 10);
-        assert_eq!(default_config.retry_delay, Duration::from_millis(400));
-        assert_eq!(default_config.retry_jitter, 400);
-        assert_eq!(default_config.drift_factor, 0.01);
+    fn test_cleanup_empty_prefix() {
+        let redlock = Redlock::new(Config::default()).unwrap();
+        assert!(matches!(redlock.cleanup_prefix(true), Err(RedlockError::EmptyKeyPrefix)));
     }
 
     #[test]
-    #[should_panic]
-    fn test_new_with_no_server() {
-        Redlock::new::<&str>(Config {
-                                 addrs: vec![],
-                                 retry_count: 10,
-                                 retry_delay: Duration::from_millis(400),
-                                 retry_jitter: 400,
-                                 drift_factor: 0.01,
-                             })
+    fn test_warmup() {
+        let observer = CountingObserver::default();
+        let node_errors = observer.node_errors.clone();
+        let redlock = Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1/0",
+                                                           "redis://127.0.0.1:1",
+                                                           "redis://127.0.0.1/2"]))
+                .unwrap()
+                .with_observer(observer);
+
+        redlock.warmup().unwrap();
+        assert_eq!(*node_errors.lock().unwrap(), vec![(1, String::from("1"))]);
+        let idle_counts: Vec<usize> = redlock
+            .nodes
+            .iter()
+            .map(|node| match *node {
+                     Node::Redis(ref pool) => pool.idle_count(),
+                     Node::Backend(_) => unreachable!(),
+                 })
+            .collect();
+        assert_eq!(idle_counts, vec![1, 0, 1]);
+
+        let redlock = Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1",
+                                                           "redis://127.0.0.1:1",
+                                                           "redis://127.0.0.1:1"]))
                 .unwrap();
+        assert!(redlock.warmup().is_err());
     }
 
     #[test]
-    fn test_new() {
-        let redlock = Redlock::new(Config::default()).unwrap();
-        assert_eq!(redlock.clients.len(), 1);
-        assert_eq!(redlock.retry_count, 10);
-        assert_eq!(redlock.retry_delay, Duration::from_millis(400));
+    fn test_warmup_credentials() {
+        // The server has no password, and refuses the credentials
+        let redlock = Redlock::new(Config {
+                                       password: Some(String::from("secret")),
+                                       ..Config::with_addrs(vec!["redis://127.0.0.1/0",
+                                                                 "redis://127.0.0.1/1",
+                                                                 "redis://127.0.0.1/2"])
+                                   })
+                .unwrap();
+        match redlock.warmup() {
+            Err(RedlockError::AuthenticationFailed(label, _)) => assert_eq!(label, "0"),
+            res => panic!("expected AuthenticationFailed, got {:?}", res),
+        }
     }
 
+    // Runs against a redis server protected by a password, given by the
+    // REDLOCK_AUTH_ADDR and REDLOCK_AUTH_PASSWORD variables, skipped
+    // otherwise, e.g. redis-server --port 6380 --requirepass secret.
     #[test]
-    fn test_lock() {
-        let resource_name = "test_lock";
+    fn test_password_protected() {
+        let (addr, password) = match (env::var("REDLOCK_AUTH_ADDR"), env::var("REDLOCK_AUTH_PASSWORD")) {
+            (Ok(addr), Ok(password)) => (addr, password),
+            _ => return,
+        };
+        let resource_name = "test_password_protected";
+        let new_redlock = |username: Option<&str>, password: Option<&str>| {
+            Redlock::new(Config {
+                             retry_count: 1,
+                             username: username.map(String::from),
+                             password: password.map(String::from),
+                             ..Config::with_addrs(vec![addr.as_str()])
+                         })
+                    .unwrap()
+        };
+
+        for redlock in [new_redlock(None, None), new_redlock(None, Some("wrong"))] {
+            match redlock.warmup() {
+                Err(RedlockError::AuthenticationFailed(..)) => {}
+                res => panic!("expected AuthenticationFailed, got {:?}", res),
+            }
+        }
+
+        let redlock = new_redlock(Some("default"), Some(&password));
+        redlock.warmup().unwrap();
+        let lock = redlock.lock(resource_name, Duration::from_millis(1000)).unwrap();
+        lock.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_track_locks() {
         let one_second = Duration::from_millis(1000);
+        let redlock = Redlock::new(Config {
+                                       track_locks: true,
+                                       ..Config::default()
+                                   })
+                .unwrap();
+        let first = redlock.lock("test_track_locks_1", one_second).unwrap();
+        let second = redlock.lock("test_track_locks_2", one_second).unwrap();
+        assert_eq!(redlock.clone().held_locks(),
+                   vec![(String::from("test_track_locks_1"), String::from(first.value())),
+                        (String::from("test_track_locks_2"), String::from(second.value()))]);
 
-        let lock = REDLOCK.lock(resource_name, one_second).unwrap();
-        assert!(lock.expiration < SystemTime::now().add(one_second));
+        first.unlock().unwrap();
+        assert_eq!(redlock.held_locks().len(), 1);
+        let results = redlock.unlock_all();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_ok());
+        assert!(redlock.held_locks().is_empty());
+        assert!(redlock.try_lock("test_track_locks_2", one_second).unwrap().is_some());
+
+        let untracked = Redlock::new(Config::default()).unwrap();
+        let _lock = untracked.lock("test_track_locks_3", one_second).unwrap();
+        assert!(untracked.held_locks().is_empty());
+        assert!(untracked.unlock_all().is_empty());
     }
 
     #[test]
-    fn test_lock_twice() {
-        let resource_name = "test_lock_twice";
+    fn test_custom_scripts() {
+        let resource_name = "test_custom_scripts";
         let one_second = Duration::from_millis(1000);
-        let start = SystemTime::now();
-        let lock = REDLOCK.lock(resource_name, one_second).unwrap();
+        // Only lock the resource if its companion flag is set
+        let lock_script = "
+            if redis.call(\"exists\", KEYS[1] .. \":enabled\") == 1 then
+              return redis.call(\"set\", KEYS[1], ARGV[1], \"NX\", \"PX\", ARGV[2])
+            end
+            return false
+        ";
+        let redlock = Redlock::new(Config {
+                                       lock_script: Some(String::from(lock_script)),
+                                       unlock_script: Some(String::new()),
+                                       ..Config::default()
+                                   })
+                .unwrap();
+        let mut con = REDIS_CLI.get_connection().unwrap();
+        let _: () = con.del(format!("{}:enabled", resource_name)).unwrap();
 
-        assert!(lock.expiration > start);
-        assert!(lock.expiration < start.add(one_second));
-        assert!(REDLOCK.lock(resource_name, one_second).is_err());
+        assert!(redlock.try_lock(resource_name, one_second).unwrap().is_none());
+        let _: () = con.set(format!("{}:enabled", resource_name), 1).unwrap();
+        let lock = redlock.try_lock(resource_name, one_second).unwrap().unwrap();
+        assert!(redlock.try_lock(resource_name, one_second).unwrap().is_none());
+        lock.unlock().unwrap();
+        let _: () = con.del(format!("{}:enabled", resource_name)).unwrap();
+    }
 
-        thread::sleep(one_second);
+    #[test]
+    fn test_invalid_script() {
+        let res = Redlock::new(Config {
+                                   extend_script: Some(String::from("return (")),
+                                   ..Config::default()
+                               });
+        match res {
+            Err(RedlockError::InvalidScript("extend", _)) => {}
+            res => panic!("expected InvalidScript, got {:?}", res),
+        }
 
-        assert!(REDLOCK.lock(resource_name, one_second).is_ok());
+        // The unreachable servers can not tell
+        assert!(Redlock::new(Config {
+                                 extend_script: Some(String::from("return (")),
+                                 ..Config::with_addrs(vec!["redis://127.0.0.1:1"])
+                             })
+                        .is_ok());
     }
 
     #[test]
-    fn test_unlock() {
-        let resource_name = "test_unlock";
-        let lock = REDLOCK
-            .lock(resource_name, Duration::from_millis(2000))
-            .unwrap();
+    fn test_quorum_unreachable() {
+        let observer = CountingObserver::default();
+        let attempts = observer.attempts.clone();
+        let redlock = Redlock::new(Config {
+                                       retry_count: 10,
+                                       retry_delay: Duration::from_millis(200),
+                                       retry_jitter: 0,
+                                       // Do not retry releasing the server acquired
+                                       unlock_retry_count: Some(1),
+                                       ..Config::with_addrs(vec!["redis://127.0.0.1:1",
+                                                                 "redis://127.0.0.1",
+                                                                 "redis://127.0.0.1:1"])
+                                   })
+                .unwrap()
+                .with_observer(observer);
 
-        let value: String = REDIS_CLI
+        let start = Instant::now();
+        match redlock.lock("test_quorum_unreachable", Duration::from_millis(1000)) {
+            Err(RedlockError::QuorumUnreachable(2, 2, node_results)) => {
+                assert!(matches!(node_results[1], NodeOutcome::Acquired));
+            }
+            res => panic!("expected QuorumUnreachable, got {:?}", res),
+        }
+        // Given up after the second attempt instead of the tenth
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert!(start.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_random_node_order() {
+        let observer = FirstNodeObserver::default();
+        let first_nodes = observer.first_nodes.clone();
+        let redlock = Redlock::new(Config::with_addrs(vec!["redis://127.0.0.1/0",
+                                                           "redis://127.0.0.1/1",
+                                                           "redis://127.0.0.1/2"]))
+                .unwrap()
+                .with_observer(observer);
+
+        for _ in 0..30 {
+            redlock.is_locked("test_random_node_order").unwrap();
+        }
+
+        let first_nodes = first_nodes.lock().unwrap();
+        assert_eq!(first_nodes.len(), 30);
+        assert!(first_nodes.iter().any(|&index| index != first_nodes[0]));
+    }
+
+    #[test]
+    fn test_node_labels() {
+        let observer = CountingObserver::default();
+        let node_errors = observer.node_errors.clone();
+        let redlock = Redlock::new(Config {
+                                       retry_count: 1,
+                                       labels: vec![String::from("dc1"), String::from("dc2")],
+                                       ..Config::with_addrs(vec!["redis://127.0.0.1",
+                                                                 "redis://127.0.0.1:1"])
+                                   })
+                .unwrap()
+                .with_observer(observer);
+        assert_eq!(redlock.node_label(1), "dc2");
+
+        match redlock.lock("test_node_labels", Duration::from_millis(1000)) {
+            Err(RedlockError::QuorumNotReached(_, _, _, _, node_results, _)) => {
+                assert!(matches!(node_results[1], NodeOutcome::Error(ref label, _) if label == "dc2"));
+            }
+            _ => panic!("expected QuorumNotReached"),
+        }
+        // The failed attempt is released, which fails on the same server
+        assert!(node_errors
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .all(|node_error| *node_error == (1, String::from("dc2"))));
+
+        let res = Redlock::new(Config {
+                                   labels: vec![String::from("dc1"), String::from("dc2")],
+                                   ..Config::default()
+                               });
+        assert!(matches!(res, Err(RedlockError::InvalidConfig(reason))
+                              if reason.starts_with("labels")));
+    }
+
+    #[test]
+    fn test_unlock_aborts_without_quorum() {
+        // With 4 servers, the quorum of 3 is unreachable after 2 errors, the
+        // attempt fails but every error is still reported
+        let observer = CountingObserver::default();
+        let node_errors = observer.node_errors.clone();
+        let redlock = Redlock::new(Config {
+                                       retry_count: 1,
+                                       ..Config::with_addrs(vec!["redis://127.0.0.1:1"; 4])
+                                   })
+                .unwrap()
+                .with_observer(observer);
+
+        assert!(redlock
+                    .unlock("test_unlock_aborts_without_quorum", "value")
+                    .is_err());
+        let expected: Vec<(usize, String)> = (0..4).map(|index| (index, index.to_string())).collect();
+        assert_eq!(*node_errors.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_lock_guard() {
+        let resource_name = "test_lock_guard";
+        let one_second = Duration::from_millis(1000);
+        {
+            let _guard = REDLOCK.lock_guard(resource_name, one_second).unwrap();
+            assert!(REDLOCK.lock(resource_name, one_second).is_err());
+        }
+
+        let res: Option<String> = REDIS_CLI
             .get_connection()
             .unwrap()
             .get(resource_name)
             .unwrap();
-        assert_eq!(value.len(), 32);
+        assert!(res.is_none());
+    }
+
+    #[test]
+    fn test_with_lock() {
+        let resource_name = "test_with_lock";
+        let one_second = Duration::from_millis(1000);
+        let res = REDLOCK.with_lock(resource_name, one_second, |lock| {
+            assert_eq!(lock.resource_name(), resource_name);
+            assert!(REDLOCK.lock(resource_name, one_second).is_err());
+            Err::<(), &str>("failed")
+        });
+        assert_eq!(res.unwrap(), Err("failed"));
+
+        let res = panic::catch_unwind(|| {
+            REDLOCK.with_lock(resource_name, one_second, |_| panic!("failed"))
+        });
+        assert!(res.is_err());
 
-        lock.unlock().unwrap();
         let res: Option<String> = REDIS_CLI
             .get_connection()
             .unwrap()
@@ -376,22 +5264,31 @@ mod tests {
     }
 
     #[test]
-    fn test_extend() {
-        let resource_name = "test_extend";
-        let lock = REDLOCK
-            .lock(resource_name, Duration::from_millis(2000))
-            .unwrap();
-        let lock_extended = lock.extend(Duration::from_millis(2000)).unwrap();
+    fn test_lock_guard_extend() {
+        let resource_name = "test_lock_guard_extend";
+        let one_second = Duration::from_millis(1000);
+        let clock = TestClock::new();
+        let redlock = Redlock::new(Config::default()).unwrap().with_clock(clock.clone());
+        let mut guard = redlock.lock_guard(resource_name, one_second).unwrap();
+        guard.extend(Duration::from_millis(2000)).unwrap();
+        clock.advance(Duration::from_millis(1200));
 
-        assert!(lock_extended.expiration < SystemTime::now().add(Duration::from_millis(2000)));
+        assert!(guard.expiration > clock.now());
+        assert!(guard.remaining_ttl().is_some());
+        drop(guard);
+        assert!(redlock.lock(resource_name, one_second).is_ok());
     }
 
     #[test]
-    fn test_extend_expired_resource() {
+    fn test_lock_guard_into_unguarded() {
+        let resource_name = "test_lock_guard_into_unguarded";
         let one_second = Duration::from_millis(1000);
-        let resource_name = "test_extend_expired_resource";
-        let lock = REDLOCK.lock(resource_name, one_second).unwrap();
-        thread::sleep(one_second * 2);
-        assert!(lock.extend(one_second).is_err());
+        let lock = REDLOCK
+            .lock_guard(resource_name, one_second)
+            .unwrap()
+            .into_unguarded();
+
+        assert!(REDLOCK.lock(resource_name, one_second).is_err());
+        lock.unlock().unwrap();
     }
 }
@@ -0,0 +1,240 @@
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use crate::errors::{AttemptTiming, NodeOutcome, RedlockError, RedlockResult};
+use crate::quorum::{evaluate_quorum, Decision};
+use crate::redlock::{expirations, has_hash_tag, Lock, Redlock, RequestInfo};
+use crate::util;
+
+// ReadLock represents a shared lock of a resource, acquired by
+// Redlock::lock_read. Several read locks of the same resource can be held
+// at once, but none while it is write locked.
+#[derive(Debug)]
+pub struct ReadLock<'a> {
+    redlock: &'a Redlock,
+    resource_name: String,
+    value: String,
+    expiration: SystemTime,
+    valid_until: Instant,
+}
+
+impl<'a> ReadLock<'a> {
+    pub fn resource_name(&self) -> &str {
+        &self.resource_name
+    }
+
+    // The random value which identifies the reader.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    // The time the lock expires at, with the clock drift subtracted.
+    pub fn expiration(&self) -> SystemTime {
+        self.expiration
+    }
+
+    // The time left until the lock expires, or None if it has already
+    // expired.
+    pub fn remaining_ttl(&self) -> Option<Duration> {
+        self.redlock.remaining_ttl(self.valid_until)
+    }
+
+    // Release the acquired lock, the other readers keep theirs.
+    pub fn unlock(&self) -> RedlockResult<()> {
+        self.redlock.unlock_read(&self.resource_name, &self.value)
+    }
+}
+
+impl Redlock {
+    // Locks the given resource for reading: several readers can hold the
+    // lock at once, but not while a writer holds it, see lock_write. Each
+    // reader expires on its own after the TTL, so a crashed reader does not
+    // wedge the resource. The readers are kept at "<resource>:readers",
+    // in the same hash slot as the resource.
+    pub fn lock_read(&self, resource_name: &str, ttl: Duration) -> RedlockResult<ReadLock<'_>> {
+        let key = self.key(resource_name);
+        let readers_key = readers_key(&key);
+        let drift = self.get_drift(&ttl);
+
+        let mut attempts = 0;
+        let request_start = Instant::now();
+        let mut timings = Vec::new();
+        let mut node_results = Vec::new();
+        while attempts < self.retry_count {
+            attempts += 1;
+            let attempt_start = request_start.elapsed();
+
+            let value = util::get_random_token(self.token_len);
+            let start = self.clock.now();
+            let start_instant = self.clock.instant();
+            let (expiration, valid_until) = expirations(start, start_instant, ttl, drift)?;
+
+            let request_results =
+                self.request_all(|backend| backend.add_reader(&key, &readers_key, &value, &ttl));
+            node_results = self.node_outcomes(request_results);
+            let votes = node_results
+                .iter()
+                .filter(|outcome| matches!(**outcome, NodeOutcome::Acquired))
+                .count();
+
+            let lock = ReadLock {
+                redlock: self,
+                resource_name: String::from(resource_name),
+                value,
+                expiration,
+                valid_until,
+            };
+            let min_valid_until = self.clock.instant().checked_add(self.min_validity);
+            if evaluate_quorum(&node_results, self.quorum) == Decision::Acquire &&
+               min_valid_until.is_some_and(|min_valid_until| valid_until > min_valid_until) {
+                return Ok(lock);
+            }
+
+            // Remove the reader from the servers which have added it
+            if votes > 0 {
+                let _ = lock.unlock(); // Expires anyway
+            }
+            let mut timing = AttemptTiming {
+                start: attempt_start,
+                duration: request_start.elapsed() - attempt_start,
+                votes,
+                sleep: Duration::from_millis(0),
+            };
+            if attempts < self.retry_count {
+                timing.sleep = self.get_retry_timeout(attempts);
+                thread::sleep(timing.sleep);
+            }
+            timings.push(timing);
+        }
+
+        Err(self.lock_failed(String::from(resource_name), attempts, node_results, timings))
+    }
+
+    // Locks the given resource for writing: the lock is exclusive with the
+    // readers and the other writers. It is a plain lock of the resource
+    // once acquired, so it is released and extended like one, and is
+    // exclusive with lock as well. The writers do not take precedence over
+    // the readers: as long as a reader holds the lock, e.g. since the
+    // readers keep overlapping, a writer can not acquire it, and may give
+    // up after the retry count.
+    pub fn lock_write(&self, resource_name: &str, ttl: Duration) -> RedlockResult<Lock<'_>> {
+        let readers_key = readers_key(&self.key(resource_name));
+        self.request(RequestInfo::LockExclusive { readers_key: &readers_key },
+                     resource_name,
+                     ttl,
+                     None)
+    }
+
+    fn unlock_read(&self, resource_name: &str, value: &str) -> RedlockResult<()> {
+        let readers_key = readers_key(&self.key(resource_name));
+
+        let mut attempts = 0;
+        let mut votes = 0;
+        while attempts < self.unlock_retry_count {
+            attempts += 1;
+
+            // The reader is gone from a server whether it has been removed
+            // or has already expired
+            votes = self.request_all(|backend| backend.remove_reader(&readers_key, value))
+                .iter()
+                .filter(|result| result.is_ok())
+                .count();
+            if votes >= self.quorum {
+                return Ok(());
+            }
+
+            if attempts < self.unlock_retry_count {
+                thread::sleep(self.get_unlock_retry_timeout(attempts));
+            }
+        }
+
+        Err(RedlockError::UnableToUnlock(String::from(resource_name), votes, self.quorum, attempts))
+    }
+}
+
+// The readers must be in the same hash slot as the resource, like the fence
+// counter.
+fn readers_key(key: &str) -> String {
+    if has_hash_tag(key) {
+        format!("{}:readers", key)
+    } else {
+        format!("{{{}}}:readers", key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redlock::Config;
+
+    fn new_redlock() -> Redlock {
+        Redlock::new(Config {
+                         retry_count: 2,
+                         retry_delay: Duration::from_millis(50),
+                         retry_jitter: 0,
+                         ..Config::with_addrs(vec!["redis://127.0.0.1/0",
+                                                   "redis://127.0.0.1/1",
+                                                   "redis://127.0.0.1/2"])
+                     })
+                .unwrap()
+    }
+
+    #[test]
+    fn test_readers_key() {
+        assert_eq!(readers_key("resource"), "{resource}:readers");
+        assert_eq!(readers_key("user:{42}:profile"), "user:{42}:profile:readers");
+    }
+
+    #[test]
+    fn test_lock_read_concurrently() {
+        let resource_name = "test_lock_read_concurrently";
+        let ttl = Duration::from_millis(2000);
+        let redlock = new_redlock();
+        let first = redlock.lock_read(resource_name, ttl).unwrap();
+        let second = redlock.lock_read(resource_name, ttl).unwrap();
+        assert_ne!(first.value(), second.value());
+        assert!(first.remaining_ttl().is_some());
+
+        first.unlock().unwrap();
+        // A reader is left, the resource can not be written
+        assert!(redlock.lock_write(resource_name, ttl).is_err());
+        second.unlock().unwrap();
+        redlock.lock_write(resource_name, ttl).unwrap().unlock().unwrap();
+    }
+
+    #[test]
+    fn test_lock_write_exclusive() {
+        let resource_name = "test_lock_write_exclusive";
+        let ttl = Duration::from_millis(2000);
+        let redlock = new_redlock();
+        let writer = redlock.lock_write(resource_name, ttl).unwrap();
+        match redlock.lock_read(resource_name, ttl) {
+            Err(RedlockError::QuorumNotReached(_, 0, 2, 2, _, _)) => {}
+            res => panic!("expected QuorumNotReached, got {:?}", res),
+        }
+        assert!(redlock.lock_write(resource_name, ttl).is_err());
+        assert!(redlock.lock(resource_name, ttl).is_err());
+
+        writer.unlock().unwrap();
+        let reader = redlock.lock_read(resource_name, ttl).unwrap();
+        reader.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_lock_read_expires() {
+        let resource_name = "test_lock_read_expires";
+        let redlock = new_redlock();
+        let _reader = redlock
+            .lock_read(resource_name, Duration::from_millis(200))
+            .unwrap();
+        assert!(redlock
+                    .lock_write(resource_name, Duration::from_millis(1000))
+                    .is_err());
+
+        // The reader has crashed without unlocking
+        thread::sleep(Duration::from_millis(300));
+        let writer = redlock
+            .lock_write(resource_name, Duration::from_millis(1000))
+            .unwrap();
+        writer.unlock().unwrap();
+    }
+}
@@ -1,13 +1,34 @@
-use redis;
-
 lazy_static! {
   pub static ref LOCK: redis::Script = redis::Script::new("
     return redis.call(\"set\", KEYS[1], ARGV[1], \"NX\", \"PX\", ARGV[2])
   ");
 
+  pub static ref LOCK_OR_EXISTING: redis::Script = redis::Script::new("
+    if redis.call(\"set\", KEYS[1], ARGV[1], \"NX\", \"PX\", ARGV[2]) then
+      return 1
+    elseif redis.call(\"get\", KEYS[1]) == ARGV[1] then
+      return redis.call(\"pexpire\", KEYS[1], ARGV[2])
+    else
+      return 0
+    end
+  ");
+
+  pub static ref LOCK_FENCED: redis::Script = redis::Script::new("
+    if redis.call(\"set\", KEYS[1], ARGV[1], \"NX\", \"PX\", ARGV[2]) then
+      return {1, redis.call(\"incr\", KEYS[2])}
+    else
+      return {0, 0}
+    end
+  ");
+
+  // Returns 1 if the lock is released, 0 if it is not present and -1 if it
+  // is held by another value.
   pub static ref UNLOCK: redis::Script = redis::Script::new("
-    if redis.call(\"get\", KEYS[1]) == ARGV[1] then
+    local current = redis.call(\"get\", KEYS[1])
+    if current == ARGV[1] then
       return redis.call(\"del\", KEYS[1])
+    elseif current then
+      return -1
     else
       return 0
     end
@@ -20,4 +41,139 @@ lazy_static! {
       return 0
     end
   ");
+
+  // Set the lock to ARGV[2] with a fresh TTL if it is held with ARGV[1],
+  // or already with ARGV[2] so that a transfer can be retried. Returns 1 if
+  // it is set, 0 otherwise.
+  pub static ref TRANSFER: redis::Script = redis::Script::new("
+    local current = redis.call(\"get\", KEYS[1])
+    if current == ARGV[1] or current == ARGV[2] then
+      redis.call(\"set\", KEYS[1], ARGV[2], \"PX\", ARGV[3])
+      return 1
+    else
+      return 0
+    end
+  ");
+
+  // Extend the lock like EXTEND, and set the companion key KEYS[2] to the
+  // data ARGV[3] for as long. Returns 1 if it is extended, 0 otherwise.
+  pub static ref EXTEND_WITH_DATA: redis::Script = redis::Script::new("
+    if redis.call(\"get\", KEYS[1]) == ARGV[1] then
+      redis.call(\"pexpire\", KEYS[1], ARGV[2])
+      redis.call(\"set\", KEYS[2], ARGV[3], \"PX\", ARGV[2])
+      return 1
+    else
+      return 0
+    end
+  ");
+
+  // The read/write locks keep the readers in the sorted set KEYS[2], scored
+  // by the server time in milliseconds they expire at, while a writer
+  // holds KEYS[1] like a plain lock. The expired readers are pruned by
+  // every lock request.
+
+  // Add the reader ARGV[1] for ARGV[2] milliseconds unless a writer holds
+  // the lock. Returns 1 if it is added, 0 otherwise.
+  pub static ref LOCK_READ: redis::Script = redis::Script::new("
+    if redis.call(\"exists\", KEYS[1]) == 1 then
+      return 0
+    end
+    local time = redis.call(\"time\")
+    local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+    redis.call(\"zremrangebyscore\", KEYS[2], \"-inf\", now)
+    redis.call(\"zadd\", KEYS[2], now + tonumber(ARGV[2]), ARGV[1])
+    if redis.call(\"pttl\", KEYS[2]) < tonumber(ARGV[2]) then
+      redis.call(\"pexpire\", KEYS[2], ARGV[2])
+    end
+    return 1
+  ");
+
+  // Lock KEYS[1] with ARGV[1] for ARGV[2] milliseconds unless it is
+  // already locked or there is a reader. Returns 1 if it is locked, 0
+  // otherwise.
+  pub static ref LOCK_WRITE: redis::Script = redis::Script::new("
+    local time = redis.call(\"time\")
+    local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+    redis.call(\"zremrangebyscore\", KEYS[2], \"-inf\", now)
+    if redis.call(\"zcard\", KEYS[2]) > 0 then
+      return 0
+    end
+    if redis.call(\"set\", KEYS[1], ARGV[1], \"NX\", \"PX\", ARGV[2]) then
+      return 1
+    end
+    return 0
+  ");
+
+  // Returns the PTTL of the lock if it is held with the given value, nil
+  // otherwise.
+  pub static ref PTTL: redis::Script = redis::Script::new("
+    if redis.call(\"get\", KEYS[1]) == ARGV[1] then
+      return redis.call(\"pttl\", KEYS[1])
+    else
+      return false
+    end
+  ");
+}
+
+#[cfg(feature = "fair")]
+lazy_static! {
+  // Queue the contender at the tail of the queue unless it is already
+  // queued, and refresh its entry which expires after ARGV[2] milliseconds
+  // if it stops polling. The contenders whose entry has expired are removed
+  // from the head. Returns 1 if the contender is at the head, 0 otherwise.
+  pub static ref FAIR_ENQUEUE: redis::Script = redis::Script::new("
+    local entry = KEYS[1] .. \":\" .. ARGV[1]
+    if redis.call(\"exists\", entry) == 0 then
+      redis.call(\"rpush\", KEYS[1], ARGV[1])
+    end
+    redis.call(\"set\", entry, 1, \"PX\", ARGV[2])
+    redis.call(\"pexpire\", KEYS[1], ARGV[2])
+
+    local head = redis.call(\"lindex\", KEYS[1], 0)
+    while head and redis.call(\"exists\", KEYS[1] .. \":\" .. head) == 0 do
+      redis.call(\"lpop\", KEYS[1])
+      head = redis.call(\"lindex\", KEYS[1], 0)
+    end
+    if head == ARGV[1] then
+      return 1
+    else
+      return 0
+    end
+  ");
+
+  pub static ref FAIR_DEQUEUE: redis::Script = redis::Script::new("
+    redis.call(\"lrem\", KEYS[1], 0, ARGV[1])
+    return redis.call(\"del\", KEYS[1] .. \":\" .. ARGV[1])
+  ");
+}
+
+// Scripts are the scripts requested to a redis server, the built-in ones
+// unless they are overridden by the configuration.
+#[derive(Debug, Clone)]
+pub struct Scripts {
+  pub lock: redis::Script,
+  pub unlock: redis::Script,
+  pub extend: redis::Script,
+}
+
+impl Scripts {
+  // Override the built-in scripts with the given ones, an empty script
+  // keeps the built-in one.
+  pub fn new(lock: Option<&str>, unlock: Option<&str>, extend: Option<&str>) -> Scripts {
+    let script = |code: Option<&str>, built_in: &redis::Script| match code {
+      Some(code) if !code.trim().is_empty() => redis::Script::new(code),
+      _ => built_in.clone(),
+    };
+    Scripts {
+      lock: script(lock, &LOCK),
+      unlock: script(unlock, &UNLOCK),
+      extend: script(extend, &EXTEND),
+    }
+  }
+}
+
+impl Default for Scripts {
+  fn default() -> Scripts {
+    Scripts::new(None, None, None)
+  }
 }
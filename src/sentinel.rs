@@ -0,0 +1,92 @@
+use std::time::Duration;
+use std::sync::Arc;
+use redis::{ConnectionInfo, IntoConnectionInfo, RedisError, RedisResult};
+use crate::errors::{RedlockError, RedlockResult};
+use crate::redlock::AddrResolver;
+
+// How long to wait for a sentinel before asking the next one.
+const SENTINEL_TIMEOUT: Duration = Duration::from_millis(500);
+
+// Create a resolver giving the addresses of the given masters, as reported
+// by the given sentinels with SENTINEL get-master-addr-by-name, see
+// Config::resolver. Each master is a server of Redlock, so they must be
+// independent masters, e.g. monitored under different names, none being a
+// replica of another one. The sentinels are asked in turn until one of them
+// knows the master, the resolver fails if none does.
+pub fn sentinel_resolver<T>(sentinels: Vec<T>, master_names: Vec<String>) -> RedlockResult<AddrResolver>
+    where T: IntoConnectionInfo
+{
+    let mut clients = Vec::with_capacity(sentinels.len());
+    for (index, sentinel) in sentinels.into_iter().enumerate() {
+        clients.push(redis::Client::open(sentinel)
+                         .map_err(|err| RedlockError::InvalidAddress(index, err))?);
+    }
+    if clients.is_empty() || master_names.is_empty() {
+        return Err(RedlockError::NoServerError);
+    }
+
+    Ok(Arc::new(move || {
+                    master_names
+                        .iter()
+                        .map(|master_name| master_addr(&clients, master_name))
+                        .collect()
+                }))
+}
+
+// Ask the sentinels in turn for the address of the master, keeping the
+// last error if none answers.
+fn master_addr(sentinels: &[redis::Client], master_name: &str) -> RedisResult<ConnectionInfo> {
+    let mut last_error = None;
+    for sentinel in sentinels {
+        let addr = sentinel
+            .get_connection_with_timeout(SENTINEL_TIMEOUT)
+            .and_then(|mut con| {
+                          con.set_read_timeout(Some(SENTINEL_TIMEOUT))?;
+                          redis::cmd("SENTINEL")
+                              .arg("get-master-addr-by-name")
+                              .arg(master_name)
+                              .query::<Option<(String, u16)>>(&mut con)
+                      });
+        match addr {
+            Ok(Some(addr)) => return addr.into_connection_info(),
+            Ok(None) => {
+                last_error = Some(RedisError::from((redis::ErrorKind::ResponseError,
+                                                    "Unknown master",
+                                                    String::from(master_name))))
+            }
+            Err(err) => last_error = Some(err),
+        }
+    }
+
+    Err(last_error.unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sentinel_resolver_unreachable() {
+        let resolve = sentinel_resolver(vec!["redis://127.0.0.1:1", "redis://127.0.0.1:2"],
+                                        vec![String::from("mymaster")])
+                .unwrap();
+        assert!(resolve().unwrap_err().is_connection_refusal());
+    }
+
+    #[test]
+    fn test_sentinel_resolver_unknown_master() {
+        // A plain redis server does not know the SENTINEL command
+        let resolve = sentinel_resolver(vec!["redis://127.0.0.1"], vec![String::from("mymaster")])
+            .unwrap();
+        assert!(resolve().is_err());
+    }
+
+    #[test]
+    fn test_sentinel_resolver_empty() {
+        let sentinels: Vec<&str> = Vec::new();
+        assert!(matches!(sentinel_resolver(sentinels, vec![String::from("mymaster")]),
+                         Err(RedlockError::NoServerError)));
+        assert!(matches!(sentinel_resolver(vec!["redis://127.0.0.1"], Vec::new()),
+                         Err(RedlockError::NoServerError)));
+    }
+}
@@ -0,0 +1,120 @@
+use std::ptr;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use signal_hook::consts::SIGTERM;
+use signal_hook::iterator::Signals;
+use signal_hook::low_level;
+use crate::errors::{RedlockError, RedlockResult};
+use crate::redlock::Redlock;
+
+lazy_static! {
+    // The instances whose locks are released on SIGTERM, None until the
+    // handler has been installed.
+    static ref REGISTERED: Mutex<Option<Vec<Weak<Redlock>>>> = Mutex::new(None);
+}
+
+impl Redlock {
+    // Release the locks held by this instance when the process receives
+    // SIGTERM, e.g. on a deploy, then terminate it as SIGTERM does by
+    // default. The locks must be tracked, see Config::track_locks, which
+    // also tells the locks it can not see: the locks are released with
+    // unlock_all. Installing it again for the same instance does nothing,
+    // and the instances which have been dropped are skipped.
+    //
+    // The locks are released from a dedicated thread rather than from the
+    // signal handler, which can not take locks nor do I/O. An application
+    // which handles SIGTERM itself to shut down gracefully should call
+    // unlock_all on its own instead, since the process is terminated right
+    // after the locks are released.
+    pub fn install_shutdown_handler(self: &Arc<Self>) -> RedlockResult<()> {
+        if self.held_locks.is_none() {
+            return Err(RedlockError::LockTrackingDisabled);
+        }
+
+        let mut registered = REGISTERED.lock().unwrap();
+        let instances = match *registered {
+            Some(ref mut instances) => instances,
+            None => {
+                spawn_handler()?;
+                registered.insert(Vec::new())
+            }
+        };
+        instances.retain(|instance| instance.strong_count() > 0);
+        if !instances
+                .iter()
+                .any(|instance| ptr::eq(instance.as_ptr(), Arc::as_ptr(self))) {
+            instances.push(Arc::downgrade(self));
+        }
+        Ok(())
+    }
+}
+
+fn spawn_handler() -> RedlockResult<()> {
+    let mut signals = Signals::new([SIGTERM]).map_err(RedlockError::SignalHandler)?;
+    thread::Builder::new()
+        .name(String::from("redlock-shutdown"))
+        .spawn(move || if signals.forever().next().is_some() {
+                   release_registered();
+                   let _ = low_level::emulate_default_handler(SIGTERM);
+               })
+        .map_err(RedlockError::SignalHandler)?;
+    Ok(())
+}
+
+// Release the locks of the registered instances which are still alive.
+fn release_registered() {
+    let instances: Vec<Arc<Redlock>> = match *REGISTERED.lock().unwrap() {
+        Some(ref instances) => instances.iter().filter_map(Weak::upgrade).collect(),
+        None => Vec::new(),
+    };
+    for instance in instances {
+        let _ = instance.unlock_all(); // Expire anyway
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::redlock::Config;
+
+    fn registered_count(redlock: &Arc<Redlock>) -> usize {
+        REGISTERED
+            .lock()
+            .unwrap()
+            .iter()
+            .flatten()
+            .filter(|instance| ptr::eq(instance.as_ptr(), Arc::as_ptr(redlock)))
+            .count()
+    }
+
+    #[test]
+    fn test_install_shutdown_handler() {
+        let redlock = Redlock::new(Config {
+                                       track_locks: true,
+                                       ..Config::default()
+                                   })
+                .unwrap()
+                .into_arc();
+        redlock.install_shutdown_handler().unwrap();
+        redlock.install_shutdown_handler().unwrap();
+        assert_eq!(registered_count(&redlock), 1);
+
+        let lock = redlock
+            .lock("test_install_shutdown_handler", Duration::from_millis(2000))
+            .unwrap();
+        release_registered();
+        assert!(redlock.held_locks().is_empty());
+        assert!(!redlock.is_locked(lock.resource_name()).unwrap());
+    }
+
+    #[test]
+    fn test_install_shutdown_handler_untracked() {
+        let redlock = Redlock::new(Config::default()).unwrap().into_arc();
+        match redlock.install_shutdown_handler() {
+            Err(RedlockError::LockTrackingDisabled) => {}
+            res => panic!("expected LockTrackingDisabled, got {:?}", res),
+        }
+        assert_eq!(registered_count(&redlock), 0);
+    }
+}
@@ -0,0 +1,73 @@
+use std::panic;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::{self, JoinError};
+use crate::errors::{RedlockError, RedlockResult};
+use crate::redlock::{OwnedLock, Redlock};
+
+impl Redlock {
+    // Locks the given resource like lock_owned, on the blocking thread pool
+    // of tokio, so that an async caller can await it without blocking the
+    // runtime. Each pending call takes a thread of the pool, which is
+    // bounded, for as long as the lock is retried: many contended calls
+    // delay the other blocking tasks. Dropping the future does not stop
+    // the call, the lock may still be acquired and is then left until it
+    // expires.
+    pub async fn lock_spawn_blocking(self: &Arc<Self>,
+                                     resource_name: &str,
+                                     ttl: Duration)
+                                     -> RedlockResult<OwnedLock> {
+        let redlock = self.clone();
+        let resource_name = String::from(resource_name);
+        task::spawn_blocking(move || redlock.lock_owned(&resource_name, ttl))
+            .await
+            .unwrap_or_else(joined)
+    }
+}
+
+impl OwnedLock {
+    // Release the acquired lock on the blocking thread pool of tokio, see
+    // Redlock::lock_spawn_blocking.
+    pub async fn unlock_spawn_blocking(self) -> RedlockResult<()> {
+        task::spawn_blocking(move || self.unlock())
+            .await
+            .unwrap_or_else(joined)
+    }
+}
+
+// Resume the panic of a blocking task, or fail with Cancelled if the
+// runtime has shut down before it ran.
+fn joined<T>(err: JoinError) -> RedlockResult<T> {
+    match err.try_into_panic() {
+        Ok(payload) => panic::resume_unwind(payload),
+        Err(_) => Err(RedlockError::Cancelled),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redlock::Config;
+
+    #[tokio::test]
+    async fn test_lock_spawn_blocking() {
+        let resource_name = "test_lock_spawn_blocking";
+        let ttl = Duration::from_millis(2000);
+        let redlock = Redlock::new(Config {
+                                       retry_count: 2,
+                                       retry_delay: Duration::from_millis(100),
+                                       retry_jitter: 0,
+                                       ..Config::default()
+                                   })
+                .unwrap()
+                .into_arc();
+
+        let lock = redlock.lock_spawn_blocking(resource_name, ttl).await.unwrap();
+        assert_eq!(lock.resource_name(), resource_name);
+        assert!(redlock.lock_spawn_blocking(resource_name, ttl).await.is_err());
+
+        lock.unlock_spawn_blocking().await.unwrap();
+        let lock = redlock.lock_spawn_blocking(resource_name, ttl).await.unwrap();
+        lock.unlock_spawn_blocking().await.unwrap();
+    }
+}
@@ -1,32 +1,106 @@
 use std::time::Duration;
-use rand::{self, Rng};
+use rand::{OsRng, Rng};
 
-pub fn get_random_string(len: usize) -> String {
-    rand::thread_rng()
-        .gen_ascii_chars()
-        .take(len)
-        .collect::<String>()
+// Generate a hex encoded token from the given number of random bytes, read
+// from the random number generator of the operating system.
+pub fn get_random_token(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng::new()
+        .expect("Unable to access the random number generator of the OS")
+        .fill_bytes(&mut bytes);
+
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
 }
 
 pub fn num_milliseconds(duration: &Duration) -> u64 {
-    let secs_part = duration.as_secs() * 1000;
-    let nano_part = duration.subsec_nanos() / 1000_000;
+    duration.as_millis() as u64
+}
+
+// (De)serialize a duration as a number of milliseconds.
+#[cfg(feature = "serde")]
+pub mod millis {
+    use std::time::Duration;
+    use serde::{Deserialize, Deserializer, Serializer};
 
-    secs_part + nano_part as u64
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(super::num_milliseconds(duration))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_millis(u64::deserialize(deserializer)?))
+    }
+}
+
+// (De)serialize an optional duration as a number of milliseconds.
+#[cfg(feature = "serde")]
+pub mod option_millis {
+    use std::time::Duration;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        match *duration {
+            Some(ref duration) => serializer.serialize_some(&super::num_milliseconds(duration)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+        where D: Deserializer<'de>
+    {
+        Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_millis))
+    }
+}
+
+// (De)serialize a map of durations as numbers of milliseconds.
+#[cfg(feature = "serde")]
+pub mod millis_map {
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(durations: &HashMap<String, Duration>, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.collect_map(durations
+                                   .iter()
+                                   .map(|(key, duration)| (key, super::num_milliseconds(duration))))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<HashMap<String, Duration>, D::Error>
+        where D: Deserializer<'de>
+    {
+        Ok(HashMap::<String, u64>::deserialize(deserializer)?
+               .into_iter()
+               .map(|(key, millis)| (key, Duration::from_millis(millis)))
+               .collect())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_get_random_token() {
+        let token = get_random_token(20);
+        assert_eq!(token.len(), 40);
+        assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+    }
 
     #[test]
-    fn test_get_random_string() {
-        assert_eq!(get_random_string(32).len(), 32);
+    fn test_get_random_token_unique() {
+        let tokens: HashSet<String> = (0..10000).map(|_| get_random_token(20)).collect();
+        assert_eq!(tokens.len(), 10000);
     }
 
     #[test]
     fn test_num_milliseconds() {
         assert_eq!(num_milliseconds(&Duration::from_millis(5010)), 5010);
         assert_eq!(num_milliseconds(&Duration::from_millis(0)), 0);
+        assert_eq!(num_milliseconds(&Duration::new(2, 999_999)), 2000);
+        assert_eq!(num_milliseconds(&Duration::from_micros(1500)), 1);
     }
 }
@@ -0,0 +1,365 @@
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use crate::errors::{RedlockResult, RedlockError};
+use crate::redlock::OwnedLock;
+
+// LockEvent is published by the watchdog of an auto extended lock.
+#[derive(Debug, Clone)]
+pub enum LockEvent {
+    // The lock has been extended until the given time.
+    Extended { new_expiration: SystemTime },
+    // The lock could not be extended and is lost, the watchdog stops.
+    ExtendFailed { error: String },
+    // The watchdog has stopped, no more event is published.
+    Stopped,
+}
+
+// The senders of the subscribers to the events, None once the watchdog
+// has stopped.
+type Subscribers = Arc<Mutex<Option<Vec<mpsc::Sender<LockEvent>>>>>;
+
+fn publish(subscribers: &Subscribers, event: LockEvent) {
+    if let Some(ref mut senders) = *subscribers.lock().unwrap() {
+        senders.retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
+
+// AutoExtendHandle keeps a lock alive by extending it periodically in a
+// background thread. Dropping the handle stops the watchdog and releases
+// the lock.
+#[derive(Debug)]
+pub struct AutoExtendHandle {
+    stop: Option<mpsc::Sender<()>>,
+    watchdog: Option<thread::JoinHandle<OwnedLock>>,
+    error: Arc<Mutex<Option<RedlockError>>>,
+    subscribers: Subscribers,
+}
+
+impl AutoExtendHandle {
+    // Whether the watchdog has failed to extend the lock, in which case it
+    // has stopped and the lock can no longer be considered held.
+    pub fn is_lost(&self) -> bool {
+        self.error.lock().unwrap().is_some()
+    }
+
+    // Subscribe to the events of the watchdog. The receiver is
+    // disconnected right after the Stopped event.
+    pub fn events(&self) -> mpsc::Receiver<LockEvent> {
+        let (sender, receiver) = mpsc::channel();
+        match *self.subscribers.lock().unwrap() {
+            Some(ref mut senders) => senders.push(sender),
+            None => {
+                let _ = sender.send(LockEvent::Stopped);
+            }
+        }
+        receiver
+    }
+
+    // Stop the watchdog and release the lock. Returns the error of the
+    // failed extension if the lock has been lost.
+    pub fn stop(mut self) -> RedlockResult<()> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> RedlockResult<()> {
+        // Disconnecting the channel wakes the watchdog up
+        self.stop.take();
+        let lock = match self.watchdog.take().map(|watchdog| watchdog.join()) {
+            Some(Ok(lock)) => lock,
+            _ => return Ok(()),
+        };
+
+        if let Some(err) = self.error.lock().unwrap().take() {
+            return Err(err);
+        }
+        lock.unlock()
+    }
+}
+
+impl Drop for AutoExtendHandle {
+    fn drop(&mut self) {
+        let _ = self.finish(); // Just ingore the result
+    }
+}
+
+impl OwnedLock {
+    // Extend the lock to the given TTL every interval in a background
+    // thread, until the returned handle is stopped or dropped. The interval
+    // should be well below the TTL, so that the lock does not expire between
+    // two extensions.
+    pub fn auto_extend(self, ttl: Duration, interval: Duration) -> AutoExtendHandle {
+        let (stop, stopped) = mpsc::channel();
+        let error = Arc::new(Mutex::new(None));
+        let watchdog_error = error.clone();
+        let subscribers: Subscribers = Arc::new(Mutex::new(Some(Vec::new())));
+        let watchdog_subscribers = subscribers.clone();
+
+        let watchdog = thread::spawn(move || {
+            let mut lock = self;
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                match lock.extend(ttl) {
+                    Ok(extended) => {
+                        lock = extended;
+                        publish(&watchdog_subscribers,
+                                LockEvent::Extended { new_expiration: lock.expiration() });
+                    }
+                    Err(err) => {
+                        publish(&watchdog_subscribers,
+                                LockEvent::ExtendFailed { error: err.to_string() });
+                        *watchdog_error.lock().unwrap() = Some(err);
+                        break;
+                    }
+                }
+            }
+
+            publish(&watchdog_subscribers, LockEvent::Stopped);
+            // Disconnect the subscribers
+            watchdog_subscribers.lock().unwrap().take();
+            lock
+        });
+
+        AutoExtendHandle {
+            stop: Some(stop),
+            watchdog: Some(watchdog),
+            error,
+            subscribers,
+        }
+    }
+}
+
+// QuorumWatch checks that a lock is still held on a quorum of servers in a
+// background thread, see OwnedLock::watch_quorum. Dropping the handle stops
+// the checks, but does not release the lock.
+#[derive(Debug)]
+pub struct QuorumWatch {
+    stop: Option<mpsc::Sender<()>>,
+    watch: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for QuorumWatch {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes the watch up
+        self.stop.take();
+        if let Some(watch) = self.watch.take() {
+            let _ = watch.join();
+        }
+    }
+}
+
+impl OwnedLock {
+    // Check every interval, in a background thread, that the lock is still
+    // held on a quorum of servers. Once it is not, or less than a quorum of
+    // servers have answered, the lock is downgraded to LockStrength::Advisory
+    // and on_advisory is called with its resource name and value, then the
+    // checks stop. It's the case as well once the lock has expired.
+    //
+    // This trades safety for availability: the owner is told that the lock
+    // may be held by another client, instead of failing, and decides whether
+    // to carry on in a degraded mode. Anything which must never run twice
+    // at once should stop on the downgrade, as it would on a hard failure.
+    // The lock may have been lost up to an interval before the downgrade.
+    pub fn watch_quorum<F>(&self, interval: Duration, on_advisory: F) -> QuorumWatch
+        where F: FnOnce(&str, &str) + Send + 'static
+    {
+        let (stop, stopped) = mpsc::channel();
+        let redlock = self.redlock.clone();
+        let resource_name = String::from(self.resource_name());
+        let value = String::from(self.value());
+        let advisory = self.advisory.clone();
+
+        let watch = thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if let Ok(true) = redlock.is_held(&resource_name, &value) {
+                    continue;
+                }
+
+                advisory.store(true, Ordering::SeqCst);
+                on_advisory(&resource_name, &value);
+                break;
+            }
+        });
+
+        QuorumWatch {
+            stop: Some(stop),
+            watch: Some(watch),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::Commands;
+    use crate::redlock::{Config, LockStrength, Redlock};
+
+    fn get(resource_name: &str) -> Option<String> {
+        redis::Client::open("redis://127.0.0.1")
+            .unwrap()
+            .get_connection()
+            .unwrap()
+            .get(resource_name)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_auto_extend() {
+        let resource_name = "test_auto_extend";
+        let ttl = Duration::from_millis(500);
+        let redlock = Redlock::new(Config::default()).unwrap().into_arc();
+        let handle = redlock
+            .lock_owned(resource_name, ttl)
+            .unwrap()
+            .auto_extend(ttl, Duration::from_millis(150));
+
+        thread::sleep(Duration::from_millis(1200));
+        assert!(!handle.is_lost());
+        assert!(get(resource_name).is_some());
+
+        handle.stop().unwrap();
+        assert!(get(resource_name).is_none());
+    }
+
+    #[test]
+    fn test_auto_extend_drop() {
+        let resource_name = "test_auto_extend_drop";
+        let ttl = Duration::from_millis(2000);
+        let redlock = Redlock::new(Config::default()).unwrap().into_arc();
+        let handle = redlock
+            .lock_owned(resource_name, ttl)
+            .unwrap()
+            .auto_extend(ttl, Duration::from_millis(500));
+
+        assert!(get(resource_name).is_some());
+        drop(handle);
+        assert!(get(resource_name).is_none());
+    }
+
+    #[test]
+    fn test_auto_extend_events() {
+        let resource_name = "test_auto_extend_events";
+        let ttl = Duration::from_millis(1000);
+        let redlock = Redlock::new(Config {
+                                       retry_count: 1,
+                                       ..Config::default()
+                                   })
+                .unwrap()
+                .into_arc();
+        let handle = redlock
+            .lock_owned(resource_name, ttl)
+            .unwrap()
+            .auto_extend(ttl, Duration::from_millis(100));
+        let events = handle.events();
+
+        match events.recv_timeout(Duration::from_millis(1000)).unwrap() {
+            LockEvent::Extended { new_expiration } => assert!(new_expiration > SystemTime::now()),
+            event => panic!("expected Extended, got {:?}", event),
+        }
+
+        let _: () = redis::Client::open("redis://127.0.0.1")
+            .unwrap()
+            .get_connection()
+            .unwrap()
+            .del(resource_name)
+            .unwrap();
+        let mut events = events
+            .iter()
+            .skip_while(|event| matches!(*event, LockEvent::Extended { .. }));
+        assert!(matches!(events.next(), Some(LockEvent::ExtendFailed { .. })));
+        assert!(matches!(events.next(), Some(LockEvent::Stopped)));
+        assert!(events.next().is_none());
+
+        assert!(matches!(handle.events().recv(), Ok(LockEvent::Stopped)));
+        assert!(handle.stop().is_err());
+    }
+
+    #[test]
+    fn test_auto_extend_lost() {
+        let resource_name = "test_auto_extend_lost";
+        let ttl = Duration::from_millis(1000);
+        let redlock = Redlock::new(Config {
+                                       retry_count: 1,
+                                       ..Config::default()
+                                   })
+                .unwrap()
+                .into_arc();
+        let handle = redlock
+            .lock_owned(resource_name, ttl)
+            .unwrap()
+            .auto_extend(ttl, Duration::from_millis(100));
+
+        let _: () = redis::Client::open("redis://127.0.0.1")
+            .unwrap()
+            .get_connection()
+            .unwrap()
+            .del(resource_name)
+            .unwrap();
+        thread::sleep(Duration::from_millis(500));
+
+        assert!(handle.is_lost());
+        match handle.stop() {
+            Err(RedlockError::UnableToExtend(..)) => {}
+            res => panic!("expected UnableToExtend, got {:?}", res),
+        }
+    }
+
+    fn new_quorum_redlock() -> Arc<Redlock> {
+        Redlock::new(Config {
+                         retry_count: 1,
+                         ..Config::with_addrs(vec!["redis://127.0.0.1/0",
+                                                   "redis://127.0.0.1/1",
+                                                   "redis://127.0.0.1/2"])
+                     })
+                .unwrap()
+                .into_arc()
+    }
+
+    #[test]
+    fn test_watch_quorum_held() {
+        let resource_name = "test_watch_quorum_held";
+        let redlock = new_quorum_redlock();
+        let lock = redlock
+            .lock_owned(resource_name, Duration::from_millis(2000))
+            .unwrap();
+        let (advisory, notified) = mpsc::channel();
+        let watch = lock.watch_quorum(Duration::from_millis(50), move |_, _| {
+            let _ = advisory.send(());
+        });
+
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(lock.strength(), LockStrength::Quorum);
+        drop(watch);
+        assert!(notified.try_recv().is_err());
+        lock.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_watch_quorum_lost() {
+        let resource_name = "test_watch_quorum_lost";
+        let redlock = new_quorum_redlock();
+        let lock = redlock
+            .lock_owned(resource_name, Duration::from_millis(2000))
+            .unwrap();
+        let (advisory, notified) = mpsc::channel();
+        let _watch = lock.watch_quorum(Duration::from_millis(50), move |name, value| {
+            let _ = advisory.send((String::from(name), String::from(value)));
+        });
+
+        // The key is lost on two servers out of three, e.g. on a restart
+        for addr in &["redis://127.0.0.1/0", "redis://127.0.0.1/1"] {
+            let _: () = redis::Client::open(*addr)
+                .unwrap()
+                .get_connection()
+                .unwrap()
+                .del(resource_name)
+                .unwrap();
+        }
+
+        let (name, value) = notified.recv_timeout(Duration::from_millis(1000)).unwrap();
+        assert_eq!(name, resource_name);
+        assert_eq!(value, lock.value());
+        assert_eq!(lock.strength(), LockStrength::Advisory);
+        assert!(notified.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+}